@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{path::Path, path::PathBuf, time::Duration};
+
+use discovery::{DeviceId, Discovered};
+
+/// Settings for `cli daemon`, read from a TOML file. Everything has a
+/// default, so an empty file syncs every station it hears from.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where synced files are written.
+    pub data: PathBuf,
+    pub database: PathBuf,
+    pub devices: Devices,
+    pub sync: SyncPolicy,
+    /// Done in order after each sync that wrote a file.
+    pub after_sync: Vec<AfterSync>,
+    pub portal: Portal,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data: PathBuf::from("fk-data"),
+            database: PathBuf::from("fk-data/fk.db"),
+            devices: Devices::default(),
+            sync: SyncPolicy::default(),
+            after_sync: Vec::new(),
+            portal: Portal::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Reading {:?}", path))?;
+        let config: Config =
+            toml::from_str(&text).with_context(|| format!("Parsing {:?}", path))?;
+
+        if config.after_sync.contains(&AfterSync::Upload) && config.portal.token.is_none() {
+            return Err(anyhow::anyhow!(
+                "Uploading after sync requires a portal token"
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Which stations we'll talk to. An empty allow list allows everyone, and
+/// deny wins over allow.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Devices {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    /// HTTP ports stations may announce, others are usually simulators or
+    /// development builds.
+    pub ports: Vec<u16>,
+}
+
+impl Devices {
+    pub fn allows(&self, discovered: &Discovered) -> bool {
+        let DeviceId(device_id) = &discovered.device_id;
+        if self.deny.contains(device_id) {
+            return false;
+        }
+        if !self.allow.is_empty() && !self.allow.contains(device_id) {
+            return false;
+        }
+
+        match discovered.http_addr {
+            Some(addr) if !self.ports.is_empty() => self.ports.contains(&addr.port()),
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoSync {
+    /// Never start a sync, only keep station status up to date.
+    Never,
+    /// Sync whenever a station is heard from.
+    #[default]
+    Always,
+    /// Sync when a station's status says it has records we don't.
+    NewData,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncPolicy {
+    pub auto: AutoSync,
+    /// Time to wait after a sync of a station before starting another.
+    #[serde(with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        Self {
+            auto: AutoSync::default(),
+            cooldown: Duration::from_secs(60 * 15),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AfterSync {
+    /// Merge the station generation's files into one.
+    Merge,
+    /// Upload synced files to the portal, waiting for it to be reachable.
+    Upload,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Portal {
+    pub url: String,
+    pub token: Option<String>,
+}
+
+impl Default for Portal {
+    fn default() -> Self {
+        Self {
+            url: "https://api.fieldkit.org".to_owned(),
+            token: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discovered(device_id: &str, port: u16) -> Discovered {
+        Discovered {
+            device_id: DeviceId(device_id.to_owned()),
+            http_addr: Some(format!("192.168.0.100:{}", port).parse().unwrap()),
+            udp_addr: None,
+        }
+    }
+
+    #[test]
+    fn test_parsing_config() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            data = "/var/lib/fk"
+            database = "/var/lib/fk/fk.db"
+            after_sync = ["merge", "upload"]
+
+            [devices]
+            deny = ["0011"]
+            ports = [80]
+
+            [sync]
+            auto = "new-data"
+            cooldown = "1h"
+
+            [portal]
+            token = "token"
+            "#,
+        )?;
+
+        assert_eq!(config.data, PathBuf::from("/var/lib/fk"));
+        assert_eq!(config.devices.deny, vec!["0011".to_owned()]);
+        assert_eq!(config.sync.auto, AutoSync::NewData);
+        assert_eq!(config.sync.cooldown, Duration::from_secs(3600));
+        assert_eq!(config.after_sync, vec![AfterSync::Merge, AfterSync::Upload]);
+        assert_eq!(config.portal.url, "https://api.fieldkit.org");
+
+        assert_eq!(toml::from_str::<Config>("")?, Config::default());
+        assert!(toml::from_str::<Config>("data_dir = \"fk-data\"").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowing_devices() {
+        let devices = Devices::default();
+        assert!(devices.allows(&discovered("0011", 2380)));
+
+        let devices = Devices {
+            allow: vec!["0011".to_owned(), "0022".to_owned()],
+            deny: vec!["0022".to_owned()],
+            ports: vec![80],
+        };
+        assert!(devices.allows(&discovered("0011", 80)));
+        assert!(!devices.allows(&discovered("0011", 2380)));
+        assert!(!devices.allows(&discovered("0022", 80)));
+        assert!(!devices.allows(&discovered("0033", 80)));
+    }
+}
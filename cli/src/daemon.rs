@@ -0,0 +1,345 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{signal, sync::mpsc};
+use tracing::*;
+
+use discovery::{DeviceId, Discovered, Discovery};
+use query::portal::{AuthenticatedClient, Tokens};
+use store::Db;
+use sync::{FilesRecordSink, Flushed, Server, ServerEvent, UdpTransport};
+
+use crate::config::{AfterSync, AutoSync, Config};
+use crate::stations;
+use crate::uploads::UploadQueue;
+
+/// How long shutdown waits for syncs to cancel and post-sync work to finish.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stations being synced and when we last started or finished one.
+struct Syncing {
+    cooldown: Duration,
+    active: HashSet<DeviceId>,
+    last: HashMap<DeviceId, Instant>,
+}
+
+impl Syncing {
+    fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            active: HashSet::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    fn ready(&self, device_id: &DeviceId, now: Instant) -> bool {
+        !self.active.contains(device_id)
+            && self
+                .last
+                .get(device_id)
+                .map(|last| now.duration_since(*last) >= self.cooldown)
+                .unwrap_or(true)
+    }
+
+    fn touch(&mut self, device_id: &DeviceId, now: Instant) {
+        self.last.insert(device_id.clone(), now);
+    }
+
+    fn began(&mut self, device_id: &DeviceId) {
+        self.active.insert(device_id.clone());
+    }
+
+    fn finished(&mut self, device_id: &DeviceId, now: Instant) {
+        self.active.remove(device_id);
+        self.touch(device_id, now);
+    }
+}
+
+type SyncServer = Server<UdpTransport, FilesRecordSink>;
+
+/// Syncs stations as they're discovered and records what happened, until
+/// interrupted.
+pub struct Daemon {
+    config: Arc<Config>,
+    db: Arc<Mutex<Db>>,
+    server: Arc<SyncServer>,
+    uploads: Option<Arc<UploadQueue<AuthenticatedClient>>>,
+    syncing: Arc<Mutex<Syncing>>,
+    /// Held while merging, so merges of the same files never overlap.
+    merging: Arc<Mutex<()>>,
+}
+
+impl Daemon {
+    pub fn new(config: Config) -> Result<Self> {
+        let mut db = Db::new();
+        db.open_path(&config.database)?;
+
+        let db = Arc::new(Mutex::new(db));
+
+        let uploads = match (
+            &config.portal.token,
+            config.after_sync.contains(&AfterSync::Upload),
+        ) {
+            (Some(token), true) => Some(Arc::new(UploadQueue::new(
+                db.clone(),
+                AuthenticatedClient::new(&config.portal.url, Tokens::new(token.clone()))?,
+            ))),
+            (None, true) => return Err(anyhow!("Uploading after sync requires a portal token")),
+            (_, false) => None,
+        };
+
+        Ok(Self {
+            server: Arc::new(Server::new(
+                UdpTransport::new(),
+                FilesRecordSink::new(&config.data),
+            )),
+            syncing: Arc::new(Mutex::new(Syncing::new(config.sync.cooldown))),
+            merging: Arc::new(Mutex::new(())),
+            config: Arc::new(config),
+            db,
+            uploads,
+        })
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let this = Arc::new(self);
+        let (discovered_tx, discovered_rx) = mpsc::channel::<Discovered>(32);
+        let (events_tx, events_rx) = mpsc::channel::<ServerEvent>(32);
+
+        let serving = tokio::spawn({
+            let server = this.server.clone();
+            async move { server.run(events_tx).await }
+        });
+        let recording = tokio::spawn({
+            let this = this.clone();
+            async move { this.record(events_rx).await }
+        });
+        let uploading = this.uploads.clone().map(|uploads| {
+            tokio::spawn(async move {
+                if let Err(e) = uploads.run().await {
+                    warn!("Upload queue: {:?}", e);
+                }
+            })
+        });
+        let scheduling = tokio::spawn({
+            let this = this.clone();
+            async move { this.schedule(discovered_rx).await }
+        });
+
+        let discovery = Discovery::default();
+
+        let res = tokio::select! {
+            res = discovery.run(discovered_tx) => res,
+            res = signal::ctrl_c() => {
+                info!("shutting down");
+                res.map_err(|e| e.into())
+            }
+        };
+
+        scheduling.abort();
+        this.shutdown().await;
+        recording.abort();
+        serving.abort();
+        if let Some(uploading) = uploading {
+            // Anything interrupted is still pending and uploads next time.
+            uploading.abort();
+        }
+
+        res
+    }
+
+    /// Cancels syncs in progress and gives them a chance to finish. Merges
+    /// run on blocking threads, which the runtime finishes before exiting.
+    async fn shutdown(&self) {
+        let active: Vec<_> = {
+            let syncing = self.syncing.lock().expect("Lock error");
+            syncing.active.iter().cloned().collect()
+        };
+
+        for device_id in active {
+            info!("{:?} cancelling", device_id);
+            if let Err(e) = self.server.cancel(device_id).await {
+                warn!("Cancel error: {:?}", e);
+            }
+        }
+
+        let started = Instant::now();
+        while started.elapsed() < SHUTDOWN_TIMEOUT {
+            if self.syncing.lock().expect("Lock error").active.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        warn!("gave up waiting for syncs to finish");
+    }
+
+    async fn schedule(self: Arc<Self>, mut rx: mpsc::Receiver<Discovered>) {
+        while let Some(discovered) = rx.recv().await {
+            if !self.config.devices.allows(&discovered) {
+                trace!("{:?} (ignored)", discovered);
+                continue;
+            }
+
+            {
+                let now = Instant::now();
+                let mut syncing = self.syncing.lock().expect("Lock error");
+                if !syncing.ready(&discovered.device_id, now) {
+                    continue;
+                }
+                syncing.touch(&discovered.device_id, now);
+            }
+
+            info!("{:?}", discovered);
+
+            tokio::spawn({
+                let this = self.clone();
+                async move {
+                    let device_id = discovered.device_id.clone();
+                    if let Err(e) = this.visit(discovered).await {
+                        warn!("{:?} {:?}", device_id, e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Refreshes what we know about a station and syncs it if the policy
+    /// says to. The store needs to know a station to record its downloads.
+    async fn visit(&self, discovered: Discovered) -> Result<()> {
+        let sync = match (self.config.sync.auto, self.refresh(&discovered).await) {
+            (AutoSync::Never, res) => res.map(|_| false)?,
+            (AutoSync::NewData, res) => res?,
+            (AutoSync::Always, Ok(_)) => true,
+            (AutoSync::Always, Err(e)) => {
+                warn!("{:?} querying status: {:?}", discovered.device_id, e);
+                true
+            }
+        };
+
+        if sync {
+            self.server.sync(discovered).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Queries a station's status into the store, returning true if it has
+    /// records we haven't downloaded.
+    async fn refresh(&self, discovered: &Discovered) -> Result<bool> {
+        let station = stations::refresh(&self.db, discovered).await?;
+
+        let db = self.db.lock().expect("Lock error");
+        let station_id = station.id.ok_or_else(|| anyhow!("Unsaved station"))?;
+        let downloaded = db
+            .get_station_downloads(station_id)?
+            .into_iter()
+            .filter(|d| d.finished.is_some() && d.generation_id == station.generation_id)
+            .map(|d| d.end + 1)
+            .max()
+            .unwrap_or_default();
+
+        debug!(
+            "{:?} has {} records, downloaded {}",
+            discovered.device_id, station.data.records, downloaded
+        );
+
+        Ok(station.data.records > downloaded)
+    }
+
+    async fn record(self: Arc<Self>, mut rx: mpsc::Receiver<ServerEvent>) {
+        while let Some(event) = rx.recv().await {
+            trace!("{:?}", event);
+
+            if let Err(e) = self
+                .db
+                .lock()
+                .expect("Lock error")
+                .record_sync_event(&event)
+            {
+                warn!("Recording download: {:?}", e);
+            }
+
+            match &event {
+                ServerEvent::Began(device_id) => {
+                    self.syncing.lock().expect("Lock error").began(device_id);
+                }
+                ServerEvent::Completed(device_id, flushed) => {
+                    self.syncing
+                        .lock()
+                        .expect("Lock error")
+                        .finished(device_id, Instant::now());
+                    if let Some(flushed) = flushed {
+                        self.after_sync(device_id, flushed);
+                    }
+                }
+                ServerEvent::Failed(device_id, failure) => {
+                    warn!("{:?} {}", device_id, failure);
+                    self.syncing
+                        .lock()
+                        .expect("Lock error")
+                        .finished(device_id, Instant::now());
+                }
+                ServerEvent::Transferring(..) | ServerEvent::Processing(_) => {}
+            }
+        }
+    }
+
+    /// Starts what's configured to happen after a sync, without holding up
+    /// the events of other syncs.
+    fn after_sync(&self, device_id: &DeviceId, flushed: &Flushed) {
+        for action in self.config.after_sync.iter() {
+            match action {
+                AfterSync::Merge => {
+                    let sink = FilesRecordSink::new(&self.config.data);
+                    let device_id = device_id.clone();
+                    let generation_id = flushed.generation_id.clone();
+                    let merging = self.merging.clone();
+                    // Merging reads every file of the generation, so keep it
+                    // off the workers handling events.
+                    tokio::task::spawn_blocking(move || {
+                        let _merging = merging.lock().expect("Lock error");
+                        match sink.merge(&device_id, &generation_id) {
+                            Ok(merged) => {
+                                info!("{:?} merged {:?}", device_id, merged.flushed.path)
+                            }
+                            Err(e) => warn!("{:?} merge failed: {:?}", device_id, e),
+                        }
+                    });
+                }
+                AfterSync::Upload => {
+                    if let Some(uploads) = &self.uploads {
+                        uploads.wake();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syncing_cooldown() {
+        let device_id = DeviceId("0011".to_owned());
+        let mut syncing = Syncing::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(syncing.ready(&device_id, now));
+
+        syncing.touch(&device_id, now);
+        syncing.began(&device_id);
+        assert!(!syncing.ready(&device_id, now + Duration::from_secs(120)));
+
+        syncing.finished(&device_id, now + Duration::from_secs(120));
+        assert!(!syncing.ready(&device_id, now + Duration::from_secs(150)));
+        assert!(syncing.ready(&device_id, now + Duration::from_secs(180)));
+
+        assert!(syncing.ready(&DeviceId("0022".to_owned()), now));
+    }
+}
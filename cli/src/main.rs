@@ -1,18 +1,24 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use clap::{Args, Parser, Subcommand};
 use query::portal::{LoginPayload, PortalError, Tokens};
 use std::{
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
-use tokio::{pin, signal, sync::mpsc};
-use tokio_stream::StreamExt;
+use tokio::{signal, sync::mpsc};
 use tracing::*;
 use tracing_subscriber::prelude::*;
 
 use discovery::{DeviceId, Discovered, Discovery};
+use store::Db;
 use sync::{FilesRecordSink, Server, ServerEvent, UdpTransport};
 
+mod config;
+mod daemon;
+mod stations;
+mod uploads;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -25,6 +31,13 @@ pub enum Commands {
     QueryDevice,
     QueryPortal,
     Sync(SyncCommand),
+    Export(ExportCommand),
+    Inspect(InspectCommand),
+    Repair(RepairCommand),
+    Import(ImportCommand),
+    Daemon(DaemonCommand),
+    Firmware(FirmwareCommand),
+    Upgrade(UpgradeCommand),
 }
 
 #[derive(Args)]
@@ -35,6 +48,103 @@ pub struct SyncCommand {
     discover_ip: Option<String>,
 }
 
+#[derive(Args)]
+pub struct ExportCommand {
+    /// Synced data file to export.
+    data: PathBuf,
+    /// Meta file the data's readings refer to.
+    #[arg(long)]
+    meta: PathBuf,
+    /// csv or jsonl
+    #[arg(long, default_value = "csv")]
+    format: export::Format,
+    /// Written to stdout when missing.
+    #[arg(long)]
+    output: Option<PathBuf>,
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+    #[arg(long)]
+    module: Vec<String>,
+    #[arg(long)]
+    sensor: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct InspectCommand {
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ImportCommand {
+    /// Synced data directory to record in the store. Files are recorded as
+    /// already uploaded, so the daemon won't send them to the portal.
+    #[arg(default_value = "fk-data")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+pub struct RepairCommand {
+    /// Synced data directory, or a single file to repair.
+    #[arg(default_value = "fk-data")]
+    path: PathBuf,
+}
+
+#[derive(Args)]
+pub struct DaemonCommand {
+    /// TOML file configuring which stations to sync and what to do after.
+    #[arg(default_value = "fk-daemon.toml")]
+    config: PathBuf,
+}
+
+const FIRMWARE_CACHE: &str = "fk-firmware";
+const FIRMWARE_MODULE: &str = "fk-core";
+const FIRMWARE_PROFILE: &str = "standard";
+
+#[derive(Args)]
+pub struct FirmwareCommand {
+    /// Where firmware and the portal's listing are kept.
+    #[arg(long, default_value = FIRMWARE_CACHE)]
+    cache: PathBuf,
+    #[arg(long, default_value = "fk-data/fk.db")]
+    database: PathBuf,
+    /// Check every station against this module, rather than the one each
+    /// was last upgraded to.
+    #[arg(long)]
+    module: Option<String>,
+    /// Check every station against this profile, rather than the one each
+    /// was last upgraded to.
+    #[arg(long)]
+    profile: Option<String>,
+    #[arg(long, default_value = "https://api.fieldkit.org")]
+    portal: String,
+    /// Use the listing and images already cached.
+    #[arg(long)]
+    offline: bool,
+}
+
+#[derive(Args)]
+pub struct UpgradeCommand {
+    /// Station's HTTP address, as host:port.
+    addr: String,
+    #[arg(long, default_value = FIRMWARE_CACHE)]
+    cache: PathBuf,
+    #[arg(long, default_value = FIRMWARE_MODULE)]
+    module: String,
+    #[arg(long, default_value = FIRMWARE_PROFILE)]
+    profile: String,
+    #[arg(long, default_value = "https://api.fieldkit.org")]
+    portal: String,
+    /// Only send the firmware, the station runs it after its next restart.
+    #[arg(long)]
+    no_swap: bool,
+    /// Seconds to wait for the station to restart.
+    #[arg(long, default_value_t = 180)]
+    timeout: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     fn get_rust_log() -> String {
@@ -72,23 +182,19 @@ async fn main() -> Result<()> {
                 })
                 .await?;
 
-            let firmwares = client.available_firmware().await?;
+            let manager = firmware::FirmwareManager::new(
+                query::portal::Client::new("https://api.fieldkit.org")?,
+                Path::new(FIRMWARE_CACHE),
+            );
+            let firmwares = manager.refresh().await?;
             info!("{:?}", firmwares);
 
-            let firmware = firmwares.get(0).unwrap();
-            let path = PathBuf::from("firmware.bin");
-
-            let res = client.download_firmware(firmware, &path).await?;
-
-            pin!(res);
-
-            while let Some(bytes) = res.next().await {
-                info!("{:?}", bytes);
+            let target = firmware::Target::new(FIRMWARE_MODULE, FIRMWARE_PROFILE);
+            if let Some(firmware) = firmware::newest(&firmwares, &target) {
+                info!("{:?}", manager.download(firmware).await?);
             }
 
-            let broken_client = client.to_authenticated(Tokens {
-                token: "INVALID".to_string(),
-            })?;
+            let broken_client = client.to_authenticated(Tokens::new("INVALID".to_string()))?;
             match broken_client.query_ourselves().await {
                 Ok(_) => panic!("Whoa, how'd that happen?"),
                 Err(PortalError::HttpStatus(status)) => info!("http status: {:?}", status),
@@ -122,30 +228,31 @@ async fn main() -> Result<()> {
             Ok(())
         }
         Some(Commands::Sync(command)) => {
-            let (transfer_publish, mut transfer_events) = mpsc::channel::<ServerEvent>(32);
+            let (transfer_publish, transfer_events) = mpsc::channel::<ServerEvent>(32);
             let server = Arc::new(Server::new(
                 UdpTransport::new(),
-                FilesRecordSink::new(&Path::new("fk-data")),
+                FilesRecordSink::new(Path::new("fk-data")),
             ));
             let discovery = Discovery::default();
             let (tx, mut rx) = mpsc::channel::<Discovered>(32);
 
-            let ignore = tokio::spawn({
-                async move {
-                    while let Some(d) = transfer_events.recv().await {
-                        trace!("{:?}", d);
-                    }
-                }
-            });
+            let mut db = Db::new();
+            db.open_path(Path::new("fk-data/fk.db"))?;
+            let db = Arc::new(Mutex::new(db));
+
+            let recording = tokio::spawn(stations::record(db.clone(), transfer_events));
 
             let pump = tokio::spawn({
                 let server = server.clone();
+                let db = db.clone();
                 async move {
                     while let Some(d) = rx.recv().await {
                         if let Some(http_addr) = d.http_addr {
                             if http_addr.port() == 80 || http_addr.port() == 0 {
                                 info!("{:?}", d);
-                                server.sync(d).await.expect("Error initiating sync");
+                                stations::sync(&db, &server, d)
+                                    .await
+                                    .expect("Error initiating sync");
                             } else {
                                 debug!("{:?} (ignored)", d);
                             }
@@ -154,33 +261,34 @@ async fn main() -> Result<()> {
                 }
             });
 
-            match (command.discover_device_id, command.discover_ip) {
-                (Some(device_id), Some(ip)) => {
-                    let _begin = tokio::spawn({
-                        let server = server.clone();
-                        async move {
-                            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-
-                            server
-                                .sync(Discovered {
-                                    device_id: DeviceId(device_id),
-                                    http_addr: Some(
-                                        format!("{}:80", ip)
-                                            .parse()
-                                            .expect("Parsing http_addr failed"),
-                                    ),
-                                    udp_addr: Some(
-                                        format!("{}:22144", ip)
-                                            .parse()
-                                            .expect("Parsing udp_addr failed"),
-                                    ),
-                                })
-                                .await
-                                .expect("error initiating sync");
-                        }
-                    });
-                }
-                _ => {}
+            if let (Some(device_id), Some(ip)) = (command.discover_device_id, command.discover_ip) {
+                let _begin = tokio::spawn({
+                    let server = server.clone();
+                    let db = db.clone();
+                    async move {
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+
+                        stations::sync(
+                            &db,
+                            &server,
+                            Discovered {
+                                device_id: DeviceId(device_id),
+                                http_addr: Some(
+                                    format!("{}:80", ip)
+                                        .parse()
+                                        .expect("Parsing http_addr failed"),
+                                ),
+                                udp_addr: Some(
+                                    format!("{}:22144", ip)
+                                        .parse()
+                                        .expect("Parsing udp_addr failed"),
+                                ),
+                            },
+                        )
+                        .await
+                        .expect("error initiating sync");
+                    }
+                });
             }
 
             #[allow(clippy::unit_arg)]
@@ -188,12 +296,207 @@ async fn main() -> Result<()> {
                 _ = discovery.run(tx) => {},
                 _ = server.run(transfer_publish) => {},
                 _ = pump => {},
-                _ = ignore=> {},
+                _ = recording => {},
                 res = signal::ctrl_c() => {
                     return res.map_err(|e| e.into())
                 },
             })
         }
+        Some(Commands::Export(command)) => {
+            let exporter = export::Exporter::open(&command.meta, command.format)
+                .with_context(|| format!("{:?}", command.meta))?
+                .with_filter(export::Filter {
+                    since: command.since,
+                    until: command.until,
+                    modules: command.module,
+                    sensors: command.sensor,
+                });
+
+            let summary = match &command.output {
+                Some(path) => {
+                    let file = std::fs::File::create(path)?;
+                    exporter.export_file(&command.data, std::io::BufWriter::new(file))?
+                }
+                None => exporter.export_file(&command.data, std::io::stdout().lock())?,
+            };
+
+            info!("{:?}", summary);
+
+            Ok(())
+        }
+        Some(Commands::Inspect(command)) => {
+            let mut unhealthy = 0;
+            for path in command.paths.iter() {
+                let inspection = inspect::inspect(path).with_context(|| format!("{:?}", path))?;
+                print!("{}", inspection);
+                if !inspection.is_healthy() {
+                    unhealthy += 1;
+                }
+            }
+
+            if unhealthy > 0 {
+                Err(anyhow::anyhow!("{} file(s) have problems", unhealthy))
+            } else {
+                Ok(())
+            }
+        }
+        Some(Commands::Repair(command)) => {
+            let repaired = if command.path.is_dir() {
+                FilesRecordSink::new(&command.path).repair()?
+            } else {
+                sync::repair_file(&command.path).with_context(|| format!("{:?}", command.path))?
+            };
+
+            for repair in repaired.iter() {
+                println!("{}", repair);
+            }
+
+            info!("{} repair(s)", repaired.len());
+
+            Ok(())
+        }
+        Some(Commands::Import(command)) => {
+            let mut db = Db::new();
+            db.open_path(&command.path.join("fk.db"))?;
+
+            let imported = db.import_directory(&command.path)?;
+
+            println!(
+                "{} station(s), {} download(s), {} unchanged, {} skipped",
+                imported.stations, imported.downloads, imported.unchanged, imported.skipped
+            );
+
+            Ok(())
+        }
+        Some(Commands::Daemon(command)) => {
+            let config = config::Config::load(&command.config)?;
+
+            daemon::Daemon::new(config)?.run().await
+        }
+        Some(Commands::Firmware(command)) => {
+            let manager = firmware::FirmwareManager::new(
+                query::portal::Client::new(&command.portal)?,
+                &command.cache,
+            );
+            let target = match (&command.module, &command.profile) {
+                (None, None) => None,
+                (module, profile) => Some(firmware::Target::new(
+                    module.as_deref().unwrap_or(FIRMWARE_MODULE),
+                    profile.as_deref().unwrap_or(FIRMWARE_PROFILE),
+                )),
+            };
+
+            let firmwares = if command.offline {
+                manager.listing()?
+            } else {
+                match manager.refresh().await {
+                    Ok(firmwares) => firmwares,
+                    Err(e) => {
+                        warn!("Portal unavailable, using cached listing: {:?}", e);
+                        manager.listing()?
+                    }
+                }
+            };
+
+            let mut db = Db::new();
+            db.open_path(&command.database)?;
+
+            let report = manager.report(&db.get_stations()?, &firmwares, target.as_ref())?;
+
+            // Keep what the stations will need on hand for upgrading them.
+            let mut wanted: Vec<&query::portal::Firmware> = target
+                .as_ref()
+                .and_then(|target| firmware::newest(&firmwares, target))
+                .into_iter()
+                .chain(report.iter().filter_map(|station| match &station.status {
+                    firmware::FirmwareStatus::Outdated(newer) => Some(newer.as_ref()),
+                    _ => None,
+                }))
+                .collect();
+            wanted.sort_by_key(|f| f.id);
+            wanted.dedup_by_key(|f| f.id);
+
+            for newest in wanted {
+                let cached = if command.offline {
+                    manager.cached(newest)
+                } else {
+                    manager.download(newest).await
+                };
+                match cached {
+                    Ok(path) => info!("{} {:?}", newest.version, path),
+                    Err(e) => warn!("{} unavailable: {:?}", newest.version, e),
+                }
+            }
+
+            for station in report.iter() {
+                let status = match &station.status {
+                    firmware::FirmwareStatus::UpToDate => "up to date".to_owned(),
+                    firmware::FirmwareStatus::Outdated(newer) => {
+                        format!("out of date, {} available", newer.version)
+                    }
+                    firmware::FirmwareStatus::Ahead => "newer than available".to_owned(),
+                    firmware::FirmwareStatus::Unavailable => "no firmware available".to_owned(),
+                    firmware::FirmwareStatus::UnknownTarget => "unknown target".to_owned(),
+                };
+                println!(
+                    "{} ({}) {}: {}",
+                    station.name, station.device_id.0, station.running.label, status
+                );
+            }
+
+            info!(
+                "{} of {} station(s) out of date",
+                report.iter().filter(|s| s.status.is_outdated()).count(),
+                report.len()
+            );
+
+            Ok(())
+        }
+        Some(Commands::Upgrade(command)) => {
+            let manager = firmware::FirmwareManager::new(
+                query::portal::Client::new(&command.portal)?,
+                &command.cache,
+            );
+            let target = firmware::Target::new(&command.module, &command.profile);
+
+            let firmwares = match manager.refresh().await {
+                Ok(firmwares) => firmwares,
+                Err(e) => {
+                    warn!("Portal unavailable, using cached listing: {:?}", e);
+                    manager.listing()?
+                }
+            };
+            let newest = firmware::newest(&firmwares, &target)
+                .ok_or_else(|| anyhow::anyhow!("No firmware for {:?}", target))?;
+            manager.download(newest).await?;
+
+            let (tx, mut rx) = mpsc::channel::<Discovered>(32);
+            let discovering = tokio::spawn(async move { Discovery::default().run(tx).await });
+
+            let outcome = firmware::Upgrader::new()?
+                .with_swap(!command.no_swap)
+                .with_timeout(std::time::Duration::from_secs(command.timeout))
+                .on_progress(|progress| info!("{:?}", progress))
+                .upgrade(&manager, newest, &command.addr, Some(&mut rx))
+                .await;
+
+            discovering.abort();
+
+            match outcome? {
+                firmware::UpgradeOutcome::AlreadyCurrent => {
+                    println!("Already running {}", newest.version)
+                }
+                firmware::UpgradeOutcome::Staged => {
+                    println!("Sent {}, runs after the next restart", newest.version)
+                }
+                firmware::UpgradeOutcome::Upgraded { version, hash } => {
+                    println!("Upgraded to {} ({})", version, hash)
+                }
+                outcome => return Err(anyhow::anyhow!("Upgrade failed: {:?}", outcome)),
+            }
+
+            Ok(())
+        }
         _ => Ok(()),
     }
 }
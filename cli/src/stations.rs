@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::*;
+
+use discovery::Discovered;
+use store::{Db, Station};
+use sync::{RecordsSink, Server, ServerEvent, Transport};
+
+/// Queries a station's status into the store. The store needs to know a
+/// station before it can record its downloads.
+pub async fn refresh(db: &Mutex<Db>, discovered: &Discovered) -> Result<Station> {
+    let addr = discovered
+        .http_addr
+        .ok_or_else(|| anyhow!("No http address"))?;
+    let reply = query::device::Client::new()?
+        .query_status(&addr.to_string())
+        .await?;
+
+    let db = db.lock().expect("Lock error");
+    db.merge_reply(store::DeviceId(discovered.device_id.0.clone()), reply)
+}
+
+/// Starts syncing a station, refreshing it first so the sync is recorded.
+pub async fn sync<T: Transport, R: RecordsSink + 'static>(
+    db: &Mutex<Db>,
+    server: &Server<T, R>,
+    discovered: Discovered,
+) -> Result<()> {
+    if let Err(e) = refresh(db, &discovered).await {
+        warn!("{:?} querying status: {:?}", discovered.device_id, e);
+    }
+
+    server.sync(discovered).await
+}
+
+/// Records sync events in the store until the server goes away.
+pub async fn record(db: Arc<Mutex<Db>>, mut events: mpsc::Receiver<ServerEvent>) {
+    while let Some(event) = events.recv().await {
+        trace!("{:?}", event);
+        if let Err(e) = db.lock().expect("Lock error").record_sync_event(&event) {
+            warn!("Recording download: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+    use tempdir::TempDir;
+
+    use simulator::{identity, load_reply, Simulator, Station as SimulatedStation};
+    use sync::{FilesRecordSink, UdpTransport};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sync_records_download() -> Result<()> {
+        let records: Vec<Vec<u8>> = (0..50).map(|n| vec![n as u8; 64]).collect();
+        let station = SimulatedStation::new(
+            identity("0011223344556677", "8899aabbccddeeff", "Simulated")?,
+            load_reply(
+                &PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("../libs/query/examples/status_1.fkpb"),
+            )?,
+        )
+        .with_records(records);
+        let device_id = station.identity.device_id.clone();
+        let simulator =
+            Simulator::bind(station, "127.0.0.1:0".parse()?, "127.0.0.1:0".parse()?).await?;
+        let discovered = Discovered {
+            device_id: device_id.clone(),
+            http_addr: Some(simulator.http_addr()?),
+            udp_addr: Some(simulator.udp_addr()?),
+        };
+        let simulating = tokio::spawn(simulator.run());
+
+        let dir = TempDir::new("fk-tests-cli")?;
+        let mut db = Db::new();
+        db.open()?;
+        let db = Arc::new(Mutex::new(db));
+
+        let server = Arc::new(Server::new(
+            UdpTransport::new_with_port(0),
+            FilesRecordSink::new(dir.path()),
+        ));
+        let (publish, events) = mpsc::channel::<ServerEvent>(32);
+        let serving = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(publish).await }
+        });
+        let recording = tokio::spawn(record(db.clone(), events));
+
+        // The server only accepts commands once it's running.
+        while sync(&db, &server, discovered.clone()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let download = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let finished = {
+                    let db = db.lock().unwrap();
+                    let station =
+                        db.get_station_by_device_id(&store::DeviceId(device_id.0.clone()))?;
+                    match station.and_then(|s| s.id) {
+                        Some(id) => db
+                            .get_station_downloads(id)?
+                            .into_iter()
+                            .find(|d| d.finished.is_some()),
+                        None => None,
+                    }
+                };
+                if let Some(download) = finished {
+                    return Ok::<_, anyhow::Error>(download);
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await??;
+
+        assert_eq!((download.begin, download.end), (0, 49));
+        assert!(download.error.is_none());
+        assert!(PathBuf::from(&download.path).exists());
+
+        recording.abort();
+        serving.abort();
+        simulating.abort();
+
+        Ok(())
+    }
+}
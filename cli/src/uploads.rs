@@ -0,0 +1,332 @@
+use anyhow::Result;
+use backoff::{backoff::Backoff, ExponentialBackoff, ExponentialBackoffBuilder};
+use std::{
+    collections::HashSet,
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+use query::portal::{AuthenticatedClient, PortalError, Uploading};
+use store::{Db, StationDownload};
+
+/// How often the store is checked for files to upload when nothing wakes
+/// the queue, in case another process synced.
+const IDLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Sends a synced file to the portal.
+pub trait Uploader {
+    fn upload(&self, path: &Path) -> impl Future<Output = Result<(), PortalError>> + Send;
+}
+
+impl Uploader for AuthenticatedClient {
+    async fn upload(&self, path: &Path) -> Result<(), PortalError> {
+        let uploading = self.upload_readings(path).await?;
+        tokio::pin!(uploading);
+        while let Some(uploading) = uploading.next().await {
+            match uploading? {
+                Uploading::Completed(ingestion) => {
+                    debug!("{:?}", ingestion);
+                    return Ok(());
+                }
+                uploading => trace!("{:?}", uploading),
+            }
+        }
+
+        Err(PortalError::UnexpectedError)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Pass {
+    pub uploaded: usize,
+    /// Files the portal refused, these aren't tried again until restarted.
+    pub refused: usize,
+    /// The portal couldn't be reached, so files are still waiting.
+    pub offline: bool,
+}
+
+/// Uploads synced files recorded in the store and marks them uploaded.
+/// The store is the queue, so files left when offline or interrupted are
+/// picked up again after a restart.
+pub struct UploadQueue<U> {
+    db: Arc<Mutex<Db>>,
+    uploader: U,
+    wake: Notify,
+    refused: Mutex<HashSet<i64>>,
+}
+
+impl<U: Uploader> UploadQueue<U> {
+    pub fn new(db: Arc<Mutex<Db>>, uploader: U) -> Self {
+        Self {
+            db,
+            uploader,
+            wake: Notify::new(),
+            refused: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Has the queue look for new files now, typically after a sync.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut backoff = Self::offline_backoff();
+
+        loop {
+            let wait = match self.upload_pending().await {
+                Ok(pass) if !pass.offline => {
+                    if pass.uploaded > 0 || pass.refused > 0 {
+                        info!("{:?}", pass);
+                    }
+                    backoff.reset();
+                    IDLE_INTERVAL
+                }
+                Ok(_) => backoff.next_backoff().unwrap_or(IDLE_INTERVAL),
+                Err(e) => {
+                    warn!("Uploading: {:?}", e);
+                    backoff.next_backoff().unwrap_or(IDLE_INTERVAL)
+                }
+            };
+
+            debug!("waiting {:?}", wait);
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    /// Tries every waiting file once, stopping early if the portal can't be
+    /// reached.
+    pub async fn upload_pending(&self) -> Result<Pass> {
+        let pending = self.db.lock().expect("Lock error").get_pending_uploads()?;
+        let mut pass = Pass::default();
+
+        for download in pending {
+            let Some(id) = download.id else {
+                continue;
+            };
+            if self.refused.lock().expect("Lock error").contains(&id) {
+                continue;
+            }
+
+            match self.uploader.upload(Path::new(&download.path)).await {
+                Ok(()) => {
+                    info!("{} uploaded", download.path);
+                    self.db
+                        .lock()
+                        .expect("Lock error")
+                        .update_station_download(&StationDownload {
+                            uploaded: true,
+                            ..download
+                        })?;
+                    pass.uploaded += 1;
+                }
+                Err(e) if e.is_transient() => {
+                    info!("{} waiting, portal unavailable: {:?}", download.path, e);
+                    pass.offline = true;
+                    break;
+                }
+                Err(e) => {
+                    warn!("{} refused: {:?}", download.path, e);
+                    self.refused.lock().expect("Lock error").insert(id);
+                    pass.refused += 1;
+                }
+            }
+        }
+
+        Ok(pass)
+    }
+
+    fn offline_backoff() -> ExponentialBackoff {
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_secs(30))
+            .with_multiplier(2.0)
+            .with_max_interval(Duration::from_secs(60 * 30))
+            .with_max_elapsed_time(None)
+            .build();
+        backoff.reset();
+        backoff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use std::collections::VecDeque;
+    use tempdir::TempDir;
+
+    use store::{Battery, DeviceId, Firmware, Solar, Station, Stream};
+
+    use query::portal::StatusCode;
+
+    use super::*;
+
+    /// Answers uploads from a script, remembering what was asked for.
+    #[derive(Default)]
+    struct ScriptedUploader {
+        answers: Mutex<VecDeque<Result<(), PortalError>>>,
+        paths: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedUploader {
+        fn new(answers: Vec<Result<(), PortalError>>) -> Self {
+            Self {
+                answers: Mutex::new(answers.into()),
+                paths: Default::default(),
+            }
+        }
+    }
+
+    impl Uploader for &ScriptedUploader {
+        async fn upload(&self, path: &Path) -> Result<(), PortalError> {
+            self.paths.lock().unwrap().push(path.display().to_string());
+            self.answers.lock().unwrap().pop_front().unwrap_or(Ok(()))
+        }
+    }
+
+    fn open_db(paths: &[&str]) -> Result<Arc<Mutex<Db>>> {
+        let mut db = Db::new();
+        db.open()?;
+
+        let station = db.add_station(&Station {
+            id: None,
+            device_id: DeviceId("0011".to_owned()),
+            generation_id: "generation".to_owned(),
+            name: "Station".to_owned(),
+            firmware: Firmware {
+                label: "label".to_owned(),
+                time: 0,
+            },
+            last_seen: Utc::now(),
+            status: None,
+            meta: Stream::default(),
+            data: Stream::default(),
+            battery: Battery::default(),
+            solar: Solar::default(),
+            modules: Vec::new(),
+        })?;
+
+        for path in paths {
+            db.add_station_download(&StationDownload {
+                id: None,
+                station_id: station.id,
+                generation_id: "generation".to_owned(),
+                started: Utc::now(),
+                begin: 0,
+                end: 99,
+                path: path.to_string(),
+                uploaded: false,
+                finished: Some(Utc::now()),
+                size: Some(4096),
+                error: None,
+            })?;
+        }
+
+        Ok(Arc::new(Mutex::new(db)))
+    }
+
+    fn pending(db: &Arc<Mutex<Db>>) -> Result<Vec<String>> {
+        Ok(db
+            .lock()
+            .unwrap()
+            .get_pending_uploads()?
+            .into_iter()
+            .map(|d| d.path)
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn test_uploading_pending_files() -> Result<()> {
+        let db = open_db(&["a.fkpb", "b.fkpb"])?;
+        let uploader = ScriptedUploader::default();
+        let queue = UploadQueue::new(db.clone(), &uploader);
+
+        assert_eq!(
+            queue.upload_pending().await?,
+            Pass {
+                uploaded: 2,
+                ..Default::default()
+            }
+        );
+        assert!(pending(&db)?.is_empty());
+        assert_eq!(queue.upload_pending().await?, Pass::default());
+        assert_eq!(uploader.paths.lock().unwrap().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_imported_files_are_not_uploaded() -> Result<()> {
+        let dir = TempDir::new("fk-tests-uploads")?;
+        let device_path = dir.path().join("0011");
+        std::fs::create_dir_all(&device_path)?;
+        std::fs::write(device_path.join("20230710_120000.fkpb"), b"records")?;
+        std::fs::write(
+            device_path.join("20230710_120000.fkpb.json"),
+            r#"{"headers":{"Fk-DeviceId":"0011","Fk-Generation":"generation","Fk-Blocks":"0,9","Fk-Type":"data"}}"#,
+        )?;
+
+        let db = open_db(&["synced.fkpb"])?;
+        assert_eq!(
+            db.lock().unwrap().import_directory(dir.path())?.downloads,
+            1
+        );
+
+        let uploader = ScriptedUploader::default();
+        let queue = UploadQueue::new(db.clone(), &uploader);
+
+        assert_eq!(queue.upload_pending().await?.uploaded, 1);
+        assert_eq!(
+            *uploader.paths.lock().unwrap(),
+            vec!["synced.fkpb".to_owned()]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_waiting_while_offline() -> Result<()> {
+        let db = open_db(&["a.fkpb", "b.fkpb"])?;
+        let uploader = ScriptedUploader::new(vec![
+            Ok(()),
+            Err(PortalError::HttpStatus(StatusCode::BAD_GATEWAY)),
+        ]);
+        let queue = UploadQueue::new(db.clone(), &uploader);
+
+        let pass = queue.upload_pending().await?;
+        assert_eq!(pass.uploaded, 1);
+        assert!(pass.offline);
+        assert_eq!(pending(&db)?, vec!["b.fkpb".to_owned()]);
+
+        // A new queue, as after a restart, picks up where we left off.
+        let queue = UploadQueue::new(db.clone(), &uploader);
+        assert_eq!(queue.upload_pending().await?.uploaded, 1);
+        assert!(pending(&db)?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refused_files_are_skipped() -> Result<()> {
+        let db = open_db(&["a.fkpb", "b.fkpb"])?;
+        let uploader =
+            ScriptedUploader::new(vec![Err(PortalError::HttpStatus(StatusCode::BAD_REQUEST))]);
+        let queue = UploadQueue::new(db.clone(), &uploader);
+
+        let pass = queue.upload_pending().await?;
+        assert_eq!((pass.uploaded, pass.refused), (1, 1));
+        assert_eq!(pending(&db)?, vec!["a.fkpb".to_owned()]);
+
+        assert_eq!(queue.upload_pending().await?, Pass::default());
+
+        Ok(())
+    }
+}
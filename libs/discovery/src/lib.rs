@@ -1,8 +1,11 @@
 use anyhow::Result;
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
 };
+use thiserror::Error;
 use tokio::{net::UdpSocket, sync::mpsc::Sender};
 use tracing::*;
 
@@ -10,13 +13,97 @@ const MULTICAST_IP: [u8; 4] = [224, 1, 2, 3];
 const MULTICAST_PORT: u16 = 22143;
 const READ_BUFFER_SIZE: usize = 4096;
 const DEFAULT_UDP_SERVER_PORT: u16 = 22144;
+/// Most peers we keep malformed packet counts for.
+const MAXIMUM_ERROR_PEERS: usize = 256;
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct DeviceId(pub String);
 
-impl Into<String> for DeviceId {
-    fn into(self) -> String {
-        self.0
+impl From<DeviceId> for String {
+    fn from(value: DeviceId) -> Self {
+        value.0
+    }
+}
+
+/// Everything that can be wrong with a packet from a station, on either the
+/// discovery or the sync port.
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("Malformed packet")]
+    Malformed(#[from] quick_protobuf::Error),
+    #[error("Unknown message kind {0}")]
+    UnknownKind(u32),
+    #[error("Unexpected payload")]
+    UnexpectedPayload,
+    #[error("Invalid range {0}+{1}")]
+    InvalidRange(u64, u64),
+    #[error("Malformed records")]
+    MalformedRecords,
+    #[error("Malformed identity")]
+    MalformedIdentity,
+    #[error("Unexpected tag {0}")]
+    UnexpectedTag(u32),
+    #[error("Invalid port {0}")]
+    InvalidPort(i32),
+}
+
+/// Counts malformed packets from each peer. Sources are trivially spoofed,
+/// so once too many are being counted the one heard from least recently is
+/// forgotten.
+pub struct ErrorCounts<K> {
+    counts: HashMap<K, (u64, u64)>,
+    capacity: usize,
+    seen: u64,
+}
+
+impl<K: Clone + Eq + Hash> ErrorCounts<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            counts: HashMap::new(),
+            capacity,
+            seen: 0,
+        }
+    }
+
+    /// Counts another error from the peer, returning how many so far.
+    pub fn increment(&mut self, peer: K) -> u64 {
+        if self.counts.len() >= self.capacity && !self.counts.contains_key(&peer) {
+            let oldest = self
+                .counts
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(peer, _)| peer.clone());
+            if let Some(oldest) = oldest {
+                self.counts.remove(&oldest);
+            }
+        }
+
+        self.seen += 1;
+        let entry = self.counts.entry(peer).or_default();
+        entry.0 += 1;
+        entry.1 = self.seen;
+        entry.0
+    }
+
+    pub fn get(&self, peer: &K) -> u64 {
+        self.counts
+            .get(peer)
+            .map(|(count, _)| *count)
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+impl<K: Clone + Eq + Hash> Default for ErrorCounts<K> {
+    fn default() -> Self {
+        Self::new(MAXIMUM_ERROR_PEERS)
     }
 }
 
@@ -42,13 +129,21 @@ impl Discovery {
         let receiving = Arc::new(self.bind(&addr)?);
 
         let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        let mut errors: ErrorCounts<IpAddr> = ErrorCounts::default();
 
         loop {
             let (len, addr) = receiving.recv_from(&mut buffer[..]).await?;
             trace!("{} bytes from {}", len, addr);
 
             let bytes = &buffer[0..len];
-            let announced = Announce::parse(bytes)?;
+            let announced = match Announce::parse(bytes) {
+                Ok(announced) => announced,
+                Err(e) => {
+                    let count = errors.increment(addr.ip());
+                    warn!("{} malformed announce ({} so far): {}", addr, count, e);
+                    continue;
+                }
+            };
             let discovered = Discovered {
                 device_id: announced.device_id().clone(),
                 http_addr: announced
@@ -97,7 +192,7 @@ pub enum Announce {
 }
 
 impl Announce {
-    fn parse(bytes: &[u8]) -> Result<Self> {
+    fn parse(bytes: &[u8]) -> Result<Self, ProtocolError> {
         const DEVICE_ID_TAG: u32 = 1;
         const PORT_TAG: u32 = 4;
         use quick_protobuf::BytesReader;
@@ -105,13 +200,16 @@ impl Announce {
         let mut reader = BytesReader::from_bytes(bytes);
         let _size = reader.read_varint32(bytes)?;
         let tag = reader.next_tag(bytes)?;
-        assert_eq!(tag >> 3, DEVICE_ID_TAG);
+        if tag >> 3 != DEVICE_ID_TAG {
+            return Err(ProtocolError::UnexpectedTag(tag >> 3));
+        }
         let id_bytes = reader.read_bytes(bytes)?;
         let device_id = DeviceId(hex::encode(id_bytes));
         let port = if !reader.is_eof() {
             let tag = reader.next_tag(bytes)?;
             if tag >> 3 == PORT_TAG {
-                reader.read_int32(bytes)?
+                let port = reader.read_int32(bytes)?;
+                u16::try_from(port).map_err(|_| ProtocolError::InvalidPort(port))?
             } else {
                 80
             }
@@ -120,7 +218,7 @@ impl Announce {
         };
 
         if reader.is_eof() {
-            Ok(Announce::Hello(device_id, port as u16))
+            Ok(Announce::Hello(device_id, port))
         } else {
             Ok(Announce::Bye(device_id))
         }
@@ -140,3 +238,112 @@ impl Announce {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn announcement(fields: &[(u32, u32)]) -> Vec<u8> {
+        use quick_protobuf::Writer;
+
+        let mut message = Vec::new();
+        {
+            let mut writer = Writer::new(&mut message);
+            writer
+                .write_with_tag(1 << 3 | 2, |w| w.write_bytes(&[0x01, 0x02, 0x03]))
+                .expect("Writing device id");
+            for (tag, value) in fields {
+                writer
+                    .write_with_tag(tag << 3, |w| w.write_uint32(*value))
+                    .expect("Writing field");
+            }
+        }
+
+        let mut bytes = Vec::new();
+        {
+            let mut writer = Writer::new(&mut bytes);
+            writer.write_bytes(&message).expect("Delimiting");
+        }
+        bytes
+    }
+
+    #[test]
+    pub fn test_parse_hello() -> Result<()> {
+        match Announce::parse(&announcement(&[(4, 2380)]))? {
+            Announce::Hello(device_id, port) => {
+                assert_eq!(device_id, DeviceId("010203".to_owned()));
+                assert_eq!(port, 2380);
+            }
+            Announce::Bye(_) => panic!("Expected Hello"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_parse_bye() -> Result<()> {
+        assert!(matches!(
+            Announce::parse(&announcement(&[(4, 80), (5, 1)]))?,
+            Announce::Bye(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_error_counts_forget_least_recent_peer() {
+        let mut errors = ErrorCounts::new(2);
+
+        assert_eq!(errors.increment(1), 1);
+        assert_eq!(errors.increment(2), 1);
+        assert_eq!(errors.increment(1), 2);
+        assert_eq!(errors.increment(3), 1);
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors.get(&1), 2);
+        assert_eq!(errors.get(&2), 0);
+
+        for peer in 100..1000 {
+            errors.increment(peer);
+        }
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    pub fn test_parse_unexpected_tag() {
+        assert!(matches!(
+            Announce::parse(&[0x02, 2 << 3, 0x01]),
+            Err(ProtocolError::UnexpectedTag(2))
+        ));
+    }
+
+    #[test]
+    pub fn test_parse_invalid_port() {
+        assert!(matches!(
+            Announce::parse(&announcement(&[(4, 70000)])),
+            Err(ProtocolError::InvalidPort(70000))
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = Announce::parse(&bytes);
+        }
+
+        #[test]
+        fn test_parse_never_panics_when_mangled(
+            index in any::<prop::sample::Index>(),
+            value in any::<u8>(),
+            truncate in any::<prop::sample::Index>(),
+        ) {
+            let mut bytes = announcement(&[(4, 2380)]);
+            let i = index.index(bytes.len());
+            bytes[i] = value;
+            bytes.truncate(truncate.index(bytes.len() + 1));
+            let _ = Announce::parse(&bytes);
+        }
+    }
+}
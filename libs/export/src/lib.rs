@@ -0,0 +1,417 @@
+use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::{io::Write, path::Path, str::FromStr};
+use thiserror::Error;
+use tracing::*;
+
+use protos::{data::DeviceLocation, RecordsReader, ResolvedValue, Resolver};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown format: {0}")]
+pub struct UnknownFormat(String);
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" | "json-lines" => Ok(Self::JsonLines),
+            _ => Err(UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// Which rows to keep. Empty module and sensor lists keep everything.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub modules: Vec<String>,
+    pub sensors: Vec<String>,
+}
+
+impl Filter {
+    fn includes(&self, value: &ResolvedValue) -> bool {
+        let time = Utc.timestamp_opt(value.time, 0).single();
+        if let (Some(since), Some(time)) = (self.since, time) {
+            if time < since {
+                return false;
+            }
+        }
+        if let (Some(until), Some(time)) = (self.until, time) {
+            if time >= until {
+                return false;
+            }
+        }
+
+        (self.modules.is_empty() || self.modules.contains(&value.module))
+            && (self.sensors.is_empty() || self.sensors.contains(&value.sensor))
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Row {
+    pub time: String,
+    pub reading: u64,
+    pub module: String,
+    pub sensor: String,
+    pub value: f32,
+    pub uncalibrated: f32,
+    pub calibrated_uom: String,
+    pub uncalibrated_uom: String,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+}
+
+impl Row {
+    fn new(value: ResolvedValue, location: Option<&DeviceLocation>) -> Self {
+        let time = Utc
+            .timestamp_opt(value.time, 0)
+            .single()
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default();
+        let location = location.filter(|l| l.fix > 0);
+
+        Self {
+            time,
+            reading: value.reading,
+            module: value.module,
+            sensor: value.sensor,
+            value: value.value,
+            uncalibrated: value.uncalibrated,
+            calibrated_uom: value.calibrated_uom,
+            uncalibrated_uom: value.uncalibrated_uom,
+            latitude: location.map(|l| l.latitude),
+            longitude: location.map(|l| l.longitude),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub records: u64,
+    pub rows: u64,
+    /// Records that couldn't be decoded or resolved against the meta.
+    pub skipped: u64,
+}
+
+enum RowWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl<W: Write> RowWriter<W> {
+    fn new(format: Format, writer: W) -> Self {
+        match format {
+            Format::Csv => Self::Csv(Box::new(csv::Writer::from_writer(writer))),
+            Format::JsonLines => Self::JsonLines(writer),
+        }
+    }
+
+    fn write(&mut self, row: &Row) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.serialize(row)?,
+            Self::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, row)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.flush()?,
+            Self::JsonLines(writer) => writer.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes one row per sensor reading, a record at a time, so files of any
+/// size can be exported.
+pub struct Exporter {
+    resolver: Resolver,
+    format: Format,
+    filter: Filter,
+}
+
+impl Exporter {
+    pub fn new(resolver: Resolver, format: Format) -> Self {
+        Self {
+            resolver,
+            format,
+            filter: Filter::default(),
+        }
+    }
+
+    pub fn open(meta_path: &Path, format: Format) -> Result<Self> {
+        Ok(Self::new(Resolver::open(meta_path)?, format))
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn export_file<W: Write>(&self, data_path: &Path, writer: W) -> Result<Summary> {
+        self.export(RecordsReader::open(data_path)?, writer)
+    }
+
+    pub fn export<R: std::io::Read, W: Write>(
+        &self,
+        reader: RecordsReader<R>,
+        writer: W,
+    ) -> Result<Summary> {
+        let mut writer = RowWriter::new(self.format, writer);
+        let mut summary = Summary::default();
+
+        for read in reader {
+            let read = read?;
+            summary.records += 1;
+
+            let record = match read.record {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("#{} {:?}", read.number, e);
+                    summary.skipped += 1;
+                    continue;
+                }
+            };
+
+            let Some(readings) = record.readings else {
+                continue;
+            };
+
+            let values = match self.resolver.resolve(read.number, &readings) {
+                Ok(values) => values,
+                Err(e) => {
+                    warn!("#{} {}", read.number, e);
+                    summary.skipped += 1;
+                    continue;
+                }
+            };
+
+            for value in values.into_iter().filter(|v| self.filter.includes(v)) {
+                writer.write(&Row::new(value, readings.location.as_ref()))?;
+                summary.rows += 1;
+            }
+        }
+
+        writer.flush()?;
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use protos::data::{
+        DataRecord, Metadata, ModuleInfo, Readings, SensorAndValue, SensorGroup, SensorInfo,
+    };
+
+    use super::*;
+
+    const TIME: i64 = 1688659549;
+
+    fn resolver() -> Resolver {
+        let sensors = |names: &[&str]| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(number, name)| SensorInfo {
+                    number: number as u32,
+                    name: name.to_string(),
+                    unit_of_measure: "°C".to_owned(),
+                    uncalibrated_unit_of_measure: "mV".to_owned(),
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        let mut resolver = Resolver::new();
+        resolver.add(
+            0,
+            &DataRecord {
+                metadata: Some(Metadata::default()),
+                modules: vec![
+                    ModuleInfo {
+                        position: 0,
+                        name: "modules.water.temp".to_owned(),
+                        sensors: sensors(&["temp"]),
+                        ..Default::default()
+                    },
+                    ModuleInfo {
+                        position: 1,
+                        name: "modules.weather".to_owned(),
+                        sensors: sensors(&["wind", "rain"]),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            },
+        );
+        resolver
+    }
+
+    fn data(readings: u64) -> Vec<u8> {
+        (0..readings)
+            .flat_map(|reading| {
+                DataRecord {
+                    readings: Some(Readings {
+                        reading,
+                        time: TIME + reading as i64 * 60,
+                        location: Some(DeviceLocation {
+                            fix: 1,
+                            latitude: 34.0,
+                            longitude: -118.0,
+                            ..Default::default()
+                        }),
+                        sensor_groups: vec![
+                            SensorGroup {
+                                module: 0,
+                                readings: vec![SensorAndValue {
+                                    sensor: 0,
+                                    value: 20.0,
+                                    uncalibrated: 2000.0,
+                                }],
+                                ..Default::default()
+                            },
+                            SensorGroup {
+                                module: 1,
+                                readings: vec![
+                                    SensorAndValue {
+                                        sensor: 0,
+                                        value: 3.5,
+                                        uncalibrated: 350.0,
+                                    },
+                                    SensorAndValue {
+                                        sensor: 1,
+                                        value: 0.0,
+                                        uncalibrated: 0.0,
+                                    },
+                                ],
+                                ..Default::default()
+                            },
+                        ],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+                .encode_length_delimited_to_vec()
+            })
+            .collect()
+    }
+
+    fn export(exporter: &Exporter, bytes: &[u8]) -> Result<(String, Summary)> {
+        let mut out = Vec::new();
+        let summary = exporter.export(RecordsReader::new(bytes), &mut out)?;
+        Ok((String::from_utf8(out)?, summary))
+    }
+
+    #[test]
+    fn test_exports_csv() -> Result<()> {
+        let exporter = Exporter::new(resolver(), Format::Csv);
+
+        let (csv, summary) = export(&exporter, &data(2))?;
+
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                rows: 6,
+                skipped: 0
+            }
+        );
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            lines[0],
+            "time,reading,module,sensor,value,uncalibrated,calibrated_uom,uncalibrated_uom,latitude,longitude"
+        );
+        assert_eq!(
+            lines[1],
+            "2023-07-06T16:05:49+00:00,0,modules.water.temp,temp,20.0,2000.0,°C,mV,34.0,-118.0"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exports_json_lines() -> Result<()> {
+        let exporter = Exporter::new(resolver(), Format::JsonLines);
+
+        let (jsonl, _) = export(&exporter, &data(1))?;
+
+        let rows = jsonl
+            .lines()
+            .map(|l| Ok(serde_json::from_str::<serde_json::Value>(l)?))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1]["module"], "modules.weather");
+        assert_eq!(rows[1]["sensor"], "wind");
+        assert_eq!(rows[1]["latitude"], 34.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filters_rows() -> Result<()> {
+        let exporter = Exporter::new(resolver(), Format::JsonLines).with_filter(Filter {
+            since: Utc.timestamp_opt(TIME + 60, 0).single(),
+            until: Utc.timestamp_opt(TIME + 180, 0).single(),
+            modules: vec!["modules.weather".to_owned()],
+            sensors: vec!["rain".to_owned()],
+        });
+
+        let (jsonl, summary) = export(&exporter, &data(5))?;
+
+        let readings = jsonl
+            .lines()
+            .map(|l| Ok(serde_json::from_str::<serde_json::Value>(l)?["reading"].clone()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(readings, vec![1, 2]);
+        assert_eq!(summary.records, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skips_unresolvable_records() -> Result<()> {
+        let exporter = Exporter::new(Resolver::new(), Format::Csv);
+
+        let mut bytes = data(1);
+        bytes.extend([2, 0xff, 0xff]);
+
+        let (_, summary) = export(&exporter, &bytes)?;
+
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                rows: 0,
+                skipped: 2
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parses_format() {
+        assert_eq!("csv".parse::<Format>().ok(), Some(Format::Csv));
+        assert_eq!("jsonl".parse::<Format>().ok(), Some(Format::JsonLines));
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use anyhow::{Context, Result};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+use query::portal::{Client, Firmware};
+use store::{DeviceId, Station};
+
+mod upgrade;
+
+pub use upgrade::{UpgradeOutcome, UpgradeProgress, Upgrader};
+
+/// Portal listing kept in the cache so stations can be checked offline.
+const LISTING_FILE: &str = "firmware.json";
+
+/// What each station was last seen running, by device id. Stations don't
+/// say which module and profile their firmware was built for.
+const TARGETS_FILE: &str = "targets.json";
+
+#[derive(Error, Debug)]
+pub enum FirmwareError {
+    #[error("Downloaded {actual} of {expected} bytes")]
+    Incomplete { expected: u64, actual: u64 },
+    #[error("Checksum mismatch, expected {expected} got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("Firmware {0} isn't cached")]
+    NotCached(i64),
+}
+
+/// The kind of firmware a station runs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub module: String,
+    pub profile: String,
+}
+
+impl Target {
+    pub fn new(module: &str, profile: &str) -> Self {
+        Self {
+            module: module.to_owned(),
+            profile: profile.to_owned(),
+        }
+    }
+
+    /// The target a firmware was built for.
+    pub fn of(firmware: &Firmware) -> Self {
+        Self::new(&firmware.module, &firmware.profile)
+    }
+
+    fn matches(&self, firmware: &Firmware) -> bool {
+        firmware.module == self.module && firmware.profile == self.profile
+    }
+}
+
+/// The newest firmware for the target, by build time and then number.
+pub fn newest<'a>(firmwares: &'a [Firmware], target: &Target) -> Option<&'a Firmware> {
+    firmwares
+        .iter()
+        .filter(|f| target.matches(f))
+        .max_by_key(|f| (f.build_time, f.build_number))
+}
+
+#[derive(Clone, Debug)]
+pub enum FirmwareStatus {
+    UpToDate,
+    Outdated(Box<Firmware>),
+    /// The station runs a build newer than any available, usually a
+    /// development build.
+    Ahead,
+    /// Nothing available for the target.
+    Unavailable,
+    /// We don't know what the station's firmware was built for, so there's
+    /// nothing to compare it with.
+    UnknownTarget,
+}
+
+impl FirmwareStatus {
+    pub fn is_outdated(&self) -> bool {
+        matches!(self, FirmwareStatus::Outdated(_))
+    }
+}
+
+/// Compares what a station last reported running with the newest firmware
+/// for the target.
+pub fn status(station: &Station, firmwares: &[Firmware], target: &Target) -> FirmwareStatus {
+    let Some(newest) = newest(firmwares, target) else {
+        return FirmwareStatus::Unavailable;
+    };
+
+    let running = station.firmware.time;
+    let available = newest.build_time.timestamp();
+    if station.firmware.label == newest.version || running == available {
+        FirmwareStatus::UpToDate
+    } else if available > running {
+        FirmwareStatus::Outdated(Box::new(newest.clone()))
+    } else {
+        FirmwareStatus::Ahead
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StationFirmware {
+    pub device_id: DeviceId,
+    pub name: String,
+    pub running: store::Firmware,
+    pub target: Option<Target>,
+    pub status: FirmwareStatus,
+}
+
+/// Keeps firmware from the portal in a local directory, so stations can be
+/// checked and upgraded without a connection.
+pub struct FirmwareManager {
+    client: Client,
+    cache: PathBuf,
+}
+
+impl FirmwareManager {
+    pub fn new(client: Client, cache: &Path) -> Self {
+        Self {
+            client,
+            cache: cache.to_owned(),
+        }
+    }
+
+    /// Asks the portal what's available, keeping the listing for later.
+    pub async fn refresh(&self) -> Result<Vec<Firmware>> {
+        let firmwares = self.client.available_firmware().await?;
+
+        std::fs::create_dir_all(&self.cache)?;
+        write_json(&self.cache.join(LISTING_FILE), &firmwares)?;
+
+        info!("{} firmware(s) available", firmwares.len());
+
+        Ok(firmwares)
+    }
+
+    /// The listing from the last refresh, empty if there hasn't been one.
+    pub fn listing(&self) -> Result<Vec<Firmware>> {
+        let path = self.cache.join(LISTING_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let file = std::fs::File::open(&path)?;
+        serde_json::from_reader(file).with_context(|| format!("{:?}", path))
+    }
+
+    /// The target each station was last seen running, by device id.
+    pub fn targets(&self) -> Result<HashMap<String, Target>> {
+        let path = self.cache.join(TARGETS_FILE);
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let file = std::fs::File::open(&path)?;
+        serde_json::from_reader(file).with_context(|| format!("{:?}", path))
+    }
+
+    /// Keeps the target a station is running, for checking it later.
+    pub fn remember_target(&self, device_id: &str, target: &Target) -> Result<()> {
+        let mut targets = self.targets()?;
+        if targets.get(device_id) == Some(target) {
+            return Ok(());
+        }
+
+        targets.insert(device_id.to_owned(), target.clone());
+
+        std::fs::create_dir_all(&self.cache)?;
+        write_json(&self.cache.join(TARGETS_FILE), &targets)
+    }
+
+    /// Where the image is kept, cached or not. Images are keyed by id and
+    /// etag so a rebuilt firmware never hides behind an old download.
+    pub fn cached_path(&self, firmware: &Firmware) -> PathBuf {
+        let etag: String = firmware
+            .etag
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect();
+        self.cache.join(format!("{}-{}.bin", firmware.id, etag))
+    }
+
+    /// Downloads the image unless it's already cached, returning its path.
+    pub async fn download(&self, firmware: &Firmware) -> Result<PathBuf> {
+        let path = self.cached_path(firmware);
+        if path.exists() {
+            debug!("{:?} cached", path);
+            return Ok(path);
+        }
+
+        std::fs::create_dir_all(&self.cache)?;
+        let temp = path.with_extension("bin.tmp");
+
+        let downloaded = self.fetch(firmware, &temp).await;
+        if let Err(e) = downloaded.and_then(|_| verify(&temp, firmware)) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e);
+        }
+
+        std::fs::rename(&temp, &path)?;
+
+        info!("{:?} downloaded", path);
+
+        Ok(path)
+    }
+
+    /// The cached image, checked again in case it was damaged since.
+    pub fn cached(&self, firmware: &Firmware) -> Result<PathBuf> {
+        let path = self.cached_path(firmware);
+        if !path.exists() {
+            return Err(FirmwareError::NotCached(firmware.id).into());
+        }
+
+        verify(&path, firmware)?;
+
+        Ok(path)
+    }
+
+    /// How each station's firmware compares to what's available, using the
+    /// target each was last seen running unless one is given for them all.
+    pub fn report(
+        &self,
+        stations: &[Station],
+        firmwares: &[Firmware],
+        target: Option<&Target>,
+    ) -> Result<Vec<StationFirmware>> {
+        let targets = self.targets()?;
+
+        Ok(stations
+            .iter()
+            .map(|station| {
+                let target = target
+                    .or_else(|| targets.get(&station.device_id.0))
+                    .cloned();
+                let status = match &target {
+                    Some(target) => status(station, firmwares, target),
+                    None => FirmwareStatus::UnknownTarget,
+                };
+
+                StationFirmware {
+                    device_id: station.device_id.clone(),
+                    name: station.name.clone(),
+                    running: station.firmware.clone(),
+                    target,
+                    status,
+                }
+            })
+            .collect())
+    }
+
+    async fn fetch(&self, firmware: &Firmware, path: &Path) -> Result<()> {
+        let progress = self.client.download_firmware(firmware, path).await?;
+        tokio::pin!(progress);
+
+        let mut last = None;
+        while let Some(downloaded) = progress.next().await {
+            let downloaded = downloaded?;
+            trace!("{:?}", downloaded);
+            last = Some(downloaded);
+        }
+
+        let expected = last.as_ref().map(|d| d.total_bytes).unwrap_or_default();
+        let actual = std::fs::metadata(path)?.len();
+        if actual != expected {
+            return Err(FirmwareError::Incomplete { expected, actual }.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces `path` in one step, so readers never see half a file.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+/// Checks an image against its etag when that's an MD5, as it is for
+/// images the portal stored in a single part.
+fn verify(path: &Path, firmware: &Firmware) -> Result<()> {
+    let expected = firmware.etag.trim_matches('"').to_lowercase();
+    if expected.len() != 32 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+        debug!("{} etag isn't a checksum, skipping", firmware.id);
+        return Ok(());
+    }
+
+    let actual = hex::encode(Md5::digest(std::fs::read(path)?));
+    if actual != expected {
+        return Err(FirmwareError::ChecksumMismatch { expected, actual }.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tempdir::TempDir;
+
+    use super::*;
+
+    const IMAGE: &[u8] = b"firmware image";
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn firmware(id: i64, profile: &str, build_time: i64, etag: &str) -> Firmware {
+        Firmware {
+            id,
+            time: at(build_time),
+            etag: etag.to_owned(),
+            module: "fk-core".to_owned(),
+            profile: profile.to_owned(),
+            version: format!("1.0.{}", id),
+            url: format!("/firmware/{}/download", id),
+            build_number: id,
+            build_time: at(build_time),
+            meta: HashMap::new(),
+        }
+    }
+
+    fn station(label: &str, time: i64) -> Station {
+        Station {
+            id: None,
+            device_id: DeviceId("0011".to_owned()),
+            generation_id: "generation".to_owned(),
+            name: "Station".to_owned(),
+            firmware: store::Firmware {
+                label: label.to_owned(),
+                time,
+            },
+            last_seen: Utc::now(),
+            status: None,
+            meta: Default::default(),
+            data: Default::default(),
+            battery: Default::default(),
+            solar: Default::default(),
+            modules: Vec::new(),
+        }
+    }
+
+    fn target() -> Target {
+        Target::new("fk-core", "standard")
+    }
+
+    fn md5(bytes: &[u8]) -> String {
+        hex::encode(Md5::digest(bytes))
+    }
+
+    /// Serves the listing and images, counting image downloads.
+    fn serve(firmwares: Vec<Firmware>) -> Result<(String, Arc<Mutex<usize>>)> {
+        use query::test_server::{self, Body, Request, Response};
+
+        let downloads = Arc::new(Mutex::new(0));
+        let listing = serde_json::json!({ "firmwares": firmwares }).to_string();
+
+        let addr = test_server::serve({
+            let downloads = Arc::clone(&downloads);
+            move |req: Request<Body>| {
+                let body = if req.uri().path() == "/firmware" {
+                    Body::from(listing.clone())
+                } else {
+                    *downloads.lock().unwrap() += 1;
+                    Body::from(IMAGE)
+                };
+                async move { Response::new(body) }
+            }
+        })?;
+
+        Ok((format!("http://{}", addr), downloads))
+    }
+
+    #[test]
+    fn test_newest_for_target() {
+        let firmwares = vec![
+            firmware(1, "standard", 1000, ""),
+            firmware(2, "standard", 3000, ""),
+            firmware(3, "debug", 5000, ""),
+        ];
+
+        assert_eq!(newest(&firmwares, &target()).map(|f| f.id), Some(2));
+        assert!(newest(&firmwares, &Target::new("fk-core", "other")).is_none());
+    }
+
+    #[test]
+    fn test_station_status() {
+        let firmwares = vec![
+            firmware(1, "standard", 1000, ""),
+            firmware(2, "standard", 3000, ""),
+        ];
+
+        assert!(matches!(
+            status(&station("1.0.1", 1000), &firmwares, &target()),
+            FirmwareStatus::Outdated(f) if f.id == 2
+        ));
+        assert!(matches!(
+            status(&station("1.0.2", 3000), &firmwares, &target()),
+            FirmwareStatus::UpToDate
+        ));
+        assert!(matches!(
+            status(&station("1.1.0-dev", 4000), &firmwares, &target()),
+            FirmwareStatus::Ahead
+        ));
+        assert!(matches!(
+            status(&station("1.0.2", 3000), &[], &target()),
+            FirmwareStatus::Unavailable
+        ));
+    }
+
+    #[test]
+    fn test_report_uses_remembered_targets() -> Result<()> {
+        let dir = TempDir::new("fk-tests-firmware")?;
+        let manager = FirmwareManager::new(Client::new("http://127.0.0.1:0")?, dir.path());
+        let firmwares = vec![
+            firmware(1, "standard", 1000, ""),
+            firmware(2, "debug", 3000, ""),
+        ];
+        let stations = vec![station("1.0.1", 1000)];
+
+        let report = manager.report(&stations, &firmwares, None)?;
+        assert!(matches!(report[0].status, FirmwareStatus::UnknownTarget));
+        assert_eq!(report[0].target, None);
+
+        manager.remember_target("0011", &Target::new("fk-core", "debug"))?;
+        let report = manager.report(&stations, &firmwares, None)?;
+        assert!(matches!(&report[0].status, FirmwareStatus::Outdated(f) if f.id == 2));
+
+        let report = manager.report(&stations, &firmwares, Some(&target()))?;
+        assert!(matches!(report[0].status, FirmwareStatus::UpToDate));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_downloading_into_cache() -> Result<()> {
+        let dir = TempDir::new("fk-tests-firmware")?;
+        let (url, downloads) = serve(vec![firmware(1, "standard", 1000, &md5(IMAGE))])?;
+        let manager = FirmwareManager::new(Client::new(&url)?, dir.path());
+
+        let firmwares = manager.refresh().await?;
+        assert_eq!(manager.listing()?.len(), 1);
+
+        let path = manager.download(&firmwares[0]).await?;
+        assert_eq!(std::fs::read(&path)?, IMAGE);
+        assert_eq!(manager.download(&firmwares[0]).await?, path);
+        assert_eq!(*downloads.lock().unwrap(), 1);
+
+        assert_eq!(manager.cached(&firmwares[0])?, path);
+        std::fs::write(&path, b"damaged")?;
+        assert!(manager.cached(&firmwares[0]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejecting_mismatched_download() -> Result<()> {
+        let dir = TempDir::new("fk-tests-firmware")?;
+        let (url, _) = serve(vec![firmware(1, "standard", 1000, &md5(b"other"))])?;
+        let manager = FirmwareManager::new(Client::new(&url)?, dir.path());
+
+        let firmwares = manager.refresh().await?;
+        let err = manager.download(&firmwares[0]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FirmwareError>(),
+            Some(FirmwareError::ChecksumMismatch { .. })
+        ));
+        assert!(!manager.cached_path(&firmwares[0]).exists());
+        assert!(matches!(
+            manager
+                .cached(&firmwares[0])
+                .unwrap_err()
+                .downcast_ref::<FirmwareError>(),
+            Some(FirmwareError::NotCached(1))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::Instant};
+use tokio_stream::{Stream, StreamExt};
+use tracing::*;
+
+use discovery::{DeviceId, Discovered};
+use query::{
+    device::{self, UpgradeError},
+    portal::Firmware,
+    BytesUploaded,
+};
+
+use crate::{FirmwareManager, Target};
+
+pub type UpgradeProgress = Arc<dyn Fn(&BytesUploaded) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum UpgradeOutcome {
+    /// The station was already running the firmware, nothing was sent.
+    AlreadyCurrent,
+    /// Sent without swapping, the station runs it after its next restart.
+    Staged,
+    /// The station came back running the firmware.
+    Upgraded {
+        version: String,
+        hash: String,
+    },
+    /// The station came back running something else, usually because it
+    /// refused the image and kept the old one.
+    Mismatch {
+        expected: String,
+        running: String,
+    },
+    UploadFailed(UpgradeError),
+    /// We didn't hear from the station after it should have restarted.
+    TimedOut,
+}
+
+/// What the station says it's running.
+#[derive(Clone, Debug)]
+struct Running {
+    device_id: DeviceId,
+    version: String,
+    hash: String,
+    /// Milliseconds since the station started.
+    uptime: u32,
+}
+
+impl Running {
+    fn from_reply(reply: device::HttpReply) -> Result<Self> {
+        let status = reply
+            .status
+            .ok_or(device::DeviceError::MissingSection("status"))?;
+        let identity = status
+            .identity
+            .ok_or(device::DeviceError::MissingSection("identity"))?;
+        let firmware = status
+            .firmware
+            .ok_or(device::DeviceError::MissingSection("firmware"))?;
+
+        Ok(Self {
+            device_id: DeviceId(hex::encode(identity.device_id)),
+            version: firmware.version,
+            hash: firmware.hash,
+            uptime: status.uptime,
+        })
+    }
+
+    /// A different image, or the same one after a restart. Uptime only goes
+    /// backwards if `before` was taken long enough before the restart, so it
+    /// should be the last time we heard from the station.
+    fn restarted_since(&self, before: &Running) -> bool {
+        self.hash != before.hash || self.uptime < before.uptime
+    }
+}
+
+/// The station runs what we sent, so now we know what it was built for.
+fn remember_target(manager: &FirmwareManager, running: &Running, firmware: &Firmware) {
+    if let Err(e) = manager.remember_target(&running.device_id.0, &Target::of(firmware)) {
+        warn!("{:?} remembering target: {:?}", running.device_id, e);
+    }
+}
+
+/// Sends firmware from the cache to a station and confirms it restarted
+/// into it.
+pub struct Upgrader {
+    client: device::Client,
+    swap: bool,
+    timeout: Duration,
+    poll: Duration,
+    progress: Option<UpgradeProgress>,
+}
+
+impl Upgrader {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            client: device::Client::new()?,
+            swap: true,
+            timeout: Duration::from_secs(180),
+            poll: Duration::from_secs(5),
+            progress: None,
+        })
+    }
+
+    /// Whether the station switches to the new image and restarts once it
+    /// has it, on by default.
+    pub fn with_swap(mut self, swap: bool) -> Self {
+        self.swap = swap;
+        self
+    }
+
+    /// How long to wait for the station to come back after the upload.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_poll_interval(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    pub fn on_progress(
+        mut self,
+        progress: impl Fn(&BytesUploaded) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Upgrades the station at `addr`. Announcements from discovery, when
+    /// given, let us notice the station coming back sooner and follow it to
+    /// a new address.
+    pub async fn upgrade(
+        &self,
+        manager: &FirmwareManager,
+        firmware: &Firmware,
+        addr: &str,
+        mut discovered: Option<&mut mpsc::Receiver<Discovered>>,
+    ) -> Result<UpgradeOutcome> {
+        let image = manager.cached(firmware)?;
+
+        let before = self.running(addr).await?;
+        if before.version == firmware.version {
+            remember_target(manager, &before, firmware);
+            return Ok(UpgradeOutcome::AlreadyCurrent);
+        }
+
+        info!(
+            "{:?} upgrading {} to {}",
+            before.device_id, before.version, firmware.version
+        );
+
+        let uploading = self.client.upgrade(addr, &image, self.swap).await?;
+        if let Err(e) = self.send(uploading).await {
+            return Ok(UpgradeOutcome::UploadFailed(e));
+        }
+
+        if !self.swap {
+            return Ok(UpgradeOutcome::Staged);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut addr = addr.to_owned();
+        // The upload can take longer than the station had been up, so each
+        // answer is compared with the one before it.
+        let mut last = before.clone();
+        // When the station stopped answering. Busy stations miss the odd
+        // query too, so coming back only counts as a restart if it's running
+        // the new firmware or hasn't been up since it went away.
+        let mut went_away: Option<Instant> = None;
+
+        loop {
+            match self.running(&addr).await {
+                Ok(now)
+                    if now.restarted_since(&last)
+                        || now.version == firmware.version
+                        || went_away.is_some_and(|gone| {
+                            Duration::from_millis(now.uptime as u64) < gone.elapsed()
+                        }) =>
+                {
+                    return Ok(if now.version == firmware.version {
+                        remember_target(manager, &now, firmware);
+                        UpgradeOutcome::Upgraded {
+                            version: now.version,
+                            hash: now.hash,
+                        }
+                    } else {
+                        UpgradeOutcome::Mismatch {
+                            expected: firmware.version.clone(),
+                            running: now.version,
+                        }
+                    });
+                }
+                Ok(now) => {
+                    debug!("{:?} hasn't restarted", before.device_id);
+                    last = now;
+                    went_away = None;
+                }
+                Err(e) => {
+                    debug!("{:?} unavailable: {:?}", before.device_id, e);
+                    went_away.get_or_insert_with(Instant::now);
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(UpgradeOutcome::TimedOut);
+            }
+            let wait = tokio::time::sleep(std::cmp::min(self.poll, remaining));
+
+            match discovered.as_deref_mut() {
+                Some(rx) => tokio::select! {
+                    announced = rx.recv() => match announced {
+                        Some(Discovered { device_id, http_addr: Some(http_addr), .. })
+                            if device_id == before.device_id =>
+                        {
+                            addr = http_addr.to_string();
+                        }
+                        Some(_) => {}
+                        None => discovered = None,
+                    },
+                    _ = wait => {}
+                },
+                None => wait.await,
+            }
+        }
+    }
+
+    async fn running(&self, addr: &str) -> Result<Running> {
+        Running::from_reply(self.client.query_status(addr).await?)
+    }
+
+    async fn send(
+        &self,
+        uploading: impl Stream<Item = Result<BytesUploaded, UpgradeError>>,
+    ) -> Result<(), UpgradeError> {
+        tokio::pin!(uploading);
+
+        let mut completed = false;
+        while let Some(uploaded) = uploading.next().await {
+            let uploaded = uploaded?;
+            if let Some(progress) = &self.progress {
+                progress(&uploaded);
+            }
+            completed = uploaded.completed();
+        }
+
+        if completed {
+            Ok(())
+        } else {
+            Err(UpgradeError::Incomplete)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use md5::{Digest, Md5};
+    use prost::Message;
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::Mutex,
+    };
+    use tempdir::TempDir;
+
+    use query::device::{HttpReply, Identity, Status};
+    use query::portal::Client;
+
+    use super::*;
+
+    const IMAGE: &[u8] = b"firmware image";
+
+    /// How a station answers a query between receiving firmware and
+    /// restarting.
+    enum Answer {
+        Silent,
+        /// Still running the old image.
+        Old,
+    }
+
+    /// A station that restarts into whatever `boots` says once it has been
+    /// sent firmware and asked about once more, answering as `after_upload`
+    /// says until then.
+    struct FakeStation {
+        version: String,
+        hash: String,
+        uptime: u32,
+        boots: Option<(String, String)>,
+        /// Uptime when it's back, as if it was slow to be asked.
+        booted_uptime: u32,
+        after_upload: VecDeque<Answer>,
+        received: Vec<(String, usize)>,
+        restarting: bool,
+    }
+
+    impl FakeStation {
+        fn new(boots: Option<(&str, &str)>) -> Self {
+            Self {
+                version: "1.0.0".to_owned(),
+                hash: "old-hash".to_owned(),
+                uptime: 1000,
+                boots: boots.map(|(v, h)| (v.to_owned(), h.to_owned())),
+                booted_uptime: 0,
+                after_upload: VecDeque::new(),
+                received: Vec::new(),
+                restarting: false,
+            }
+        }
+
+        fn reply(&mut self) -> Option<HttpReply> {
+            if self.restarting {
+                match self.after_upload.pop_front() {
+                    Some(Answer::Silent) => return None,
+                    Some(Answer::Old) => {}
+                    None => {
+                        self.restarting = false;
+                        if let Some((version, hash)) = self.boots.take() {
+                            self.version = version;
+                            self.hash = hash;
+                            self.uptime = self.booted_uptime;
+                        }
+                    }
+                }
+            }
+            self.uptime += 1;
+
+            Some(HttpReply {
+                status: Some(Status {
+                    uptime: self.uptime,
+                    identity: Some(Identity {
+                        device_id: vec![0x00, 0x11],
+                        ..Default::default()
+                    }),
+                    firmware: Some(device::Firmware {
+                        version: self.version.clone(),
+                        hash: self.hash.clone(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn serve(station: FakeStation) -> Result<(String, Arc<Mutex<FakeStation>>)> {
+        use query::test_server::{self, to_bytes, Body, Request, Response};
+
+        let station = Arc::new(Mutex::new(station));
+
+        let addr = test_server::serve({
+            let station = Arc::clone(&station);
+            move |req: Request<Body>| {
+                let station = Arc::clone(&station);
+                async move {
+                    let path = req.uri().to_string();
+                    let body = to_bytes(req.into_body()).await.unwrap();
+                    let mut station = station.lock().unwrap();
+                    if path.starts_with("/fk/v1/upload/firmware") {
+                        station.received.push((path, body.len()));
+                        // Still answers once before restarting.
+                        station.restarting = true;
+                        return Response::new(Body::empty());
+                    }
+                    match station.reply() {
+                        Some(reply) => {
+                            Response::new(Body::from(reply.encode_length_delimited_to_vec()))
+                        }
+                        None => Response::builder().status(503).body(Body::empty()).unwrap(),
+                    }
+                }
+            }
+        })?;
+
+        Ok((addr.to_string(), station))
+    }
+
+    fn cached_firmware(dir: &TempDir) -> Result<(FirmwareManager, Firmware)> {
+        let time = Utc.timestamp_opt(1688659549, 0).unwrap();
+        let firmware = Firmware {
+            id: 1,
+            time,
+            etag: hex::encode(Md5::digest(IMAGE)),
+            module: "fk-core".to_owned(),
+            profile: "standard".to_owned(),
+            version: "1.0.1".to_owned(),
+            url: "/firmware/1/download".to_owned(),
+            build_number: 1,
+            build_time: time,
+            meta: HashMap::new(),
+        };
+        let manager = FirmwareManager::new(Client::new("http://127.0.0.1:0")?, dir.path());
+        std::fs::write(manager.cached_path(&firmware), IMAGE)?;
+        Ok((manager, firmware))
+    }
+
+    fn upgrader() -> Result<Upgrader> {
+        Ok(Upgrader::new()?
+            .with_poll_interval(Duration::from_millis(20))
+            .with_timeout(Duration::from_secs(2)))
+    }
+
+    #[tokio::test]
+    async fn test_upgrading_with_swap() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, station) = serve(FakeStation::new(Some(("1.0.1", "new-hash"))))?;
+
+        let uploaded = Arc::new(Mutex::new(0));
+        let upgrader = upgrader()?.on_progress({
+            let uploaded = Arc::clone(&uploaded);
+            move |progress| *uploaded.lock().unwrap() = progress.bytes_uploaded
+        });
+
+        match upgrader.upgrade(&manager, &firmware, &addr, None).await? {
+            UpgradeOutcome::Upgraded { version, hash } => {
+                assert_eq!(version, "1.0.1");
+                assert_eq!(hash, "new-hash");
+            }
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+
+        assert_eq!(*uploaded.lock().unwrap(), IMAGE.len() as u64);
+        assert_eq!(
+            station.lock().unwrap().received,
+            vec![("/fk/v1/upload/firmware?swap=1".to_owned(), IMAGE.len())]
+        );
+
+        // Nothing to do the second time around.
+        assert!(matches!(
+            upgrader.upgrade(&manager, &firmware, &addr, None).await?,
+            UpgradeOutcome::AlreadyCurrent
+        ));
+
+        assert_eq!(
+            manager.targets()?.get("0011"),
+            Some(&Target::new("fk-core", "standard"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upgrading_without_swap() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, station) = serve(FakeStation::new(None))?;
+
+        let outcome = upgrader()?
+            .with_swap(false)
+            .upgrade(&manager, &firmware, &addr, None)
+            .await?;
+        assert!(matches!(outcome, UpgradeOutcome::Staged));
+        assert_eq!(
+            station.lock().unwrap().received[0].0,
+            "/fk/v1/upload/firmware"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_station_returns_with_other_firmware() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, _) = serve(FakeStation::new(Some(("1.0.0", "old-hash"))))?;
+
+        match upgrader()?
+            .upgrade(&manager, &firmware, &addr, None)
+            .await?
+        {
+            UpgradeOutcome::Mismatch { expected, running } => {
+                assert_eq!(expected, "1.0.1");
+                assert_eq!(running, "1.0.0");
+            }
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_station_returns_after_going_away() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, _) = serve(FakeStation {
+            booted_uptime: 5000,
+            after_upload: VecDeque::from([Answer::Silent, Answer::Silent]),
+            ..FakeStation::new(Some(("1.0.1", "old-hash")))
+        })?;
+
+        match upgrader()?
+            .upgrade(&manager, &firmware, &addr, None)
+            .await?
+        {
+            UpgradeOutcome::Upgraded { version, .. } => assert_eq!(version, "1.0.1"),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_station_busy_before_restarting() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, _) = serve(FakeStation {
+            after_upload: VecDeque::from([Answer::Silent, Answer::Old]),
+            ..FakeStation::new(Some(("1.0.1", "new-hash")))
+        })?;
+
+        match upgrader()?
+            .upgrade(&manager, &firmware, &addr, None)
+            .await?
+        {
+            UpgradeOutcome::Upgraded { version, .. } => assert_eq!(version, "1.0.1"),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_station_returns_up_since_going_away() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        // Up only moments before the upload, so its uptime after refusing
+        // the image is still higher than before.
+        let (addr, _) = serve(FakeStation {
+            uptime: 0,
+            booted_uptime: 5,
+            after_upload: VecDeque::from([Answer::Silent, Answer::Silent]),
+            ..FakeStation::new(Some(("1.0.0", "old-hash")))
+        })?;
+
+        match upgrader()?
+            .upgrade(&manager, &firmware, &addr, None)
+            .await?
+        {
+            UpgradeOutcome::Mismatch { running, .. } => assert_eq!(running, "1.0.0"),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_station_never_returns() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, _) = serve(FakeStation::new(None))?;
+
+        let outcome = upgrader()?
+            .with_timeout(Duration::from_millis(200))
+            .upgrade(&manager, &firmware, &addr, None)
+            .await?;
+        assert!(matches!(outcome, UpgradeOutcome::TimedOut));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refuses_damaged_image() -> Result<()> {
+        let dir = TempDir::new("fk-tests-upgrade")?;
+        let (manager, firmware) = cached_firmware(&dir)?;
+        let (addr, station) = serve(FakeStation::new(None))?;
+        std::fs::write(manager.cached_path(&firmware), b"damaged")?;
+
+        assert!(upgrader()?
+            .upgrade(&manager, &firmware, &addr, None)
+            .await
+            .is_err());
+        assert!(station.lock().unwrap().received.is_empty());
+
+        Ok(())
+    }
+}
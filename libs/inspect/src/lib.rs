@@ -0,0 +1,430 @@
+use anyhow::Result;
+use std::{
+    fmt::Display,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use protos::{meta_path, FileMeta, ReadError, RecordsReader, StreamKind};
+
+/// Forward jumps in reading time larger than this are reported.
+pub const CLOCK_JUMP_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Counts {
+    pub readings: u64,
+    pub metadata: u64,
+    pub logs: u64,
+    pub faults: u64,
+    pub status: u64,
+    pub other: u64,
+    pub undecodable: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ClockJump {
+    pub record: u64,
+    pub from: i64,
+    pub to: i64,
+}
+
+/// Bytes after the last complete record.
+#[derive(Debug, PartialEq)]
+pub struct Trailing {
+    pub offset: u64,
+    pub bytes: u64,
+    pub error: String,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Problem {
+    #[error("Fk-Blocks is {0:?} but records are {1:?}")]
+    BlocksMismatch(RangeInclusive<u64>, Option<RangeInclusive<u64>>),
+    #[error("Fk-Blocks is malformed: {0}")]
+    MalformedBlocks(String),
+    #[error("Fk-DeviceId is {0} but records are from {1}")]
+    DeviceIdMismatch(String, String),
+    #[error("Fk-Generation is {0} but records are from {1}")]
+    GenerationMismatch(String, String),
+    #[error("Reading number went from {0} back to {1}")]
+    ReadingsOutOfOrder(u64, u64),
+}
+
+#[derive(Debug, Default)]
+pub struct Inspection {
+    pub path: PathBuf,
+    pub size: u64,
+    pub kind: Option<StreamKind>,
+    pub counts: Counts,
+    pub records: Option<RangeInclusive<u64>>,
+    pub readings: Option<RangeInclusive<u64>>,
+    pub times: Option<RangeInclusive<i64>>,
+    /// Reading numbers missing between consecutive readings.
+    pub gaps: Vec<RangeInclusive<u64>>,
+    pub clock_jumps: Vec<ClockJump>,
+    pub trailing: Option<Trailing>,
+    pub device_ids: Vec<String>,
+    pub generations: Vec<String>,
+    pub problems: Vec<Problem>,
+}
+
+impl Inspection {
+    pub fn is_healthy(&self) -> bool {
+        self.trailing.is_none() && self.problems.is_empty() && self.counts.undecodable == 0
+    }
+}
+
+fn widen<T: Copy + Ord>(range: Option<RangeInclusive<T>>, value: T) -> RangeInclusive<T> {
+    match range {
+        Some(r) => (*r.start()).min(value)..=(*r.end()).max(value),
+        None => value..=value,
+    }
+}
+
+fn push_unique(values: &mut Vec<String>, value: String) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+/// Walks every record in the file, checking it against its `.fkpb.json`.
+pub fn inspect(path: &Path) -> Result<Inspection> {
+    let meta_path = meta_path(path);
+    let meta = if meta_path.exists() {
+        Some(FileMeta::load_from_json_sync(&meta_path)?)
+    } else {
+        None
+    };
+
+    let reader = RecordsReader::open(path)?;
+    let mut inspection = Inspection {
+        path: path.to_owned(),
+        size: std::fs::metadata(path)?.len(),
+        kind: reader.kind(),
+        ..Default::default()
+    };
+
+    let mut previous: Option<(u64, i64)> = None;
+
+    for read in reader {
+        let read = match read {
+            Ok(read) => read,
+            Err(e) => {
+                let offset = match &e {
+                    ReadError::Truncated(offset)
+                    | ReadError::MalformedLength(offset)
+                    | ReadError::TooLarge(offset, _) => *offset,
+                    ReadError::Io(_) => return Err(e.into()),
+                };
+                inspection.trailing = Some(Trailing {
+                    offset,
+                    bytes: inspection.size - offset,
+                    error: e.to_string(),
+                });
+                break;
+            }
+        };
+
+        inspection.records = Some(widen(inspection.records.take(), read.number));
+
+        let record = match read.record {
+            Ok(record) => record,
+            Err(_) => {
+                inspection.counts.undecodable += 1;
+                continue;
+            }
+        };
+
+        let counts = &mut inspection.counts;
+        let mut known = false;
+        if let Some(readings) = &record.readings {
+            counts.readings += 1;
+            known = true;
+
+            inspection.readings = Some(widen(inspection.readings.take(), readings.reading));
+            inspection.times = Some(widen(inspection.times.take(), readings.time));
+
+            if let Some((number, time)) = previous {
+                if readings.reading > number + 1 {
+                    inspection.gaps.push(number + 1..=readings.reading - 1);
+                } else if readings.reading <= number {
+                    inspection
+                        .problems
+                        .push(Problem::ReadingsOutOfOrder(number, readings.reading));
+                }
+
+                if readings.time < time || readings.time - time > CLOCK_JUMP_SECONDS {
+                    inspection.clock_jumps.push(ClockJump {
+                        record: read.number,
+                        from: time,
+                        to: readings.time,
+                    });
+                }
+            }
+
+            previous = Some((readings.reading, readings.time));
+        }
+        if let Some(metadata) = &record.metadata {
+            counts.metadata += 1;
+            known = true;
+
+            if !metadata.device_id.is_empty() {
+                push_unique(&mut inspection.device_ids, hex::encode(&metadata.device_id));
+            }
+            if !metadata.generation.is_empty() {
+                push_unique(
+                    &mut inspection.generations,
+                    hex::encode(&metadata.generation),
+                );
+            }
+        }
+        if record.log.is_some() || !record.logs.is_empty() {
+            counts.logs += 1;
+            known = true;
+        }
+        if !record.faults.is_empty() {
+            counts.faults += 1;
+            known = true;
+        }
+        if record.status.is_some() {
+            counts.status += 1;
+            known = true;
+        }
+        if !known {
+            counts.other += 1;
+        }
+    }
+
+    if let Some(meta) = &meta {
+        check_headers(meta, &mut inspection);
+    }
+
+    Ok(inspection)
+}
+
+fn check_headers(meta: &FileMeta, inspection: &mut Inspection) {
+    match (meta.headers.get("Fk-Blocks"), meta.blocks()) {
+        (Some(_), Some(blocks)) => {
+            if inspection.records.as_ref() != Some(&blocks) {
+                inspection
+                    .problems
+                    .push(Problem::BlocksMismatch(blocks, inspection.records.clone()));
+            }
+        }
+        (Some(value), None) => inspection
+            .problems
+            .push(Problem::MalformedBlocks(value.clone())),
+        (None, _) => {}
+    }
+
+    if let Some(header) = meta.headers.get("Fk-DeviceId") {
+        for actual in inspection.device_ids.iter().filter(|id| *id != header) {
+            inspection
+                .problems
+                .push(Problem::DeviceIdMismatch(header.clone(), actual.clone()));
+        }
+    }
+
+    if let Some(header) = meta.headers.get("Fk-Generation") {
+        for actual in inspection.generations.iter().filter(|id| *id != header) {
+            inspection
+                .problems
+                .push(Problem::GenerationMismatch(header.clone(), actual.clone()));
+        }
+    }
+}
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} ({} bytes)", self.path.display(), self.size)?;
+        writeln!(f, "  kind: {:?}", self.kind)?;
+        writeln!(f, "  records: {:?}", self.records)?;
+        writeln!(
+            f,
+            "  readings: {} metadata: {} logs: {} faults: {} status: {} other: {} undecodable: {}",
+            self.counts.readings,
+            self.counts.metadata,
+            self.counts.logs,
+            self.counts.faults,
+            self.counts.status,
+            self.counts.other,
+            self.counts.undecodable
+        )?;
+        writeln!(f, "  reading numbers: {:?}", self.readings)?;
+        writeln!(f, "  times: {:?}", self.times)?;
+        for gap in self.gaps.iter() {
+            writeln!(f, "  gap: {:?}", gap)?;
+        }
+        for jump in self.clock_jumps.iter() {
+            writeln!(
+                f,
+                "  clock jump at #{}: {} -> {}",
+                jump.record, jump.from, jump.to
+            )?;
+        }
+        if let Some(trailing) = &self.trailing {
+            writeln!(
+                f,
+                "  trailing {} bytes at {}: {}",
+                trailing.bytes, trailing.offset, trailing.error
+            )?;
+        }
+        for problem in self.problems.iter() {
+            writeln!(f, "  problem: {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use protos::data::{DataRecord, LogMessage, Metadata, Readings};
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn readings(reading: u64, time: i64) -> DataRecord {
+        DataRecord {
+            readings: Some(Readings {
+                reading,
+                time,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn write(dir: &TempDir, records: &[DataRecord], headers: &[(&str, &str)]) -> Result<PathBuf> {
+        let path = dir.path().join("data.fkpb");
+        let bytes: Vec<u8> = records
+            .iter()
+            .flat_map(|r| r.encode_length_delimited_to_vec())
+            .collect();
+        std::fs::write(&path, bytes)?;
+
+        if !headers.is_empty() {
+            let meta = FileMeta {
+                headers: headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<_, _>>(),
+            };
+            std::fs::write(
+                format!("{}.json", path.display()),
+                serde_json::to_vec(&meta)?,
+            )?;
+        }
+
+        Ok(path)
+    }
+
+    #[test]
+    fn test_healthy_data_file() -> Result<()> {
+        let dir = TempDir::new("fk-tests-inspect")?;
+        let records: Vec<_> = (0..10).map(|i| readings(i, 1000 + i as i64 * 60)).collect();
+        let path = write(
+            &dir,
+            &records,
+            &[("Fk-Type", "data"), ("Fk-Blocks", "100,109")],
+        )?;
+
+        let inspection = inspect(&path)?;
+
+        assert!(inspection.is_healthy(), "{}", inspection);
+        assert_eq!(inspection.kind, Some(StreamKind::Data));
+        assert_eq!(inspection.counts.readings, 10);
+        assert_eq!(inspection.records, Some(100..=109));
+        assert_eq!(inspection.readings, Some(0..=9));
+        assert_eq!(inspection.times, Some(1000..=1540));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gaps_and_clock_jumps() -> Result<()> {
+        let dir = TempDir::new("fk-tests-inspect")?;
+        let path = write(
+            &dir,
+            &[
+                readings(0, 1000),
+                readings(1, 1060),
+                readings(5, 1120),
+                readings(6, 900),
+                readings(7, 1000 + CLOCK_JUMP_SECONDS * 2),
+            ],
+            &[],
+        )?;
+
+        let inspection = inspect(&path)?;
+
+        assert_eq!(inspection.gaps, vec![2..=4]);
+        assert_eq!(inspection.clock_jumps.len(), 2);
+        assert_eq!(inspection.clock_jumps[0].record, 3);
+        assert_eq!(inspection.clock_jumps[1].from, 900);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_partial_record() -> Result<()> {
+        let dir = TempDir::new("fk-tests-inspect")?;
+        let path = write(&dir, &[readings(0, 1000), readings(1, 1060)], &[])?;
+        let size = std::fs::metadata(&path)?.len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(size - 2)?;
+
+        let inspection = inspect(&path)?;
+
+        assert!(!inspection.is_healthy());
+        assert_eq!(inspection.counts.readings, 1);
+        let trailing = inspection.trailing.expect("No trailing");
+        assert_eq!(trailing.offset, readings(0, 1000).encoded_len() as u64 + 1);
+        assert_eq!(trailing.offset + trailing.bytes, size - 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_mismatches() -> Result<()> {
+        let dir = TempDir::new("fk-tests-inspect")?;
+        let meta = DataRecord {
+            metadata: Some(Metadata {
+                device_id: vec![0x00, 0x11],
+                generation: vec![0xaa, 0xbb],
+                ..Default::default()
+            }),
+            log: Some(LogMessage::default()),
+            ..Default::default()
+        };
+        let path = write(
+            &dir,
+            &[meta.clone(), meta],
+            &[
+                ("Fk-Type", "meta"),
+                ("Fk-Blocks", "0,5"),
+                ("Fk-DeviceId", "0011"),
+                ("Fk-Generation", "ccdd"),
+            ],
+        )?;
+
+        let inspection = inspect(&path)?;
+
+        assert_eq!(inspection.counts.metadata, 2);
+        assert_eq!(inspection.counts.logs, 2);
+        assert_eq!(
+            inspection.problems,
+            vec![
+                Problem::BlocksMismatch(0..=5, Some(0..=1)),
+                Problem::GenerationMismatch("ccdd".to_owned(), "aabb".to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -1,8 +1,20 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
 use tokio::{fs::File, io::AsyncReadExt};
 
+mod readings;
+mod records;
+
+pub use readings::{ModuleMeta, ResolveError, ResolvedValue, Resolver, SensorMeta, StationMeta};
+pub use records::{
+    AsyncRecordsReader, ReadError, ReadRecord, RecordsReader, StreamKind, MAXIMUM_RECORD_LENGTH,
+};
+
 pub mod data {
     include!("fk_data.rs");
 }
@@ -11,6 +23,11 @@ pub mod http {
     include!("fk_app.rs");
 }
 
+/// Where the `.fkpb.json` kept alongside a synced file lives.
+pub fn meta_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.json", path.display()))
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileMeta {
     pub headers: HashMap<String, String>,
@@ -23,4 +40,27 @@ impl FileMeta {
         file.read_to_string(&mut string).await?;
         Ok(serde_json::from_str(&string)?)
     }
+
+    pub fn load_from_json_sync(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn kind(&self) -> Option<StreamKind> {
+        self.headers
+            .get("Fk-Type")
+            .and_then(|v| StreamKind::from_header(v))
+    }
+
+    /// First and last record numbers, which is what Fk-Blocks holds despite
+    /// the name.
+    pub fn blocks(&self) -> Option<RangeInclusive<u64>> {
+        let (first, last) = self.headers.get("Fk-Blocks")?.split_once(',')?;
+        Some(first.trim().parse().ok()?..=last.trim().parse().ok()?)
+    }
+
+    /// Whether the file was merged from others, whose records it repeats.
+    pub fn is_merged(&self) -> bool {
+        self.headers.contains_key("Fk-Merged")
+    }
 }
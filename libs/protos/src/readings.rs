@@ -0,0 +1,382 @@
+use anyhow::Result;
+use std::{collections::BTreeMap, path::Path};
+use thiserror::Error;
+use tracing::*;
+
+use crate::{
+    data::{DataRecord, ModuleInfo, Readings},
+    RecordsReader,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorMeta {
+    pub number: u32,
+    pub key: String,
+    pub calibrated_uom: String,
+    pub uncalibrated_uom: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleMeta {
+    pub position: u32,
+    pub key: String,
+    pub sensors: Vec<SensorMeta>,
+}
+
+impl From<&ModuleInfo> for ModuleMeta {
+    fn from(value: &ModuleInfo) -> Self {
+        Self {
+            position: value.position,
+            key: value.name.clone(),
+            sensors: value
+                .sensors
+                .iter()
+                .map(|s| SensorMeta {
+                    number: s.number,
+                    key: s.name.clone(),
+                    calibrated_uom: s.unit_of_measure.clone(),
+                    uncalibrated_uom: s.uncalibrated_unit_of_measure.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What the station looked like as of a meta record.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StationMeta {
+    pub name: String,
+    pub device_id: String,
+    pub generation_id: String,
+    pub modules: Vec<ModuleMeta>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedValue {
+    /// Number of the data record the value came from.
+    pub record: u64,
+    pub reading: u64,
+    pub station: String,
+    pub device_id: String,
+    pub module_position: u32,
+    pub module: String,
+    pub sensor: String,
+    pub calibrated_uom: String,
+    pub uncalibrated_uom: String,
+    /// Seconds since the epoch.
+    pub time: i64,
+    pub value: f32,
+    pub uncalibrated: f32,
+}
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error("No meta record at or before {0}")]
+    MissingMeta(u64),
+    #[error("No module at position {1} in meta {0}")]
+    UnknownModule(u64, u32),
+    #[error("No sensor {2} on module {1} in meta {0}")]
+    UnknownSensor(u64, u32, u32),
+}
+
+/// Joins readings with the meta records they refer to, so each value can be
+/// labeled with the module and sensor that produced it.
+#[derive(Default)]
+pub struct Resolver {
+    meta: BTreeMap<u64, StationMeta>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a meta stream, skipping records that fail to decode.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut resolver = Self::new();
+        for read in RecordsReader::open(path)? {
+            let read = read?;
+            match &read.record {
+                Ok(record) => resolver.add(read.number, record),
+                Err(e) => warn!("{} meta #{} {:?}", path.display(), read.number, e),
+            }
+        }
+
+        Ok(resolver)
+    }
+
+    /// Meta records only carry what changed, so anything missing is taken
+    /// from the record before.
+    pub fn add(&mut self, number: u64, record: &DataRecord) {
+        let previous = self
+            .meta
+            .range(..number)
+            .next_back()
+            .map(|(_, m)| m.clone())
+            .unwrap_or_default();
+
+        let metadata = record.metadata.as_ref();
+        let modules = if !record.modules.is_empty() {
+            record.modules.iter().map(ModuleMeta::from).collect()
+        } else {
+            match metadata.filter(|m| !m.modules.is_empty()) {
+                Some(metadata) => metadata.modules.iter().map(ModuleMeta::from).collect(),
+                None => previous.modules,
+            }
+        };
+
+        let meta = StationMeta {
+            name: record
+                .identity
+                .as_ref()
+                .map(|i| i.name.clone())
+                .unwrap_or(previous.name),
+            device_id: metadata
+                .filter(|m| !m.device_id.is_empty())
+                .map(|m| hex::encode(&m.device_id))
+                .unwrap_or(previous.device_id),
+            generation_id: metadata
+                .filter(|m| !m.generation.is_empty())
+                .map(|m| hex::encode(&m.generation))
+                .unwrap_or(previous.generation_id),
+            modules,
+        };
+
+        self.meta.insert(number, meta);
+    }
+
+    /// The station as of the given meta record, or the closest one before it.
+    pub fn station(&self, meta: u64) -> Option<&StationMeta> {
+        self.meta.range(..=meta).next_back().map(|(_, m)| m)
+    }
+
+    pub fn resolve(
+        &self,
+        record: u64,
+        readings: &Readings,
+    ) -> Result<Vec<ResolvedValue>, ResolveError> {
+        let station = self
+            .station(readings.meta)
+            .ok_or(ResolveError::MissingMeta(readings.meta))?;
+
+        let mut values = Vec::new();
+        for group in readings.sensor_groups.iter() {
+            let module = station
+                .modules
+                .iter()
+                .find(|m| m.position == group.module)
+                .ok_or(ResolveError::UnknownModule(readings.meta, group.module))?;
+
+            let time = if group.time != 0 {
+                group.time
+            } else {
+                readings.time
+            };
+
+            for sav in group.readings.iter() {
+                let sensor = module
+                    .sensors
+                    .iter()
+                    .find(|s| s.number == sav.sensor)
+                    .ok_or(ResolveError::UnknownSensor(
+                        readings.meta,
+                        group.module,
+                        sav.sensor,
+                    ))?;
+
+                values.push(ResolvedValue {
+                    record,
+                    reading: readings.reading,
+                    station: station.name.clone(),
+                    device_id: station.device_id.clone(),
+                    module_position: module.position,
+                    module: module.key.clone(),
+                    sensor: sensor.key.clone(),
+                    calibrated_uom: sensor.calibrated_uom.clone(),
+                    uncalibrated_uom: sensor.uncalibrated_uom.clone(),
+                    time,
+                    value: sav.value,
+                    uncalibrated: sav.uncalibrated,
+                });
+            }
+        }
+
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Identity, Metadata, SensorAndValue, SensorGroup, SensorInfo};
+
+    fn module(position: u32, name: &str, sensors: &[&str]) -> ModuleInfo {
+        ModuleInfo {
+            position,
+            name: name.to_owned(),
+            sensors: sensors
+                .iter()
+                .enumerate()
+                .map(|(number, name)| SensorInfo {
+                    number: number as u32,
+                    name: name.to_string(),
+                    unit_of_measure: "°C".to_owned(),
+                    uncalibrated_unit_of_measure: "mV".to_owned(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn meta(modules: Vec<ModuleInfo>) -> DataRecord {
+        DataRecord {
+            metadata: Some(Metadata {
+                device_id: vec![0x00, 0x11, 0x22, 0x33],
+                generation: vec![0xaa, 0xbb],
+                ..Default::default()
+            }),
+            identity: Some(Identity {
+                name: "Early Impala 91".to_owned(),
+            }),
+            modules,
+            ..Default::default()
+        }
+    }
+
+    fn readings(meta: u64, groups: &[(u32, &[f32])]) -> Readings {
+        Readings {
+            reading: 7,
+            meta,
+            time: 1688659549,
+            sensor_groups: groups
+                .iter()
+                .map(|(module, values)| SensorGroup {
+                    module: *module,
+                    readings: values
+                        .iter()
+                        .enumerate()
+                        .map(|(sensor, value)| SensorAndValue {
+                            sensor: sensor as u32,
+                            value: *value,
+                            uncalibrated: value * 100.0,
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolves_labeled_values() -> Result<()> {
+        let mut resolver = Resolver::new();
+        resolver.add(0, &meta(vec![module(0, "modules.water.temp", &["temp"])]));
+
+        let values = resolver.resolve(42, &readings(0, &[(0, &[21.5])]))?;
+
+        assert_eq!(
+            values,
+            vec![ResolvedValue {
+                record: 42,
+                reading: 7,
+                station: "Early Impala 91".to_owned(),
+                device_id: "00112233".to_owned(),
+                module_position: 0,
+                module: "modules.water.temp".to_owned(),
+                sensor: "temp".to_owned(),
+                calibrated_uom: "°C".to_owned(),
+                uncalibrated_uom: "mV".to_owned(),
+                time: 1688659549,
+                value: 21.5,
+                uncalibrated: 2150.0,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolves_across_module_changes() -> Result<()> {
+        let mut resolver = Resolver::new();
+        resolver.add(0, &meta(vec![module(0, "modules.water.temp", &["temp"])]));
+        resolver.add(
+            1,
+            &meta(vec![module(0, "modules.water.ph", &["ph", "temp"])]),
+        );
+
+        let before = resolver.resolve(10, &readings(0, &[(0, &[21.5])]))?;
+        assert_eq!(before[0].module, "modules.water.temp");
+
+        let after = resolver.resolve(11, &readings(1, &[(0, &[7.0, 20.0])]))?;
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0].module, "modules.water.ph");
+        assert_eq!(after[1].sensor, "temp");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_carries_forward_unchanged_meta() -> Result<()> {
+        let mut resolver = Resolver::new();
+        resolver.add(0, &meta(vec![module(2, "modules.weather", &["wind"])]));
+        resolver.add(1, &DataRecord::default());
+
+        let station = resolver.station(1).expect("No station");
+        assert_eq!(station.name, "Early Impala 91");
+        assert_eq!(station.modules.len(), 1);
+
+        // Readings may refer to meta we never saw, the closest before wins.
+        let values = resolver.resolve(0, &readings(5, &[(2, &[3.0])]))?;
+        assert_eq!(values[0].module, "modules.weather");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_opens_meta_stream() -> Result<()> {
+        use prost::Message;
+
+        let dir = tempdir::TempDir::new("fk-tests-readings")?;
+        let path = dir.path().join("meta.fkpb");
+        let mut bytes =
+            meta(vec![module(0, "modules.water.temp", &["temp"])]).encode_length_delimited_to_vec();
+        bytes.extend([2, 0xff, 0xff]);
+        bytes.extend(
+            meta(vec![module(0, "modules.water.ph", &["ph"])]).encode_length_delimited_to_vec(),
+        );
+        std::fs::write(&path, bytes)?;
+
+        let resolver = Resolver::open(&path)?;
+        assert_eq!(
+            resolver.station(0).map(|s| s.modules[0].key.as_str()),
+            Some("modules.water.temp")
+        );
+        assert_eq!(
+            resolver.station(2).map(|s| s.modules[0].key.as_str()),
+            Some("modules.water.ph")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut resolver = Resolver::new();
+        assert!(matches!(
+            resolver.resolve(0, &readings(0, &[])),
+            Err(ResolveError::MissingMeta(0))
+        ));
+
+        resolver.add(0, &meta(vec![module(0, "modules.water.temp", &["temp"])]));
+        assert!(matches!(
+            resolver.resolve(0, &readings(0, &[(1, &[1.0])])),
+            Err(ResolveError::UnknownModule(0, 1))
+        ));
+        assert!(matches!(
+            resolver.resolve(0, &readings(0, &[(0, &[1.0, 2.0])])),
+            Err(ResolveError::UnknownSensor(0, 0, 1))
+        ));
+    }
+}
@@ -0,0 +1,504 @@
+use anyhow::Result;
+use prost::Message;
+use std::{
+    io::{BufReader, ErrorKind, Read},
+    path::Path,
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{data::DataRecord, meta_path, FileMeta};
+
+/// Anything larger is assumed to be a corrupt length rather than a record,
+/// so we never try to allocate it.
+pub const MAXIMUM_RECORD_LENGTH: u64 = 1024 * 1024;
+
+/// Longest varint a u64 length can be encoded in.
+const MAXIMUM_LENGTH_BYTES: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Data,
+    Meta,
+}
+
+impl StreamKind {
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value {
+            "data" => Some(Self::Data),
+            "meta" => Some(Self::Meta),
+            _ => None,
+        }
+    }
+}
+
+/// A record read from a stream. Decoding failures are kept per record, the
+/// framing around them is intact and reading can continue.
+#[derive(Debug)]
+pub struct ReadRecord {
+    pub number: u64,
+    /// Byte offset of the record's length prefix.
+    pub offset: u64,
+    pub length: u64,
+    pub record: Result<DataRecord, prost::DecodeError>,
+}
+
+impl ReadRecord {
+    /// Byte offset just past the record.
+    pub fn end(&self) -> u64 {
+        self.offset + prost::length_delimiter_len(self.length as usize) as u64 + self.length
+    }
+}
+
+/// Framing errors, after which nothing more can be read from the stream.
+#[derive(Error, Debug)]
+pub enum ReadError {
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+    #[error("Truncated record at {0}")]
+    Truncated(u64),
+    #[error("Malformed length at {0}")]
+    MalformedLength(u64),
+    #[error("Record at {0} too large ({1} bytes)")]
+    TooLarge(u64, u64),
+}
+
+#[derive(Default)]
+struct Varint {
+    value: u64,
+    bytes: u64,
+}
+
+impl Varint {
+    /// Returns true once the final byte has been pushed.
+    fn push(&mut self, byte: u8, offset: u64) -> Result<bool, ReadError> {
+        if self.bytes == MAXIMUM_LENGTH_BYTES {
+            return Err(ReadError::MalformedLength(offset));
+        }
+        self.value |= ((byte & 0x7f) as u64) << (7 * self.bytes);
+        self.bytes += 1;
+        Ok(byte & 0x80 == 0)
+    }
+}
+
+#[derive(Default)]
+struct Position {
+    number: u64,
+    offset: u64,
+    done: bool,
+}
+
+impl Position {
+    fn check_length(&self, varint: &Varint) -> Result<(), ReadError> {
+        if varint.value > MAXIMUM_RECORD_LENGTH {
+            Err(ReadError::TooLarge(self.offset, varint.value))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read(&mut self, varint: &Varint, bytes: &[u8]) -> ReadRecord {
+        let record = ReadRecord {
+            number: self.number,
+            offset: self.offset,
+            length: varint.value,
+            record: DataRecord::decode(bytes),
+        };
+
+        self.number += 1;
+        self.offset += varint.bytes + varint.value;
+
+        record
+    }
+
+    fn finish<T>(&mut self, res: Result<Option<T>, ReadError>) -> Option<Result<T, ReadError>> {
+        match res {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn truncated_on_eof(e: std::io::Error, offset: u64) -> ReadError {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        ReadError::Truncated(offset)
+    } else {
+        ReadError::Io(e)
+    }
+}
+
+/// Where a stream's records start and what kind they are, from the
+/// `.fkpb.json` beside it.
+fn describe(meta: Option<&FileMeta>) -> (Option<StreamKind>, u64) {
+    let kind = meta.and_then(|m| m.kind());
+    let first = meta
+        .and_then(|m| m.blocks())
+        .map(|b| *b.start())
+        .unwrap_or_default();
+    (kind, first)
+}
+
+/// Reads length delimited `DataRecord`s one at a time. Reads a byte at a
+/// time while decoding lengths, so `R` should be buffered.
+pub struct RecordsReader<R> {
+    reader: R,
+    kind: Option<StreamKind>,
+    position: Position,
+}
+
+impl RecordsReader<BufReader<std::fs::File>> {
+    /// Opens a synced file, numbering records from its `.fkpb.json` if present.
+    pub fn open(path: &Path) -> Result<Self> {
+        let meta_path = meta_path(path);
+        let meta = if meta_path.exists() {
+            Some(FileMeta::load_from_json_sync(&meta_path)?)
+        } else {
+            None
+        };
+        let (kind, first) = describe(meta.as_ref());
+
+        Ok(Self {
+            kind,
+            ..Self::new(BufReader::new(std::fs::File::open(path)?)).starting_at(first)
+        })
+    }
+}
+
+impl<R: Read> RecordsReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            kind: None,
+            position: Position::default(),
+        }
+    }
+
+    pub fn starting_at(mut self, number: u64) -> Self {
+        self.position.number = number;
+        self
+    }
+
+    pub fn kind(&self) -> Option<StreamKind> {
+        self.kind
+    }
+
+    fn read_next(&mut self) -> Result<Option<ReadRecord>, ReadError> {
+        let offset = self.position.offset;
+        let mut varint = Varint::default();
+        let mut byte = [0u8; 1];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) if varint.bytes == 0 => return Ok(None),
+                Ok(0) => return Err(ReadError::Truncated(offset)),
+                Ok(_) => {
+                    if varint.push(byte[0], offset)? {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.position.check_length(&varint)?;
+
+        let mut bytes = vec![0u8; varint.value as usize];
+        self.reader
+            .read_exact(&mut bytes)
+            .map_err(|e| truncated_on_eof(e, offset))?;
+
+        Ok(Some(self.position.read(&varint, &bytes)))
+    }
+}
+
+impl<R: Read> Iterator for RecordsReader<R> {
+    type Item = Result<ReadRecord, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position.done {
+            return None;
+        }
+        let res = self.read_next();
+        self.position.finish(res)
+    }
+}
+
+/// The async twin of `RecordsReader`.
+pub struct AsyncRecordsReader<R> {
+    reader: R,
+    kind: Option<StreamKind>,
+    position: Position,
+}
+
+impl AsyncRecordsReader<tokio::io::BufReader<tokio::fs::File>> {
+    /// Opens a synced file, numbering records from its `.fkpb.json` if present.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let meta_path = meta_path(path);
+        let meta = if tokio::fs::try_exists(&meta_path).await? {
+            Some(FileMeta::load_from_json(&meta_path).await?)
+        } else {
+            None
+        };
+        let (kind, first) = describe(meta.as_ref());
+        let file = tokio::fs::File::open(path).await?;
+
+        Ok(Self {
+            kind,
+            ..Self::new(tokio::io::BufReader::new(file)).starting_at(first)
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRecordsReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            kind: None,
+            position: Position::default(),
+        }
+    }
+
+    pub fn starting_at(mut self, number: u64) -> Self {
+        self.position.number = number;
+        self
+    }
+
+    pub fn kind(&self) -> Option<StreamKind> {
+        self.kind
+    }
+
+    pub async fn next(&mut self) -> Option<Result<ReadRecord, ReadError>> {
+        if self.position.done {
+            return None;
+        }
+        let res = self.read_next().await;
+        self.position.finish(res)
+    }
+
+    async fn read_next(&mut self) -> Result<Option<ReadRecord>, ReadError> {
+        let offset = self.position.offset;
+        let mut varint = Varint::default();
+        loop {
+            match self.reader.read_u8().await {
+                Ok(byte) => {
+                    if varint.push(byte, offset)? {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && varint.bytes == 0 => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(truncated_on_eof(e, offset)),
+            }
+        }
+
+        self.position.check_length(&varint)?;
+
+        let mut bytes = vec![0u8; varint.value as usize];
+        self.reader
+            .read_exact(&mut bytes)
+            .await
+            .map_err(|e| truncated_on_eof(e, offset))?;
+
+        Ok(Some(self.position.read(&varint, &bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::data::Readings;
+
+    fn readings(reading: u64) -> DataRecord {
+        DataRecord {
+            readings: Some(Readings {
+                reading,
+                time: 1688659549 + reading as i64,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn stream(records: &[DataRecord]) -> Vec<u8> {
+        records
+            .iter()
+            .flat_map(|r| r.encode_length_delimited_to_vec())
+            .collect()
+    }
+
+    fn reading_number(read: &ReadRecord) -> Option<u64> {
+        read.record
+            .as_ref()
+            .ok()
+            .and_then(|r| r.readings.as_ref())
+            .map(|r| r.reading)
+    }
+
+    #[test]
+    fn test_reads_numbers_and_offsets() -> Result<()> {
+        let records: Vec<_> = (0..300).map(readings).collect();
+        let bytes = stream(&records);
+
+        let read = RecordsReader::new(bytes.as_slice())
+            .starting_at(1000)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(read.len(), 300);
+        for (i, read) in read.iter().enumerate() {
+            assert_eq!(read.number, 1000 + i as u64);
+            assert_eq!(reading_number(read), Some(i as u64));
+            let mut at = &bytes[read.offset as usize..];
+            assert_eq!(DataRecord::decode_length_delimited(&mut at)?, records[i]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_continues_past_undecodable_record() -> Result<()> {
+        let mut bytes = stream(&[readings(0)]);
+        bytes.extend([3, 0xff, 0xff, 0xff]);
+        bytes.extend(stream(&[readings(2)]));
+
+        let read = RecordsReader::new(bytes.as_slice()).collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(read.len(), 3);
+        assert!(read[1].record.is_err());
+        assert_eq!(read[1].length, 3);
+        assert_eq!(reading_number(&read[2]), Some(2));
+        assert_eq!(read[2].number, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        let mut bytes = stream(&[readings(0), readings(1)]);
+        let second = readings(0).encoded_len() as u64 + 1;
+        bytes.truncate(bytes.len() - 2);
+
+        let read: Vec<_> = RecordsReader::new(bytes.as_slice()).collect();
+
+        assert_eq!(read.len(), 2);
+        assert!(read[0].is_ok());
+        assert!(matches!(read[1], Err(ReadError::Truncated(offset)) if offset == second));
+    }
+
+    #[test]
+    fn test_refuses_huge_length() {
+        let mut bytes = Vec::new();
+        prost::encoding::encode_varint(MAXIMUM_RECORD_LENGTH + 1, &mut bytes);
+        bytes.extend([0; 16]);
+
+        let read: Vec<_> = RecordsReader::new(bytes.as_slice()).collect();
+
+        assert!(matches!(read.as_slice(), [Err(ReadError::TooLarge(0, _))]));
+    }
+
+    #[test]
+    fn test_malformed_length() {
+        let bytes = vec![0xff; 16];
+
+        let read: Vec<_> = RecordsReader::new(bytes.as_slice()).collect();
+
+        assert!(matches!(
+            read.as_slice(),
+            [Err(ReadError::MalformedLength(0))]
+        ));
+    }
+
+    fn write_synced(dir: &TempDir, kind: &str) -> Result<PathBuf> {
+        let path = dir.path().join("20230710_120000.fkpb");
+        std::fs::write(&path, stream(&(0..10).map(readings).collect::<Vec<_>>()))?;
+
+        let meta = FileMeta {
+            headers: HashMap::from([
+                ("Fk-Type".to_owned(), kind.to_owned()),
+                ("Fk-Blocks".to_owned(), "500,509".to_owned()),
+            ]),
+        };
+        std::fs::write(meta_path(&path), serde_json::to_vec(&meta)?)?;
+
+        Ok(path)
+    }
+
+    #[test]
+    fn test_opens_with_file_meta() -> Result<()> {
+        let dir = TempDir::new("fk-tests-records")?;
+        let path = write_synced(&dir, "data")?;
+
+        let reader = RecordsReader::open(&path)?;
+        assert_eq!(reader.kind(), Some(StreamKind::Data));
+
+        let numbers = reader.map(|r| Ok(r?.number)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(numbers, (500..=509).collect::<Vec<_>>());
+
+        let path = write_synced(&dir, "meta")?;
+        assert_eq!(RecordsReader::open(&path)?.kind(), Some(StreamKind::Meta));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_opens_without_file_meta() -> Result<()> {
+        let dir = TempDir::new("fk-tests-records")?;
+        let path = dir.path().join("bare.fkpb");
+        std::fs::write(&path, stream(&[readings(0)]))?;
+
+        let mut reader = RecordsReader::open(&path)?;
+        assert_eq!(reader.kind(), None);
+        assert_eq!(reader.next().transpose()?.map(|r| r.number), Some(0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_matches_sync() -> Result<()> {
+        let dir = TempDir::new("fk-tests-records")?;
+        let path = write_synced(&dir, "data")?;
+
+        let mut reader = AsyncRecordsReader::open(&path).await?;
+        assert_eq!(reader.kind(), Some(StreamKind::Data));
+
+        let mut read = Vec::new();
+        while let Some(record) = reader.next().await {
+            let record = record?;
+            read.push((record.number, record.offset, reading_number(&record)));
+        }
+
+        let expected = RecordsReader::open(&path)?
+            .map(|r| {
+                let r = r?;
+                Ok((r.number, r.offset, reading_number(&r)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(read, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_truncated_stream() {
+        let mut bytes = stream(&[readings(0)]);
+        bytes.pop();
+
+        let mut reader = AsyncRecordsReader::new(bytes.as_slice());
+        assert!(matches!(
+            reader.next().await,
+            Some(Err(ReadError::Truncated(0)))
+        ));
+        assert!(reader.next().await.is_none());
+    }
+}
@@ -6,7 +6,6 @@ use std::io::Cursor;
 use std::path::Path;
 use std::time::Duration;
 use thiserror::Error;
-use tokio::fs::File;
 use tokio_stream::{Stream, StreamExt};
 use tokio_util::io::ReaderStream;
 use tracing::*;
@@ -23,8 +22,87 @@ pub struct Client {
 pub enum UpgradeError {
     #[error("Server error")]
     ServerError,
+    #[error("Station refused upload: {0}")]
+    Refused(reqwest::StatusCode),
+    #[error("Upload failed")]
+    Request(#[from] reqwest::Error),
+    #[error("Upload incomplete")]
+    Incomplete,
 }
 
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("Station error: {0:?}")]
+    Reply(Vec<String>),
+    #[error("Station busy")]
+    Busy,
+    #[error("Reply missing {0}")]
+    MissingSection(&'static str),
+    #[error("Interval too long: {0:?}")]
+    IntervalTooLong(Duration),
+}
+
+/// How often the station does something, for the schedules that can be
+/// configured. Schedules left as None are unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct ScheduleSettings {
+    pub readings: Option<Duration>,
+    pub network: Option<Duration>,
+    pub lora: Option<Duration>,
+    pub gps: Option<Duration>,
+}
+
+impl ScheduleSettings {
+    fn to_schedules(&self) -> Result<Schedules, DeviceError> {
+        fn every(interval: &Option<Duration>) -> Result<Option<Schedule>, DeviceError> {
+            let Some(interval) = interval else {
+                return Ok(None);
+            };
+            let seconds = u32::try_from(interval.as_secs())
+                .map_err(|_| DeviceError::IntervalTooLong(*interval))?;
+
+            Ok(Some(Schedule {
+                interval: seconds,
+                intervals: vec![Interval {
+                    start: 0,
+                    end: SECONDS_PER_DAY,
+                    interval: seconds,
+                }],
+                ..Default::default()
+            }))
+        }
+
+        Ok(Schedules {
+            modifying: true,
+            readings: every(&self.readings)?,
+            network: every(&self.network)?,
+            lora: every(&self.lora)?,
+            gps: every(&self.gps)?,
+        })
+    }
+}
+
+/// A WiFi network for the station to join.
+#[derive(Clone, Debug)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+    pub preferred: bool,
+}
+
+impl From<&WifiNetwork> for NetworkInfo {
+    fn from(value: &WifiNetwork) -> Self {
+        NetworkInfo {
+            ssid: value.ssid.clone(),
+            password: value.password.clone(),
+            preferred: value.preferred,
+            ..Default::default()
+        }
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 86400;
+
 impl Client {
     pub fn new() -> Result<Self> {
         let mut headers = HeaderMap::new();
@@ -46,16 +124,185 @@ impl Client {
     }
 
     pub async fn query_readings(&self, addr: &str) -> Result<HttpReply> {
-        let mut query = HttpQuery::default();
-        query.r#type = QueryType::QueryGetReadings as i32;
+        let query = HttpQuery {
+            r#type: QueryType::QueryGetReadings as i32,
+            ..Default::default()
+        };
         let encoded = query.encode_length_delimited_to_vec();
         let req = self.new_request(addr)?.body(encoded).build()?;
         self.execute(req).await
     }
 
+    pub async fn configure_identity(&self, addr: &str, name: &str) -> Result<Identity> {
+        let reply = self
+            .query(
+                addr,
+                HttpQuery {
+                    r#type: QueryType::QueryConfigureIdentity as i32,
+                    identity: Some(Identity {
+                        name: name.to_owned(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(reply
+            .status
+            .and_then(|s| s.identity)
+            .ok_or(DeviceError::MissingSection("identity"))?)
+    }
+
+    pub async fn start_recording(&self, addr: &str) -> Result<Recording> {
+        self.recording_control(addr, true).await
+    }
+
+    pub async fn stop_recording(&self, addr: &str) -> Result<Recording> {
+        self.recording_control(addr, false).await
+    }
+
+    pub async fn configure_schedules(
+        &self,
+        addr: &str,
+        schedules: &ScheduleSettings,
+    ) -> Result<Schedules> {
+        let reply = self
+            .query(
+                addr,
+                HttpQuery {
+                    r#type: QueryType::QueryConfigureSchedules as i32,
+                    schedules: Some(schedules.to_schedules()?),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(reply
+            .schedules
+            .ok_or(DeviceError::MissingSection("schedules"))?)
+    }
+
+    pub async fn configure_networks(
+        &self,
+        addr: &str,
+        networks: &[WifiNetwork],
+    ) -> Result<NetworkSettings> {
+        let reply = self
+            .query(
+                addr,
+                HttpQuery {
+                    r#type: QueryType::QueryConfigureNetworkSettings as i32,
+                    network_settings: Some(NetworkSettings {
+                        modifying: true,
+                        networks: networks.iter().map(|n| n.into()).collect(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(reply
+            .network_settings
+            .ok_or(DeviceError::MissingSection("network settings"))?)
+    }
+
+    /// Returns the SSIDs of the networks the station can see.
+    pub async fn scan_networks(&self, addr: &str) -> Result<Vec<String>> {
+        let reply = self
+            .query(addr, Self::new_query(QueryType::QueryScanNetworks))
+            .await?;
+
+        Ok(reply
+            .nearby_networks
+            .ok_or(DeviceError::MissingSection("nearby networks"))?
+            .networks
+            .into_iter()
+            .map(|n| n.ssid)
+            .collect())
+    }
+
+    pub async fn scan_modules(&self, addr: &str) -> Result<Vec<ModuleCapabilities>> {
+        let reply = self
+            .query(addr, Self::new_query(QueryType::QueryScanModules))
+            .await?;
+
+        Ok(reply.modules)
+    }
+
+    pub async fn take_readings(&self, addr: &str) -> Result<Vec<LiveReadings>> {
+        let reply = self
+            .query(addr, Self::new_query(QueryType::QueryTakeReadings))
+            .await?;
+
+        Ok(reply.live_readings)
+    }
+
+    pub async fn reset(&self, addr: &str) -> Result<()> {
+        self.query(addr, Self::new_query(QueryType::QueryReset))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn format(&self, addr: &str) -> Result<()> {
+        self.query(addr, Self::new_query(QueryType::QueryFormat))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn recording_control(&self, addr: &str, enabled: bool) -> Result<Recording> {
+        let reply = self
+            .query(
+                addr,
+                HttpQuery {
+                    r#type: QueryType::QueryRecordingControl as i32,
+                    recording: Some(Recording {
+                        modifying: true,
+                        enabled,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(reply
+            .status
+            .and_then(|s| s.recording)
+            .ok_or(DeviceError::MissingSection("recording"))?)
+    }
+
+    fn new_query(r#type: QueryType) -> HttpQuery {
+        HttpQuery {
+            r#type: r#type as i32,
+            ..Default::default()
+        }
+    }
+
+    /// Sends the query and fails if the station replied with errors.
+    async fn query(&self, addr: &str, query: HttpQuery) -> Result<HttpReply> {
+        let encoded = query.encode_length_delimited_to_vec();
+        let req = self.new_request(addr)?.body(encoded).build()?;
+        let reply: HttpReply = self.execute(req).await?;
+
+        match ReplyType::from_i32(reply.r#type) {
+            Some(ReplyType::ReplyError) => Err(DeviceError::Reply(
+                reply.errors.into_iter().map(|e| e.message).collect(),
+            )
+            .into()),
+            Some(ReplyType::ReplyBusy) => Err(DeviceError::Busy.into()),
+            _ => Ok(reply),
+        }
+    }
+
     pub async fn clear_calibration(&self, addr: &str, module: usize) -> Result<ModuleHttpReply> {
-        let mut query = ModuleHttpQuery::default();
-        query.r#type = ModuleQueryType::ModuleQueryReset as i32;
+        let query = ModuleHttpQuery {
+            r#type: ModuleQueryType::ModuleQueryReset as i32,
+            ..Default::default()
+        };
         let encoded = query.encode_length_delimited_to_vec();
         let req = self
             .new_module_request(addr, module)?
@@ -70,9 +317,11 @@ impl Client {
         module: usize,
         data: &[u8],
     ) -> Result<ModuleHttpReply> {
-        let mut query = ModuleHttpQuery::default();
-        query.r#type = ModuleQueryType::ModuleQueryConfigure as i32;
-        query.configuration = data.to_vec();
+        let query = ModuleHttpQuery {
+            r#type: ModuleQueryType::ModuleQueryConfigure as i32,
+            configuration: data.to_vec(),
+            ..Default::default()
+        };
         let encoded = query.encode_length_delimited_to_vec();
         let req = self
             .new_module_request(addr, module)?
@@ -87,7 +336,7 @@ impl Client {
         path: &Path,
         swap: bool,
     ) -> Result<impl Stream<Item = Result<BytesUploaded, UpgradeError>>> {
-        let file = File::open(path).await?;
+        let file = tokio::fs::File::open(path).await?;
         let md = file.metadata().await?;
         let total_bytes = md.len();
 
@@ -106,9 +355,8 @@ impl Client {
                 while let Some(chunk) = reader_stream.next().await {
                     if let Ok(chunk) = &chunk {
                         uploaded = std::cmp::min(uploaded + (chunk.len() as u64), total_bytes);
-                        match copying.send(Ok(BytesUploaded { bytes_uploaded: uploaded, total_bytes })) {
-                            Err(e) => warn!("{:?}", e),
-                            Ok(_) => {},
+                        if let Err(e) = copying.send(Ok(BytesUploaded { bytes_uploaded: uploaded, total_bytes })) {
+                            warn!("{:?}", e);
                         }
                     }
 
@@ -126,17 +374,25 @@ impl Client {
                     .send()
                     .await;
 
-                match response {
+                let failed = match response {
                     Ok(response) => {
-                        info!("done {:?}", response.status());
-                        if response.status().is_server_error() {
-                            match sender.send(Err(UpgradeError::ServerError)) {
-                                Err(e) => warn!("{:?}", e),
-                                Ok(_) => {}
-                            }
+                        let status = response.status();
+                        info!("done {:?}", status);
+                        if status.is_server_error() {
+                            Some(UpgradeError::ServerError)
+                        } else if !status.is_success() {
+                            Some(UpgradeError::Refused(status))
+                        } else {
+                            None
                         }
                     }
-                    Err(e) => warn!("{:?}", e),
+                    Err(e) => Some(e.into()),
+                };
+
+                if let Some(failed) = failed {
+                    if let Err(e) = sender.send(Err(failed)) {
+                        warn!("{:?}", e);
+                    }
                 }
             }
         });
@@ -207,4 +463,250 @@ mod tests {
         assert_eq!(modules.len(), 3);
         Ok(())
     }
+
+    /// Serves a single reply to the first query, which is returned.
+    async fn serve_once(reply: HttpReply) -> Result<(String, tokio::task::JoinHandle<HttpQuery>)> {
+        use crate::test_server::{self, to_bytes, Body, Request, Response};
+        use std::sync::Arc;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<HttpQuery>(1);
+        let reply = Arc::new(reply.encode_length_delimited_to_vec());
+
+        let addr = test_server::serve(move |req: Request<Body>| {
+            let tx = tx.clone();
+            let reply = Arc::clone(&reply);
+            async move {
+                let body = to_bytes(req.into_body()).await.unwrap();
+                let query = HttpQuery::decode_length_delimited(body).unwrap();
+                tx.send(query).await.unwrap();
+                Response::new(Body::from(reply.to_vec()))
+            }
+        })?;
+
+        Ok((
+            addr.to_string(),
+            tokio::spawn(async move { rx.recv().await.unwrap() }),
+        ))
+    }
+
+    #[tokio::test]
+    pub async fn test_configure_identity() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplyStatus as i32,
+            status: Some(Status {
+                identity: Some(Identity {
+                    name: "Renamed".to_owned(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await?;
+
+        let identity = Client::new()?.configure_identity(&addr, "Renamed").await?;
+        let query = queried.await?;
+
+        assert_eq!(identity.name, "Renamed");
+        assert_eq!(query.r#type, QueryType::QueryConfigureIdentity as i32);
+        assert_eq!(query.identity.unwrap().name, "Renamed");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_configure_schedules() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplySchedules as i32,
+            schedules: Some(Schedules::default()),
+            ..Default::default()
+        })
+        .await?;
+
+        Client::new()?
+            .configure_schedules(
+                &addr,
+                &ScheduleSettings {
+                    readings: Some(Duration::from_secs(60)),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let schedules = queried.await?.schedules.unwrap();
+
+        assert!(schedules.modifying);
+        assert_eq!(schedules.readings.unwrap().intervals[0].interval, 60);
+        assert_eq!(schedules.network, None);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_refuses_overlong_schedule() {
+        let interval = Duration::from_secs(u32::MAX as u64 + 1);
+        let settings = ScheduleSettings {
+            gps: Some(interval),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            settings.to_schedules(),
+            Err(DeviceError::IntervalTooLong(i)) if i == interval
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_configure_networks() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplyNetworkSettings as i32,
+            network_settings: Some(NetworkSettings {
+                mac_address: "00:11:22:33:44:55".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await?;
+
+        let settings = Client::new()?
+            .configure_networks(
+                &addr,
+                &[WifiNetwork {
+                    ssid: "Home".to_owned(),
+                    password: "secret".to_owned(),
+                    preferred: true,
+                }],
+            )
+            .await?;
+        let query = queried.await?;
+
+        assert_eq!(settings.mac_address, "00:11:22:33:44:55");
+        assert_eq!(
+            query.r#type,
+            QueryType::QueryConfigureNetworkSettings as i32
+        );
+        let sent = query.network_settings.unwrap();
+        assert!(sent.modifying);
+        assert_eq!(sent.networks.len(), 1);
+        assert_eq!(sent.networks[0].ssid, "Home");
+        assert_eq!(sent.networks[0].password, "secret");
+        assert!(sent.networks[0].preferred);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_scan_modules() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplyCapabilities as i32,
+            modules: vec![ModuleCapabilities {
+                position: 2,
+                name: "modules.water.ph".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .await?;
+
+        let modules = Client::new()?.scan_modules(&addr).await?;
+
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "modules.water.ph");
+        assert_eq!(queried.await?.r#type, QueryType::QueryScanModules as i32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_take_readings() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplyReadings as i32,
+            live_readings: vec![LiveReadings {
+                time: 1688659549,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .await?;
+
+        let readings = Client::new()?.take_readings(&addr).await?;
+
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].time, 1688659549);
+        assert_eq!(queried.await?.r#type, QueryType::QueryTakeReadings as i32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_reset() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplySuccess as i32,
+            ..Default::default()
+        })
+        .await?;
+
+        Client::new()?.reset(&addr).await?;
+
+        assert_eq!(queried.await?.r#type, QueryType::QueryReset as i32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_format() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplySuccess as i32,
+            ..Default::default()
+        })
+        .await?;
+
+        Client::new()?.format(&addr).await?;
+
+        assert_eq!(queried.await?.r#type, QueryType::QueryFormat as i32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_scan_networks() -> Result<()> {
+        let (addr, queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplyNetworks as i32,
+            nearby_networks: Some(NearbyNetworks {
+                networks: vec![NearbyNetwork {
+                    ssid: "Nearby".to_owned(),
+                }],
+            }),
+            ..Default::default()
+        })
+        .await?;
+
+        let networks = Client::new()?.scan_networks(&addr).await?;
+
+        assert_eq!(networks, vec!["Nearby".to_owned()]);
+        assert_eq!(queried.await?.r#type, QueryType::QueryScanNetworks as i32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_error_reply() -> Result<()> {
+        let (addr, _queried) = serve_once(HttpReply {
+            r#type: ReplyType::ReplyError as i32,
+            errors: vec![Error {
+                message: "Nope".to_owned(),
+                delay: 0,
+            }],
+            ..Default::default()
+        })
+        .await?;
+
+        let err = Client::new()?.format(&addr).await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<DeviceError>(),
+            Some(DeviceError::Reply(messages)) if messages == &vec!["Nope".to_owned()]
+        ));
+
+        Ok(())
+    }
 }
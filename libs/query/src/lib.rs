@@ -1,5 +1,7 @@
 pub mod device;
 pub mod portal;
+#[cfg(any(test, feature = "test-server"))]
+pub mod test_server;
 
 #[derive(Debug)]
 pub struct BytesDownloaded {
@@ -13,10 +13,8 @@ use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use std::io::Write;
-use std::{
-    path::{Path, PathBuf},
-    time::Duration,
-};
+use std::sync::Arc;
+use std::{path::Path, time::Duration};
 use thiserror::Error;
 use tokio::fs::File;
 use tokio_stream::Stream;
@@ -24,7 +22,7 @@ use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
 use tracing::{debug, info, warn};
 
-use protos::FileMeta;
+use protos::{meta_path, FileMeta};
 
 pub use reqwest::StatusCode;
 
@@ -78,7 +76,7 @@ impl Client {
     async fn build_get(&self, path: &str) -> Result<Request, PortalError> {
         Ok(self
             .client
-            .get(&format!("{}{}", self.base_url, path))
+            .get(format!("{}{}", self.base_url, path))
             .timeout(Duration::from_secs(10))
             .build()?)
     }
@@ -89,7 +87,7 @@ impl Client {
     {
         Ok(self
             .client
-            .post(&format!("{}{}", self.base_url, path))
+            .post(format!("{}{}", self.base_url, path))
             .json(&payload)
             .timeout(Duration::from_secs(10))
             .build()?)
@@ -98,7 +96,7 @@ impl Client {
     fn response_to_tokens(&self, response: Response) -> Result<Tokens, PortalError> {
         if let Some(auth) = response.headers().get("authorization") {
             let token = auth.to_str()?.to_owned();
-            Ok(Tokens { token })
+            Ok(Tokens::new(token))
         } else {
             Err(PortalError::NoAuthorizationHeader)
         }
@@ -152,7 +150,7 @@ impl Client {
 
         Ok(async_stream::try_stream! {
             while let Some(item) = stream.next().await {
-                let chunk = item.or_else(|_| Err(anyhow!("Error while downloading firmware")))?;
+                let chunk = item.map_err(|_| anyhow!("Error while downloading firmware"))?;
                 file.write_all(&chunk)?;
 
                 downloaded = std::cmp::min(downloaded + (chunk.len() as u64), total_bytes);
@@ -174,7 +172,7 @@ struct Firmwares {
     firmwares: Vec<Firmware>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Firmware {
     pub id: i64,
     #[serde(deserialize_with = "deserialize_firmware_time")]
@@ -211,43 +209,135 @@ where
         })
 }
 
-#[derive(Debug)]
+/// Tokens expiring within this many seconds are refreshed before being used.
+const REFRESH_MARGIN_SECS: i64 = 5 * 60;
+
+pub type TokensRefreshed = Arc<dyn Fn(&Tokens) + Send + Sync>;
+
 pub struct AuthenticatedClient {
-    tokens: Tokens,
+    tokens: tokio::sync::Mutex<Tokens>,
+    refreshed: Option<TokensRefreshed>,
+    upload_retry_delay: Duration,
     plain: Client,
 }
 
+impl std::fmt::Debug for AuthenticatedClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthenticatedClient")
+            .field("plain", &self.plain)
+            .finish_non_exhaustive()
+    }
+}
+
 impl AuthenticatedClient {
     pub fn new(base_url: &str, tokens: Tokens) -> Result<Self, PortalError> {
-        let mut headers = HeaderMap::new();
-        headers.insert("authorization", tokens.token.parse()?);
-
         Ok(Self {
-            tokens,
-            plain: Client::new_with_headers(base_url, headers)?,
+            tokens: tokio::sync::Mutex::new(tokens),
+            refreshed: None,
+            upload_retry_delay: UPLOAD_RETRY_DELAY,
+            plain: Client::new(base_url)?,
         })
     }
 
+    /// Invoked with the new tokens whenever they're rotated, so they can be
+    /// persisted for the next session.
+    pub fn on_refreshed(mut self, refreshed: impl Fn(&Tokens) + Send + Sync + 'static) -> Self {
+        self.refreshed = Some(Arc::new(refreshed));
+        self
+    }
+
+    /// Multiplied by the attempt number to get the wait before retrying an
+    /// upload.
+    pub fn with_upload_retry_delay(mut self, delay: Duration) -> Self {
+        self.upload_retry_delay = delay;
+        self
+    }
+
+    pub async fn tokens(&self) -> Tokens {
+        self.tokens.lock().await.clone()
+    }
+
+    /// Returns a token that's good to use, refreshing ahead of expiry.
+    async fn fresh_token(&self) -> Result<String, PortalError> {
+        let mut tokens = self.tokens.lock().await;
+        if tokens.refresh_token.is_some() && tokens.expiring() {
+            // The current token is still valid, so failing here isn't fatal.
+            if let Err(e) = self.refresh(&mut tokens).await {
+                warn!("refresh failed: {:?}", e);
+            }
+        }
+
+        Ok(tokens.token.clone())
+    }
+
+    /// Refreshes after `rejected` was refused, unless another request already has.
+    async fn refresh_rejected(&self, rejected: &str) -> Result<String, PortalError> {
+        let mut tokens = self.tokens.lock().await;
+        if tokens.token == rejected {
+            if tokens.refresh_token.is_none() {
+                return Err(PortalError::HttpStatus(StatusCode::UNAUTHORIZED));
+            }
+            self.refresh(&mut tokens).await?;
+        }
+
+        Ok(tokens.token.clone())
+    }
+
+    async fn refresh(&self, tokens: &mut Tokens) -> Result<(), PortalError> {
+        let refresh_token = tokens
+            .refresh_token
+            .clone()
+            .ok_or(PortalError::NoRefreshToken)?;
+
+        let mut rotated = self.plain.use_refresh_token(&refresh_token).await?;
+        if rotated.refresh_token.is_none() {
+            rotated.refresh_token = Some(refresh_token);
+        }
+
+        info!("tokens refreshed");
+
+        *tokens = rotated;
+
+        if let Some(refreshed) = &self.refreshed {
+            refreshed(tokens);
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, path: &str) -> Result<Response, PortalError> {
+        let token = self.fresh_token().await?;
+        let req = authorize(self.plain.build_get(path).await?, &token)?;
+        match self.plain.execute_req(req).await {
+            Err(PortalError::HttpStatus(StatusCode::UNAUTHORIZED)) => {
+                let token = self.refresh_rejected(&token).await?;
+                let req = authorize(self.plain.build_get(path).await?, &token)?;
+                self.plain.execute_req(req).await
+            }
+            res => res,
+        }
+    }
+
     pub async fn query_ourselves(&self) -> Result<User, PortalError> {
-        let req = self.plain.build_get("/user").await?;
-        let response = self.plain.execute_req(req).await?;
+        let response = self.get("/user").await?;
         Ok(response.json().await?)
     }
 
     pub async fn issue_transmission_token(&self) -> Result<TransmissionToken, PortalError> {
-        let req = self.plain.build_get("/user/transmission-token").await?;
-        let response = self.plain.execute_req(req).await?;
+        let response = self.get("/user/transmission-token").await?;
         Ok(response.json().await?)
     }
 
+    /// Uploads a synced file, retrying from the start after failures worth
+    /// retrying. The stream always ends with `Uploading::Completed` or an
+    /// error.
     pub async fn upload_readings(
         &self,
         path: &Path,
-    ) -> Result<impl Stream<Item = Result<BytesUploaded, PortalError>>, PortalError> {
-        let json_path = PathBuf::from(format!("{}.json", path.display()));
-        let file_meta = FileMeta::load_from_json(&json_path).await?;
+    ) -> Result<impl Stream<Item = Result<Uploading, PortalError>> + '_, PortalError> {
+        let file_meta = FileMeta::load_from_json(&meta_path(path)).await?;
 
-        let header_map: HeaderMap = file_meta
+        let mut header_map: HeaderMap = file_meta
             .headers
             .into_iter()
             .map(|(k, v)| {
@@ -260,75 +350,219 @@ impl AuthenticatedClient {
             .into_iter()
             .collect();
 
+        header_map.insert(CONTENT_HASH_HEADER, content_hash(path).await?.parse()?);
+
         info!("headers {:?}", &header_map);
 
-        let file = File::open(path).await?;
-        let md = file.metadata().await?;
-        let total_bytes = md.len();
+        let path = path.to_owned();
+        let url = format!("{}{}", self.plain.base_url, "/ingestion");
 
-        let (sender, recv) =
-            tokio::sync::mpsc::unbounded_channel::<Result<BytesUploaded, PortalError>>();
+        Ok(async_stream::stream! {
+            let mut token = match self.fresh_token().await {
+                Ok(token) => token,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            let mut attempt = 1;
 
-        let mut uploaded = 0;
-        let mut reader_stream = ReaderStream::new(file);
+            loop {
+                let res = {
+                    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+                    let sending =
+                        self.send_upload(&url, &path, header_map.clone(), &token, progress_tx);
+                    tokio::pin!(sending);
 
-        tokio::spawn({
-            let url = format!("{}{}", self.plain.base_url, "/ingestion");
-            let token = self.tokens.token.clone();
-            let copying = sender.clone();
-
-            let async_stream = async_stream::stream! {
-                while let Some(chunk) = reader_stream.next().await {
-                    if let Ok(chunk) = &chunk {
-                        uploaded = std::cmp::min(uploaded + (chunk.len() as u64), total_bytes);
-                        match copying.send(Ok(BytesUploaded { bytes_uploaded: uploaded, total_bytes })) {
-                            Err(e) => warn!("{:?}", e),
-                            Ok(_) => {},
+                    // Forward progress until the portal answers.
+                    loop {
+                        tokio::select! {
+                            res = &mut sending => break res,
+                            Some(uploaded) = progress_rx.recv() => yield Ok(Uploading::Progress(uploaded)),
                         }
                     }
-                    yield chunk;
-                }
-            };
+                };
 
-            async move {
-                info!(%url, "uploading {} bytes", total_bytes);
-
-                let response = reqwest::Client::new()
-                    .post(&url)
-                    .headers(header_map)
-                    .header("content-type", "application/octet-stream")
-                    .header("content-length", format!("{}", total_bytes))
-                    .header("authorization", token)
-                    .body(reqwest::Body::wrap_stream(async_stream))
-                    .send()
-                    .await;
-
-                match response {
-                    Ok(response) => {
-                        info!("done {:?}", response.status());
-                        if response.status().is_server_error() {
-                            match sender.send(Err(PortalError::HttpStatus(response.status()))) {
-                                Err(e) => warn!("{:?}", e),
-                                Ok(_) => {}
+                let error = match res {
+                    Ok(ingestion) => {
+                        yield Ok(Uploading::Completed(ingestion));
+                        return;
+                    }
+                    Err(PortalError::HttpStatus(StatusCode::UNAUTHORIZED)) if attempt == 1 => {
+                        match self.refresh_rejected(&token).await {
+                            Ok(refreshed) => token = refreshed,
+                            Err(e) => {
+                                yield Err(e);
+                                return;
                             }
                         }
+                        PortalError::HttpStatus(StatusCode::UNAUTHORIZED)
                     }
-                    Err(e) => warn!("{:?}", e),
+                    Err(e) if e.is_transient() && attempt < UPLOAD_ATTEMPTS => e,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+
+                warn!("upload attempt {} failed: {:?}", attempt, error);
+                yield Ok(Uploading::Retrying { attempt, error });
+
+                tokio::time::sleep(self.upload_retry_delay * attempt).await;
+                attempt += 1;
+            }
+        })
+    }
+
+    /// One attempt at sending the whole file.
+    async fn send_upload(
+        &self,
+        url: &str,
+        path: &Path,
+        headers: HeaderMap,
+        token: &str,
+        progress: tokio::sync::mpsc::UnboundedSender<BytesUploaded>,
+    ) -> Result<Ingestion, PortalError> {
+        let file = File::open(path).await?;
+        let total_bytes = file.metadata().await?.len();
+
+        let mut uploaded = 0;
+        let mut reader_stream = ReaderStream::new(file);
+        let body = async_stream::stream! {
+            while let Some(chunk) = reader_stream.next().await {
+                if let Ok(chunk) = &chunk {
+                    uploaded = std::cmp::min(uploaded + (chunk.len() as u64), total_bytes);
+                    // Nobody listening just means nobody cares.
+                    let _ = progress.send(BytesUploaded { bytes_uploaded: uploaded, total_bytes });
                 }
+                yield chunk;
             }
-        });
+        };
+
+        info!(%url, "uploading {} bytes", total_bytes);
 
-        Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(recv))
+        let response = self
+            .plain
+            .client
+            .post(url)
+            .headers(headers)
+            .header("content-type", "application/octet-stream")
+            .header("content-length", format!("{}", total_bytes))
+            .header("authorization", token)
+            .timeout(UPLOAD_TIMEOUT)
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await?;
+
+        let status = response.status();
+        info!("done {:?}", status);
+
+        if !status.is_success() {
+            return Err(PortalError::HttpStatus(status));
+        }
+
+        // The portal has the file by now, so a body we can't make sense of
+        // mustn't turn into a failed upload.
+        let body = match response.bytes().await {
+            Ok(body) if body.is_empty() => serde_json::Value::Null,
+            Ok(body) => serde_json::from_slice(&body).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())
+            }),
+            Err(e) => {
+                warn!("reading ingestion body: {:?}", e);
+                serde_json::Value::Null
+            }
+        };
+
+        Ok(Ingestion { status, body })
     }
 
-    pub async fn available_firmware(&self) -> Result<Vec<Firmware>> {
-        self.plain.available_firmware().await
+    pub async fn available_firmware(&self) -> Result<Vec<Firmware>, PortalError> {
+        let response = self.get("/firmware").await?;
+        let firmwares: Firmwares = response.json().await?;
+
+        Ok(firmwares.firmwares)
     }
 }
 
+/// Hex SHA-256 of an upload, so the portal can spot files it already has.
+pub const CONTENT_HASH_HEADER: &str = "fk-content-sha256";
+
+/// Attempts at an upload before giving up on a failure worth retrying.
+const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// Multiplied by the attempt number to get the wait before the next one.
+const UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Files can be large and field connections slow.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+pub enum Uploading {
+    Progress(BytesUploaded),
+    /// The attempt failed and the file will be sent again from the start.
+    Retrying {
+        attempt: u32,
+        error: PortalError,
+    },
+    Completed(Ingestion),
+}
+
+/// The portal's answer to an upload it accepted.
+#[derive(Clone, Debug)]
+pub struct Ingestion {
+    pub status: StatusCode,
+    /// Null when the portal said nothing more, a string when it wasn't JSON.
+    pub body: serde_json::Value,
+}
+
+async fn content_hash(path: &Path) -> Result<String, PortalError> {
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn authorize(mut req: Request, token: &str) -> Result<Request, PortalError> {
+    req.headers_mut().insert("authorization", token.parse()?);
+    Ok(req)
+}
+
 #[derive(Clone)]
 pub struct Tokens {
     pub token: String,
+    pub refresh_token: Option<String>,
+}
+
+impl Tokens {
+    /// Picks up the refresh token carried in the token's claims, if any.
+    pub fn new(token: String) -> Self {
+        let refresh_token = DecodedToken::decode(&token)
+            .ok()
+            .map(|decoded| decoded.refresh_token);
+
+        Self {
+            token,
+            refresh_token,
+        }
+    }
+
+    fn expiring(&self) -> bool {
+        DecodedToken::decode(&self.token)
+            .map(|decoded| decoded.remaining() < chrono::Duration::seconds(REFRESH_MARGIN_SECS))
+            .unwrap_or(false)
+    }
 }
 
 impl std::fmt::Debug for Tokens {
@@ -427,6 +661,411 @@ pub enum PortalError {
     UnexpectedError,
     #[error("Expected authorization header")]
     NoAuthorizationHeader,
+    #[error("No refresh token")]
+    NoRefreshToken,
     #[error("General error")]
     General(#[from] anyhow::Error),
 }
+
+impl PortalError {
+    /// Failures that may go away if tried again later, like being offline
+    /// or the portal having trouble, as opposed to requests it won't take.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PortalError::Request(_) => true,
+            PortalError::HttpStatus(status) => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, sync::Mutex};
+
+    fn token(refresh_token: &str, expires_in: i64) -> String {
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "email": "jacob@example.com",
+            "exp": now + expires_in,
+            "iat": now,
+            "refresh_token": refresh_token,
+            "scopes": [],
+            "sub": 1,
+        });
+        format!(
+            "Bearer header.{}.signature",
+            general_purpose::STANDARD_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    #[derive(Default)]
+    struct Portal {
+        valid: String,
+        rotated: String,
+        refreshes: usize,
+        rejecting: bool,
+        /// Statuses to answer uploads with before accepting them.
+        failing: Vec<u16>,
+        /// Content hash header and size of each upload received.
+        uploads: Vec<(String, usize)>,
+        /// Accepts uploads with this instead of JSON.
+        plain_text: Option<String>,
+        /// Authorization header of each request for the firmware listing.
+        firmware_authorizations: Vec<String>,
+    }
+
+    /// Accepts only `valid` on /user, unless `rejecting`, and rotates it to
+    /// `rotated` on /refresh. Uploads to /ingestion fail with `failing` and
+    /// then succeed.
+    fn serve(portal: Portal) -> Result<(String, Arc<Mutex<Portal>>)> {
+        use crate::test_server::{self, to_bytes, Body, Request, Response};
+
+        let portal = Arc::new(Mutex::new(portal));
+
+        let addr = test_server::serve({
+            let portal = Arc::clone(&portal);
+            move |req: Request<Body>| {
+                let portal = Arc::clone(&portal);
+                async move {
+                    if req.uri().path() == "/ingestion" {
+                        let hash = req.headers()[CONTENT_HASH_HEADER]
+                            .to_str()
+                            .unwrap()
+                            .to_owned();
+                        let body = to_bytes(req.into_body()).await.unwrap();
+                        let mut portal = portal.lock().unwrap();
+                        portal.uploads.push((hash, body.len()));
+                        let response = match portal.failing.first().copied() {
+                            Some(status) => {
+                                portal.failing.remove(0);
+                                Response::builder().status(status).body(Body::empty())
+                            }
+                            None => match &portal.plain_text {
+                                Some(text) => Response::builder()
+                                    .header("content-type", "text/plain")
+                                    .body(Body::from(text.clone())),
+                                None => Response::builder()
+                                    .body(Body::from(json!({ "id": 7 }).to_string())),
+                            },
+                        };
+                        return response.unwrap();
+                    }
+
+                    let mut portal = portal.lock().unwrap();
+                    let authorization = req
+                        .headers()
+                        .get("authorization")
+                        .map(|v| v.to_str().unwrap().to_owned());
+                    let authorized = !portal.rejecting
+                        && authorization.as_deref() == Some(portal.valid.as_str());
+                    let response = match req.uri().path() {
+                        "/refresh" => {
+                            portal.refreshes += 1;
+                            portal.valid = portal.rotated.clone();
+                            Response::builder()
+                                .header("authorization", portal.valid.as_str())
+                                .body(Body::empty())
+                        }
+                        "/firmware" => {
+                            portal
+                                .firmware_authorizations
+                                .push(authorization.unwrap_or_default());
+                            if authorized {
+                                Response::builder()
+                                    .body(Body::from(json!({ "firmwares": [] }).to_string()))
+                            } else {
+                                Response::builder()
+                                    .status(StatusCode::UNAUTHORIZED.as_u16())
+                                    .body(Body::empty())
+                            }
+                        }
+                        "/user" if authorized => Response::builder().body(Body::from(
+                            json!({
+                                "id": 1,
+                                "email": "jacob@example.com",
+                                "name": "Jacob",
+                                "bio": "",
+                                "photo": { "url": "" },
+                            })
+                            .to_string(),
+                        )),
+                        _ => Response::builder()
+                            .status(StatusCode::UNAUTHORIZED.as_u16())
+                            .body(Body::empty()),
+                    };
+                    response.unwrap()
+                }
+            }
+        })?;
+
+        Ok((format!("http://{}", addr), portal))
+    }
+
+    #[test]
+    fn test_tokens_carry_refresh_token() {
+        let tokens = Tokens::new(token("refresh-1", 3600));
+        assert_eq!(tokens.refresh_token.as_deref(), Some("refresh-1"));
+        assert!(!tokens.expiring());
+
+        assert!(Tokens::new(token("refresh-1", 60)).expiring());
+        assert_eq!(Tokens::new("INVALID".to_owned()).refresh_token, None);
+    }
+
+    #[tokio::test]
+    async fn test_refreshes_and_retries_after_unauthorized() -> Result<()> {
+        let stale = token("refresh-1", 3600);
+        let rotated = token("refresh-2", 3600);
+        let (url, portal) = serve(Portal {
+            valid: "revoked".to_owned(),
+            rotated: rotated.clone(),
+            ..Default::default()
+        })?;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = AuthenticatedClient::new(&url, Tokens::new(stale))?.on_refreshed({
+            let seen = Arc::clone(&seen);
+            move |tokens| seen.lock().unwrap().push(tokens.clone())
+        });
+
+        let user = client.query_ourselves().await?;
+        assert_eq!(user.id, 1);
+        assert_eq!(portal.lock().unwrap().refreshes, 1);
+
+        assert_eq!(client.tokens().await.token, rotated);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].token, rotated);
+        assert_eq!(seen[0].refresh_token.as_deref(), Some("refresh-2"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_firmware_listing_is_authenticated() -> Result<()> {
+        let stale = token("refresh-1", 3600);
+        let rotated = token("refresh-2", 3600);
+        let (url, portal) = serve(Portal {
+            valid: "revoked".to_owned(),
+            rotated: rotated.clone(),
+            ..Default::default()
+        })?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new(stale.clone()))?;
+
+        assert!(client.available_firmware().await?.is_empty());
+
+        let portal = portal.lock().unwrap();
+        assert_eq!(portal.refreshes, 1);
+        assert_eq!(portal.firmware_authorizations, vec![stale, rotated]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refreshes_before_expiry() -> Result<()> {
+        let expiring = token("refresh-1", 60);
+        let rotated = token("refresh-2", 3600);
+        let (url, portal) = serve(Portal {
+            valid: expiring.clone(),
+            rotated: rotated.clone(),
+            ..Default::default()
+        })?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new(expiring))?;
+
+        client.query_ourselves().await?;
+        client.query_ourselves().await?;
+        assert_eq!(portal.lock().unwrap().refreshes, 1);
+        assert_eq!(client.tokens().await.token, rotated);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retries_only_once() -> Result<()> {
+        let (url, portal) = serve(Portal {
+            rotated: token("refresh-2", 3600),
+            rejecting: true,
+            ..Default::default()
+        })?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new(token("refresh-1", 3600)))?;
+
+        match client.query_ourselves().await {
+            Err(PortalError::HttpStatus(StatusCode::UNAUTHORIZED)) => {}
+            res => panic!("unexpected {:?}", res.map(|u| u.id)),
+        }
+        assert_eq!(portal.lock().unwrap().refreshes, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_without_refresh_token() -> Result<()> {
+        let (url, portal) = serve(Portal::default())?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new("INVALID".to_owned()))?;
+
+        match client.query_ourselves().await {
+            Err(PortalError::HttpStatus(StatusCode::UNAUTHORIZED)) => {}
+            res => panic!("unexpected {:?}", res.map(|u| u.id)),
+        }
+        assert_eq!(portal.lock().unwrap().refreshes, 0);
+
+        Ok(())
+    }
+
+    fn synced_file(dir: &tempdir::TempDir) -> Result<PathBuf> {
+        let path = dir.path().join("20230710_120000.fkpb");
+        std::fs::write(&path, b"records")?;
+        std::fs::write(
+            dir.path().join("20230710_120000.fkpb.json"),
+            json!({ "headers": { "Fk-Type": "data", "Fk-Blocks": "0,9" } }).to_string(),
+        )?;
+        Ok(path)
+    }
+
+    async fn upload(
+        client: &AuthenticatedClient,
+        path: &Path,
+    ) -> Result<(Vec<Uploading>, Result<Ingestion, PortalError>)> {
+        let mut seen = Vec::new();
+        let uploading = client.upload_readings(path).await?;
+        tokio::pin!(uploading);
+        while let Some(uploading) = uploading.next().await {
+            match uploading {
+                Ok(Uploading::Completed(ingestion)) => return Ok((seen, Ok(ingestion))),
+                Ok(uploading) => seen.push(uploading),
+                Err(e) => return Ok((seen, Err(e))),
+            }
+        }
+        panic!("upload ended without a result")
+    }
+
+    #[tokio::test]
+    async fn test_upload_completes_with_ingestion() -> Result<()> {
+        let dir = tempdir::TempDir::new("fk-tests-upload")?;
+        let path = synced_file(&dir)?;
+        let (url, portal) = serve(Portal::default())?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new("token".to_owned()))?;
+        let (seen, res) = upload(&client, &path).await?;
+
+        let ingestion = res?;
+        assert_eq!(ingestion.status, StatusCode::OK);
+        assert_eq!(ingestion.body["id"], 7);
+        assert!(matches!(seen.last(), Some(Uploading::Progress(p)) if p.completed()));
+
+        let portal = portal.lock().unwrap();
+        assert_eq!(
+            portal.uploads,
+            vec![(
+                // sha256sum of "records"
+                "a94e7bcfcbed3c846d491d4f47c948e53a23908d480248b3ffe9e126e83ea865".to_owned(),
+                7
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_completes_with_plain_text() -> Result<()> {
+        let dir = tempdir::TempDir::new("fk-tests-upload")?;
+        let path = synced_file(&dir)?;
+        let (url, portal) = serve(Portal {
+            plain_text: Some("thanks".to_owned()),
+            ..Default::default()
+        })?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new("token".to_owned()))?;
+        let (_, res) = upload(&client, &path).await?;
+
+        let ingestion = res?;
+        assert_eq!(ingestion.status, StatusCode::OK);
+        assert_eq!(ingestion.body, json!("thanks"));
+        assert_eq!(portal.lock().unwrap().uploads.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_retries_from_start() -> Result<()> {
+        let dir = tempdir::TempDir::new("fk-tests-upload")?;
+        let path = synced_file(&dir)?;
+        let (url, portal) = serve(Portal {
+            failing: vec![503],
+            ..Default::default()
+        })?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new("token".to_owned()))?
+            .with_upload_retry_delay(Duration::from_millis(10));
+        let (seen, res) = upload(&client, &path).await?;
+
+        assert!(res.is_ok());
+        assert!(seen
+            .iter()
+            .any(|u| matches!(u, Uploading::Retrying { attempt: 1, .. })));
+
+        let portal = portal.lock().unwrap();
+        assert_eq!(portal.uploads.len(), 2);
+        assert_eq!(portal.uploads[0], portal.uploads[1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_reports_refusal() -> Result<()> {
+        let dir = tempdir::TempDir::new("fk-tests-upload")?;
+        let path = synced_file(&dir)?;
+        let (url, portal) = serve(Portal {
+            failing: vec![400],
+            ..Default::default()
+        })?;
+
+        let client = AuthenticatedClient::new(&url, Tokens::new("token".to_owned()))?;
+        let (_, res) = upload(&client, &path).await?;
+
+        match res {
+            Err(PortalError::HttpStatus(StatusCode::BAD_REQUEST)) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(portal.lock().unwrap().uploads.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_reports_unreachable_portal() -> Result<()> {
+        let dir = tempdir::TempDir::new("fk-tests-upload")?;
+        let path = synced_file(&dir)?;
+
+        // Nothing listens here once the listener is dropped.
+        let url = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            format!("http://{}", listener.local_addr()?)
+        };
+
+        let client = AuthenticatedClient::new(&url, Tokens::new("token".to_owned()))?
+            .with_upload_retry_delay(Duration::from_millis(10));
+        let (seen, res) = upload(&client, &path).await?;
+
+        let e = res.unwrap_err();
+        assert!(e.is_transient());
+        assert_eq!(
+            seen.iter()
+                .filter(|u| matches!(u, Uploading::Retrying { .. }))
+                .count(),
+            UPLOAD_ATTEMPTS as usize - 1
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! A local HTTP server for tests, standing in for stations and the portal.
+
+use anyhow::Result;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Server,
+};
+use std::{convert::Infallible, future::Future, net::SocketAddr};
+
+pub use hyper::{body::to_bytes, Body, Request, Response};
+
+/// Answers every request with `handle`, on a free local port, until the
+/// runtime shuts down. Returns the address it's listening on.
+pub fn serve<H, F>(handle: H) -> Result<SocketAddr>
+where
+    H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = Server::from_tcp(listener)?.serve(make_service_fn(move |_| {
+        let handle = handle.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let responding = handle(req);
+                async move { Ok::<_, Infallible>(responding.await) }
+            }))
+        }
+    }));
+
+    tokio::spawn(server);
+
+    Ok(addr)
+}
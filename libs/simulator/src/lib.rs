@@ -0,0 +1,491 @@
+use anyhow::{anyhow, Result};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use prost::Message as ProstMessage;
+use quick_protobuf::BytesReader;
+use std::{
+    convert::Infallible,
+    net::{SocketAddr, SocketAddrV4, TcpListener},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+use tracing::*;
+
+use discovery::DeviceId;
+use protos::http::{HttpQuery, HttpReply, QueryType, UdpMessage};
+use sync::{pack_records, Identity, Message, MessageCodec, RecordRange};
+
+pub use sync::MAXIMUM_PACKET_SIZE;
+
+const MULTICAST_IP: [u8; 4] = [224, 1, 2, 3];
+const MULTICAST_PORT: u16 = 22143;
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Everything a simulated station knows about itself.
+pub struct Station {
+    pub identity: Identity,
+    pub status: HttpReply,
+    pub readings: Option<HttpReply>,
+    pub records: Vec<Vec<u8>>,
+}
+
+impl Station {
+    pub fn new(identity: Identity, status: HttpReply) -> Self {
+        Self {
+            identity,
+            status,
+            readings: None,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn with_readings(self, readings: HttpReply) -> Self {
+        Self {
+            readings: Some(readings),
+            ..self
+        }
+    }
+
+    pub fn with_records(self, records: Vec<Vec<u8>>) -> Self {
+        Self { records, ..self }
+    }
+
+    /// Replies with our identity and record counts, regardless of what the
+    /// fixture the reply was built from says.
+    fn reply(&self, query: &HttpQuery) -> Result<HttpReply> {
+        let reply = match (QueryType::from_i32(query.r#type), &self.readings) {
+            (Some(QueryType::QueryGetReadings), Some(readings)) => readings.clone(),
+            _ => self.status.clone(),
+        };
+
+        let mut reply = reply;
+        if let Some(identity) = reply.status.as_mut().and_then(|s| s.identity.as_mut()) {
+            identity.device_id = hex::decode(&self.identity.device_id.0)?;
+            identity.generation_id = hex::decode(&self.identity.generation_id)?;
+            identity.name = self.identity.name.clone();
+            identity.device = self.identity.name.clone();
+        }
+        if let Some(data) = reply.streams.first_mut() {
+            data.block = self.records.len() as u64;
+            data.size = self.records.iter().map(|r| r.len() as u64).sum();
+        }
+
+        Ok(reply)
+    }
+
+    /// Encodes the multicast announcement the firmware sends periodically.
+    pub fn announcement(&self, http_port: u16) -> Result<Vec<u8>> {
+        let message = UdpMessage {
+            device_id: hex::decode(&self.identity.device_id.0)?,
+            port: http_port as u32,
+            ..Default::default()
+        };
+
+        Ok(message.encode_length_delimited_to_vec())
+    }
+
+    /// Builds the packets sent in response to a Require, records are packed
+    /// together until a packet is full and then followed by a Batch.
+    pub fn respond_to_require(&self, range: &RecordRange) -> Vec<Message> {
+        pack_records(&self.records, range, None)
+    }
+
+    fn respond(&self, message: &Message) -> Vec<Message> {
+        match message {
+            Message::Query => vec![Message::Statistics {
+                nrecords: self.records.len() as u64,
+                identity: self.identity.clone(),
+            }],
+            Message::Require(range) => self.respond_to_require(range),
+            _ => {
+                warn!("Unexpected {:?}", message);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Reads the undelimited records from a length-delimited .fkpb file.
+pub fn load_records(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = BytesReader::from_bytes(&bytes);
+    let mut records = Vec::new();
+    while !reader.is_eof() {
+        records.push(reader.read_bytes(&bytes)?.to_vec());
+    }
+
+    Ok(records)
+}
+
+/// Reads a length-delimited HttpReply, like the fixtures under query/examples.
+pub fn load_reply(path: &Path) -> Result<HttpReply> {
+    let bytes = std::fs::read(path)?;
+    Ok(HttpReply::decode_length_delimited(&bytes[..])?)
+}
+
+pub struct Simulator {
+    station: Arc<Station>,
+    http: TcpListener,
+    udp: UdpSocket,
+    announce_interval: Duration,
+}
+
+impl Simulator {
+    pub async fn bind(
+        station: Station,
+        http_addr: SocketAddr,
+        udp_addr: SocketAddr,
+    ) -> Result<Self> {
+        let http = TcpListener::bind(http_addr)?;
+        http.set_nonblocking(true)?;
+        let udp = UdpSocket::bind(udp_addr).await?;
+
+        Ok(Self {
+            station: Arc::new(station),
+            http,
+            udp,
+            announce_interval: Duration::from_secs(5),
+        })
+    }
+
+    pub fn http_addr(&self) -> Result<SocketAddr> {
+        Ok(self.http.local_addr()?)
+    }
+
+    pub fn udp_addr(&self) -> Result<SocketAddr> {
+        Ok(self.udp.local_addr()?)
+    }
+
+    pub fn announce_every(self, announce_interval: Duration) -> Self {
+        Self {
+            announce_interval,
+            ..self
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let http_port = self.http_addr()?.port();
+
+        info!(
+            "simulating {:?} http={} udp={}",
+            self.station.identity.device_id,
+            self.http_addr()?,
+            self.udp_addr()?
+        );
+
+        tokio::select! {
+            res = announce(Arc::clone(&self.station), http_port, self.announce_interval) => res,
+            res = serve_http(Arc::clone(&self.station), self.http) => res,
+            res = serve_udp(Arc::clone(&self.station), self.udp) => res,
+        }
+    }
+}
+
+async fn announce(station: Arc<Station>, http_port: u16, interval: Duration) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let addr = SocketAddrV4::new(MULTICAST_IP.into(), MULTICAST_PORT);
+    let announcement = station.announcement(http_port)?;
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = socket.send_to(&announcement, addr).await {
+            warn!("Announce error: {:?}", e);
+        }
+    }
+}
+
+async fn serve_http(station: Arc<Station>, listener: TcpListener) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let station = Arc::clone(&station);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let station = Arc::clone(&station);
+                async move { Ok::<_, Infallible>(handle_http(&station, req).await) }
+            }))
+        }
+    });
+
+    hyper::Server::from_tcp(listener)?
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+async fn handle_http(station: &Station, req: Request<Body>) -> Response<Body> {
+    fn status(code: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = code;
+        response
+    }
+
+    if req.uri().path() != "/fk/v1" {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *req.method() {
+        Method::GET => HttpQuery::default(),
+        Method::POST => match hyper::body::to_bytes(req.into_body()).await {
+            Ok(bytes) if bytes.is_empty() => HttpQuery::default(),
+            Ok(bytes) => match HttpQuery::decode_length_delimited(bytes) {
+                Ok(query) => query,
+                Err(_) => return status(StatusCode::BAD_REQUEST),
+            },
+            Err(_) => return status(StatusCode::BAD_REQUEST),
+        },
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    match station.reply(&query) {
+        Ok(reply) => Response::new(Body::from(reply.encode_length_delimited_to_vec())),
+        Err(e) => {
+            warn!("Reply error: {:?}", e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn serve_udp(station: Arc<Station>, socket: UdpSocket) -> Result<()> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+
+    loop {
+        let (len, addr) = socket.recv_from(&mut buffer[..]).await?;
+
+        let mut codec = MessageCodec::default();
+        let message = match codec.try_read(&buffer[..len]) {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("{:?} Malformed: {:?}", addr, e);
+                continue;
+            }
+        };
+
+        debug!("{:?} {:?}", addr, message);
+
+        for replying in station.respond(&message) {
+            let mut bytes = Vec::new();
+            replying.write(&mut bytes)?;
+            socket.send_to(&bytes, addr).await?;
+
+            // Keep from overrunning the receiving socket, much like the
+            // firmware is naturally limited by the radio.
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// Builds an Identity from hex strings, failing early on malformed ids.
+pub fn identity(device_id: &str, generation_id: &str, name: &str) -> Result<Identity> {
+    hex::decode(device_id).map_err(|_| anyhow!("Malformed device id"))?;
+    hex::decode(generation_id).map_err(|_| anyhow!("Malformed generation id"))?;
+
+    Ok(Identity {
+        device_id: DeviceId(device_id.to_owned()),
+        generation_id: generation_id.to_owned(),
+        name: name.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+    use tokio::sync::mpsc;
+
+    use discovery::Discovered;
+    use sync::{FilesRecordSink, Server, ServerEvent, UdpTransport};
+
+    use super::*;
+
+    fn test_station(records: Vec<Vec<u8>>) -> Result<Station> {
+        let path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../query/examples/status_1.fkpb");
+        let identity = identity("0011223344556677", "8899aabbccddeeff", "Simulated")?;
+
+        Ok(Station::new(identity, load_reply(&path)?).with_records(records))
+    }
+
+    fn test_records(number: usize) -> Vec<Vec<u8>> {
+        (0..number)
+            .map(|n| vec![n as u8; 40 + (n * 7) % 200])
+            .collect()
+    }
+
+    fn reassemble(messages: &[Message]) -> Result<Vec<Vec<u8>>> {
+        let mut codec = MessageCodec::default();
+        let mut records = Vec::new();
+        for message in messages {
+            let mut bytes = Vec::new();
+            message.write(&mut bytes)?;
+            if let Some(Message::Records { records: read, .. }) = codec.try_read(&bytes)? {
+                records.extend(read.into_iter().map(|r| r.bytes().to_vec()));
+            }
+        }
+
+        Ok(records)
+    }
+
+    #[test]
+    fn test_announcement_decodes() -> Result<()> {
+        let station = test_station(Vec::new())?;
+        let bytes = station.announcement(2380)?;
+        let message = UdpMessage::decode_length_delimited(&bytes[..])?;
+
+        assert_eq!(hex::encode(message.device_id), "0011223344556677");
+        assert_eq!(message.port, 2380);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply_patches_identity_and_records() -> Result<()> {
+        let station = test_station(test_records(10))?;
+        let reply = station.reply(&HttpQuery::default())?;
+        let identity = reply.status.unwrap().identity.unwrap();
+
+        assert_eq!(hex::encode(identity.device_id), "0011223344556677");
+        assert_eq!(identity.name, "Simulated");
+        assert_eq!(reply.streams[0].block, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packs_records_into_packets() -> Result<()> {
+        let records = test_records(100);
+        let station = test_station(records.clone())?;
+        let messages = station.respond_to_require(&RecordRange::new(0, 99));
+
+        assert_eq!(messages.last(), Some(&Message::Batch { flags: 0 }));
+        assert!(messages.len() > 2);
+        for message in messages.iter() {
+            let mut bytes = Vec::new();
+            message.write(&mut bytes)?;
+            assert!(bytes.len() <= MAXIMUM_PACKET_SIZE + 16);
+        }
+        assert_eq!(reassemble(&messages)?, records);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clamps_require_to_available_records() -> Result<()> {
+        let records = test_records(10);
+        let station = test_station(records.clone())?;
+        let messages = station.respond_to_require(&RecordRange::new(5, 99));
+
+        assert_eq!(reassemble(&messages)?, records[5..].to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_splits_large_records_across_packets() -> Result<()> {
+        let records = vec![vec![1u8; 100], vec![2u8; 1500], vec![3u8; 100]];
+        let station = test_station(records.clone())?;
+        let messages = station.respond_to_require(&RecordRange::new(0, 2));
+
+        let fragments: Vec<_> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Records {
+                    head,
+                    flags: 1,
+                    sequence,
+                    ..
+                } => Some((*head, *sequence)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(fragments, vec![(1, 0), (1, 1)]);
+        assert_eq!(reassemble(&messages)?, records);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serves_status_over_http() -> Result<()> {
+        let station = test_station(test_records(10))?;
+        let simulator =
+            Simulator::bind(station, "127.0.0.1:0".parse()?, "127.0.0.1:0".parse()?).await?;
+        let http_addr = simulator.http_addr()?;
+        let running = tokio::spawn(simulator.run());
+
+        let client = query::device::Client::new()?;
+        let reply = client.query_status(&http_addr.to_string()).await?;
+        let identity = reply.status.unwrap().identity.unwrap();
+
+        assert_eq!(hex::encode(identity.device_id), "0011223344556677");
+
+        running.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_syncs_over_udp() -> Result<()> {
+        let records = test_records(500);
+        let station = test_station(records.clone())?;
+        let device_id = station.identity.device_id.clone();
+        let simulator =
+            Simulator::bind(station, "127.0.0.1:0".parse()?, "127.0.0.1:0".parse()?).await?;
+        let udp_addr = simulator.udp_addr()?;
+        let simulating = tokio::spawn(simulator.run());
+
+        let dir = TempDir::new("fk-tests-simulator")?;
+        let server = Arc::new(Server::new(
+            UdpTransport::new_with_port(0),
+            FilesRecordSink::new(dir.path()),
+        ));
+        let (publish, mut events) = mpsc::channel::<ServerEvent>(32);
+        let serving = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(publish).await }
+        });
+
+        let discovered = Discovered {
+            device_id: device_id.clone(),
+            http_addr: None,
+            udp_addr: Some(udp_addr),
+        };
+
+        // The server only accepts commands once it's running.
+        while server.sync(discovered.clone()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                match event {
+                    ServerEvent::Completed(_, _) => return Ok(()),
+                    ServerEvent::Failed(_, e) => return Err(anyhow!("Sync failed: {}", e)),
+                    _ => {}
+                }
+            }
+            Err(anyhow!("Server stopped"))
+        })
+        .await??;
+
+        let device_path = dir.path().join(&device_id.0);
+        let joined = std::fs::read_dir(&device_path)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| p.extension().map(|e| e == "fkpb").unwrap_or_default())
+            .ok_or_else(|| anyhow!("Missing joined file"))?;
+
+        assert_eq!(load_records(&joined)?, records);
+
+        serving.abort();
+        simulating.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use tokio::signal;
+use tracing_subscriber::prelude::*;
+
+use simulator::{identity, load_records, load_reply, Simulator, Station};
+
+#[derive(Parser)]
+#[command(author, version, about = "Pretends to be a station", long_about = None)]
+struct Cli {
+    /// Length-delimited .fkpb file of records served over UDP.
+    #[arg(long)]
+    source: Option<PathBuf>,
+    /// Length-delimited HttpReply served for status queries.
+    #[arg(long, default_value = "libs/query/examples/status_1.fkpb")]
+    status: PathBuf,
+    /// Length-delimited HttpReply served for readings queries.
+    #[arg(long)]
+    readings: Option<PathBuf>,
+    #[arg(long, default_value = "0011223344556677")]
+    device_id: String,
+    #[arg(long, default_value = "8899aabbccddeeff8899aabbccddeeff")]
+    generation_id: String,
+    #[arg(long, default_value = "Simulated Station")]
+    name: String,
+    #[arg(long, default_value = "0.0.0.0:2380")]
+    http: SocketAddr,
+    #[arg(long, default_value = "0.0.0.0:22144")]
+    udp: SocketAddr,
+    /// Seconds between multicast announcements.
+    #[arg(long, default_value_t = 5)]
+    announce: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new(
+            std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
+        ))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let cli = Cli::parse();
+
+    let identity = identity(&cli.device_id, &cli.generation_id, &cli.name)?;
+    let status = load_reply(&cli.status).with_context(|| format!("{:?}", cli.status))?;
+    let mut station = Station::new(identity, status);
+
+    if let Some(path) = &cli.readings {
+        station = station.with_readings(load_reply(path).with_context(|| format!("{:?}", path))?);
+    }
+
+    if let Some(path) = &cli.source {
+        station = station.with_records(load_records(path).with_context(|| format!("{:?}", path))?);
+    }
+
+    let simulator = Simulator::bind(station, cli.http, cli.udp)
+        .await?
+        .announce_every(Duration::from_secs(cli.announce));
+
+    tokio::select! {
+        res = simulator.run() => res,
+        res = signal::ctrl_c() => res.map_err(|e| e.into()),
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::Result;
+use chrono::Utc;
+use sync::ServerEvent;
+use tracing::*;
+
+use crate::{Db, DbError, DeviceId, StationDownload};
+
+impl Db {
+    /// Keeps station_download in step with the sync server, returning the
+    /// download that was added or updated.
+    pub fn record_sync_event(&self, event: &ServerEvent) -> Result<Option<StationDownload>> {
+        match event {
+            ServerEvent::Began(device_id) => {
+                let Some(station) =
+                    self.get_station_by_device_id(&DeviceId(device_id.0.clone()))?
+                else {
+                    warn!("{:?} unknown station, not recording download", device_id);
+                    return Ok(None);
+                };
+                let station_id = station.id.ok_or(DbError::SeriousBug)?;
+
+                // Anything still open belongs to a sync we never heard the end of.
+                for mut interrupted in self.get_unfinished_station_downloads(station_id)? {
+                    interrupted.error = Some("Interrupted".to_owned());
+                    self.update_station_download(&interrupted)?;
+                }
+
+                Ok(Some(self.add_station_download(&StationDownload {
+                    id: None,
+                    station_id: Some(station_id),
+                    generation_id: station.generation_id,
+                    started: Utc::now(),
+                    begin: 0,
+                    end: 0,
+                    path: String::new(),
+                    uploaded: false,
+                    finished: None,
+                    size: None,
+                    error: None,
+                })?))
+            }
+            ServerEvent::Completed(device_id, flushed) => {
+                let Some(mut download) = self.get_syncing(device_id)? else {
+                    return Ok(None);
+                };
+
+                match flushed {
+                    Some(flushed) => {
+                        download.generation_id = flushed.generation_id.clone();
+                        download.begin = *flushed.records.start();
+                        download.end = *flushed.records.end();
+                        download.path = flushed.path.display().to_string();
+                        download.size = Some(flushed.size as i64);
+                    }
+                    None => download.size = Some(0),
+                }
+                download.finished = Some(Utc::now());
+
+                Ok(Some(self.update_station_download(&download)?))
+            }
+            ServerEvent::Failed(device_id, failure) => {
+                let Some(mut download) = self.get_syncing(device_id)? else {
+                    return Ok(None);
+                };
+
+                download.error = Some(failure.to_string());
+
+                Ok(Some(self.update_station_download(&download)?))
+            }
+            ServerEvent::Transferring(..) | ServerEvent::Processing(_) => Ok(None),
+        }
+    }
+
+    fn get_syncing(&self, device_id: &discovery::DeviceId) -> Result<Option<StationDownload>> {
+        let station = self.get_station_by_device_id(&DeviceId(device_id.0.clone()))?;
+        let download = match station.and_then(|s| s.id) {
+            Some(station_id) => self
+                .get_unfinished_station_downloads(station_id)?
+                .into_iter()
+                .next(),
+            None => None,
+        };
+
+        if download.is_none() {
+            warn!("{:?} no download in progress", device_id);
+        }
+
+        Ok(download)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use sync::{Flushed, SyncFailure};
+
+    use crate::test::*;
+
+    use super::*;
+
+    fn device_id() -> discovery::DeviceId {
+        discovery::DeviceId("device-id".to_owned())
+    }
+
+    fn flushed() -> Flushed {
+        Flushed {
+            path: PathBuf::from("fk-data/device-id/20230710_120000.fkpb"),
+            size: 4096,
+            generation_id: "generation-id".to_owned(),
+            records: 100..=199,
+        }
+    }
+
+    #[test]
+    fn test_recording_completed_sync() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        let station = db.add_station(&build().station().build())?;
+
+        let began = db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        assert!(began.is_some());
+
+        db.record_sync_event(&ServerEvent::Completed(device_id(), Some(flushed())))?;
+
+        let downloads = db.get_station_downloads(station.id.unwrap())?;
+        assert_eq!(downloads.len(), 1);
+        let download = downloads.first().unwrap();
+        assert_eq!(download.path, "fk-data/device-id/20230710_120000.fkpb");
+        assert_eq!(download.size, Some(4096));
+        assert_eq!((download.begin, download.end), (100, 199));
+        assert!(download.finished.is_some());
+        assert_eq!(download.error, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recording_failed_sync() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        let station = db.add_station(&build().station().build())?;
+
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        db.record_sync_event(&ServerEvent::Failed(device_id(), SyncFailure::Stalled))?;
+
+        let downloads = db.get_station_downloads(station.id.unwrap())?;
+        assert_eq!(downloads.len(), 1);
+        let download = downloads.first().unwrap();
+        assert_eq!(
+            download.error.as_deref(),
+            Some("Station stopped responding")
+        );
+        assert!(download.finished.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recording_interrupted_sync() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        let station = db.add_station(&build().station().build())?;
+
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        db.record_sync_event(&ServerEvent::Completed(device_id(), None))?;
+
+        let downloads = db.get_station_downloads(station.id.unwrap())?;
+        assert_eq!(downloads.len(), 2);
+        assert_eq!(downloads[0].error.as_deref(), Some("Interrupted"));
+        assert!(downloads[1].finished.is_some());
+        assert_eq!(downloads[1].size, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_uploads() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        db.add_station(&build().station().build())?;
+
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        db.record_sync_event(&ServerEvent::Completed(device_id(), Some(flushed())))?;
+        // Nothing new, so nothing to upload.
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        db.record_sync_event(&ServerEvent::Completed(device_id(), None))?;
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+        db.record_sync_event(&ServerEvent::Failed(device_id(), SyncFailure::Stalled))?;
+        // Still syncing.
+        db.record_sync_event(&ServerEvent::Began(device_id()))?;
+
+        let pending = db.get_pending_uploads()?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].path, "fk-data/device-id/20230710_120000.fkpb");
+
+        db.update_station_download(&StationDownload {
+            uploaded: true,
+            ..pending[0].clone()
+        })?;
+        assert!(db.get_pending_uploads()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recording_unknown_station() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        assert!(db
+            .record_sync_event(&ServerEvent::Began(device_id()))?
+            .is_none());
+        assert!(db
+            .record_sync_event(&ServerEvent::Completed(device_id(), Some(flushed())))?
+            .is_none());
+
+        Ok(())
+    }
+}
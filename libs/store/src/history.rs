@@ -0,0 +1,275 @@
+use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::params;
+use std::ops::Range;
+use tracing::*;
+
+use crate::{Db, LiveValue, Station};
+
+impl Db {
+    /// Keeps the live values of a station's sensors, so each poll adds to
+    /// their history rather than replacing it. Values already kept for the
+    /// same time are ignored, as are removed sensors. Old history of the
+    /// sensors written is pruned afterwards.
+    pub fn add_live_values(&self, station: &Station) -> Result<usize> {
+        let conn = self.require_opened()?;
+        let tx = conn.unchecked_transaction()?;
+        let mut added = 0;
+        let mut written = Vec::new();
+
+        {
+            let mut stmt = tx.prepare(
+                r#"
+                INSERT OR IGNORE INTO live_value
+                (sensor_id, time, calibrated_value, uncalibrated_value) VALUES
+                (?, ?, ?, ?)
+                "#,
+            )?;
+
+            let sensors = station
+                .modules
+                .iter()
+                .filter(|m| !m.removed)
+                .flat_map(|m| m.sensors.iter())
+                .filter(|s| !s.removed);
+
+            for sensor in sensors {
+                if let (Some(sensor_id), Some(value)) = (sensor.id, &sensor.value) {
+                    added += stmt.execute(params![
+                        sensor_id,
+                        value.time.timestamp(),
+                        value.value,
+                        value.uncalibrated,
+                    ])?;
+                    written.push(sensor_id);
+                }
+            }
+        }
+
+        tx.commit()?;
+
+        for sensor_id in written {
+            self.prune_sensor_live_values(sensor_id)?;
+        }
+
+        Ok(added)
+    }
+
+    /// A sensor's live values at or after the start of the range and before
+    /// its end, oldest first.
+    pub fn get_live_values(
+        &self,
+        sensor_id: i64,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<LiveValue>> {
+        let mut stmt = self.require_opened()?.prepare(
+            r#"SELECT time, calibrated_value, uncalibrated_value
+               FROM live_value WHERE sensor_id = ? AND time >= ? AND time < ?
+               ORDER BY time"#,
+        )?;
+
+        let values = stmt.query_map(
+            params![sensor_id, range.start.timestamp(), range.end.timestamp()],
+            |row| {
+                let time: i64 = row.get(0)?;
+                Ok(LiveValue {
+                    time: Utc.timestamp_opt(time, 0).single().unwrap_or_default(),
+                    value: row.get(1)?,
+                    uncalibrated: row.get(2)?,
+                })
+            },
+        )?;
+
+        values.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
+    }
+
+    /// Removes a sensor's live values the retention policy no longer keeps,
+    /// returning how many were removed.
+    fn prune_sensor_live_values(&self, sensor_id: i64) -> Result<usize> {
+        let conn = self.require_opened()?;
+        let mut removed = 0;
+
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = (Utc::now() - max_age).timestamp();
+            removed += conn.execute(
+                "DELETE FROM live_value WHERE sensor_id = ? AND time < ?",
+                params![sensor_id, cutoff],
+            )?;
+        }
+
+        if let Some(max_per_sensor) = self.retention.max_per_sensor {
+            removed += conn.execute(
+                r#"
+                DELETE FROM live_value WHERE sensor_id = ?1 AND id NOT IN (
+                    SELECT id FROM live_value WHERE sensor_id = ?1 ORDER BY time DESC LIMIT ?2
+                )
+                "#,
+                params![sensor_id, max_per_sensor],
+            )?;
+        }
+
+        if removed > 0 {
+            debug!("pruned {} live values of sensor {}", removed, sensor_id);
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes live values the retention policy no longer keeps across every
+    /// sensor, returning how many were removed. Polls only prune the sensors
+    /// they wrote, so this is for catching up after the policy changes.
+    pub fn prune_live_values(&self) -> Result<usize> {
+        let conn = self.require_opened()?;
+        let mut removed = 0;
+
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = (Utc::now() - max_age).timestamp();
+            removed += conn.execute("DELETE FROM live_value WHERE time < ?", params![cutoff])?;
+        }
+
+        if let Some(max_per_sensor) = self.retention.max_per_sensor {
+            removed += conn.execute(
+                r#"
+                DELETE FROM live_value WHERE id IN (
+                    SELECT id FROM (
+                        SELECT id, ROW_NUMBER() OVER (PARTITION BY sensor_id ORDER BY time DESC) AS recency
+                        FROM live_value
+                    ) WHERE recency > ?
+                )
+                "#,
+                params![max_per_sensor],
+            )?;
+        }
+
+        if removed > 0 {
+            debug!("pruned {} live values", removed);
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::*;
+    use crate::{Retention, Sensor};
+
+    use super::*;
+
+    fn station_with_value(time: DateTime<Utc>, value: f32) -> Station {
+        let mut station = build()
+            .station()
+            .module(build().module().basic("fk.modules.test").build())
+            .build();
+        for sensor in station.modules[0].sensors.iter_mut() {
+            sensor.value = Some(LiveValue {
+                time,
+                value,
+                uncalibrated: value * 100.0,
+            });
+        }
+        station
+    }
+
+    fn first_sensor(station: &Station) -> &Sensor {
+        &station.modules[0].sensors[0]
+    }
+
+    #[test]
+    fn test_synchronize_keeps_live_value_history() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        let now = Utc::now();
+        let earlier = now - chrono::Duration::minutes(5);
+
+        db.synchornize(station_with_value(earlier, 1.0))?;
+        // Polling again before the station takes another reading.
+        db.synchornize(station_with_value(earlier, 1.0))?;
+        let saved = db.synchornize(station_with_value(now, 2.0))?;
+
+        let sensor_id = first_sensor(&saved).id.unwrap();
+        let values = db.get_live_values(
+            sensor_id,
+            earlier - chrono::Duration::hours(1)..now + chrono::Duration::hours(1),
+        )?;
+        assert_eq!(
+            values.iter().map(|v| v.value).collect::<Vec<_>>(),
+            vec![1.0, 2.0]
+        );
+        assert_eq!(values[1].uncalibrated, 200.0);
+
+        // The sensor row still only has the latest.
+        let hydrated = db.hydrate_station(&saved.device_id)?.unwrap();
+        let sensor = hydrated.modules[0]
+            .sensors
+            .iter()
+            .find(|s| s.id == Some(sensor_id))
+            .unwrap();
+        assert_eq!(sensor.value.as_ref().map(|v| v.value), Some(2.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_removed_sensors_keep_no_history() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+
+        let now = Utc::now();
+        let mut saved = db.synchornize(station_with_value(now, 1.0))?;
+        let sensor_id = first_sensor(&saved).id.unwrap();
+
+        saved.modules[0].sensors[0].removed = true;
+        for sensor in saved.modules[0].sensors.iter_mut() {
+            sensor.value = Some(LiveValue {
+                time: now + chrono::Duration::minutes(1),
+                value: 2.0,
+                uncalibrated: 200.0,
+            });
+        }
+        db.add_live_values(&saved)?;
+
+        let values = db.get_live_values(
+            sensor_id,
+            now - chrono::Duration::hours(1)..now + chrono::Duration::hours(1),
+        )?;
+        assert_eq!(
+            values.iter().map(|v| v.value).collect::<Vec<_>>(),
+            vec![1.0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pruning_live_values() -> Result<()> {
+        let mut db = Db::new().with_retention(Retention {
+            max_age: Some(chrono::Duration::days(1)),
+            max_per_sensor: Some(2),
+        });
+        db.open()?;
+
+        let now = Utc::now();
+        let ancient = now - chrono::Duration::days(2);
+        let mut saved = db.synchornize(station_with_value(ancient, 0.0))?;
+        for minutes in (0..3).rev() {
+            saved = db.synchornize(station_with_value(
+                now - chrono::Duration::minutes(minutes),
+                minutes as f32,
+            ))?;
+        }
+
+        let sensor_id = first_sensor(&saved).id.unwrap();
+        let values = db.get_live_values(
+            sensor_id,
+            ancient - chrono::Duration::hours(1)..now + chrono::Duration::hours(1),
+        )?;
+        assert_eq!(
+            values.iter().map(|v| v.value).collect::<Vec<_>>(),
+            vec![1.0, 0.0]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,519 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+use protos::{data::ModuleInfo, meta_path, FileMeta, RecordsReader, StreamKind};
+
+use crate::{
+    merge, Battery, Db, DbError, DeviceId, Firmware, Module, ModuleHeader, Sensor, Solar, Station,
+    StationDownload, Stream,
+};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Imported {
+    pub stations: u64,
+    pub downloads: u64,
+    /// Files that were already recorded and unchanged.
+    pub unchanged: u64,
+    /// Files without usable meta.
+    pub skipped: u64,
+}
+
+/// A synced file and what its `.fkpb.json` says about it.
+struct SyncedFile {
+    path: PathBuf,
+    meta: FileMeta,
+    size: u64,
+    started: DateTime<Utc>,
+}
+
+impl SyncedFile {
+    fn open(path: &Path) -> Result<Self> {
+        let meta = FileMeta::load_from_json_sync(&meta_path(path))?;
+        let md = std::fs::metadata(path)?;
+
+        // Files are named for the time their sync began, other files fall
+        // back to when they were last written.
+        let started = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| NaiveDateTime::parse_from_str(s, "%Y%m%d_%H%M%S").ok())
+            .map(|t| Utc.from_utc_datetime(&t))
+            .unwrap_or_else(|| {
+                md.modified()
+                    .map(|m| m.into())
+                    .unwrap_or_else(|_| Utc::now())
+            });
+
+        Ok(Self {
+            path: path.to_owned(),
+            meta,
+            size: md.len(),
+            started,
+        })
+    }
+
+    fn header(&self, key: &str) -> Option<&str> {
+        self.meta
+            .headers
+            .get(key)
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    /// Modules as of the last meta record that had any.
+    fn modules(&self) -> Result<Option<Vec<Module>>> {
+        let mut modules = None;
+        for read in RecordsReader::open(&self.path)? {
+            let read = match read {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("{} {}", self.path.display(), e);
+                    break;
+                }
+            };
+            let Ok(record) = read.record else {
+                continue;
+            };
+            if !record.modules.is_empty() {
+                modules = Some(record.modules.iter().map(to_module).collect());
+            }
+        }
+
+        Ok(modules)
+    }
+}
+
+fn to_module(info: &ModuleInfo) -> Module {
+    let header = info.header.clone().unwrap_or_default();
+
+    Module {
+        id: None,
+        station_id: None,
+        hardware_id: hex::encode(&info.id),
+        header: ModuleHeader {
+            manufacturer: header.manufacturer,
+            kind: header.kind,
+            version: header.version,
+        },
+        flags: info.flags,
+        position: info.position,
+        key: info.name.clone(),
+        path: String::new(),
+        configuration: if info.configuration.is_empty() {
+            None
+        } else {
+            Some(info.configuration.clone())
+        },
+        removed: false,
+        sensors: info
+            .sensors
+            .iter()
+            .map(|s| Sensor {
+                id: None,
+                module_id: None,
+                number: s.number,
+                flags: s.flags,
+                key: s.name.clone(),
+                calibrated_uom: s.unit_of_measure.clone(),
+                uncalibrated_uom: s.uncalibrated_unit_of_measure.clone(),
+                value: None,
+                removed: false,
+            })
+            .collect(),
+    }
+}
+
+fn synced_files(device_path: &Path, imported: &mut Imported) -> Result<Vec<SyncedFile>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(device_path)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().map(|e| e != "fkpb").unwrap_or(true) {
+            continue;
+        }
+
+        match SyncedFile::open(&path) {
+            // Merged files repeat records from the downloads they were made
+            // from, and are named for their generation.
+            Ok(file)
+                if file.meta.is_merged()
+                    || file.header("Fk-Generation")
+                        == path.file_stem().and_then(|s| s.to_str()) =>
+            {
+                debug!("{} merged, skipping", path.display());
+            }
+            Ok(file) => files.push(file),
+            Err(e) => {
+                warn!("{} skipping, {}", path.display(), e);
+                imported.skipped += 1;
+            }
+        }
+    }
+
+    files.sort_by(|a, b| (a.started, &a.path).cmp(&(b.started, &b.path)));
+
+    Ok(files)
+}
+
+impl Db {
+    /// Records the stations and downloads found in a directory written by
+    /// `FilesRecordSink`, so we know what data we have without syncing.
+    /// Running it again only picks up what's changed. Downloads are
+    /// recorded as uploaded, they were synced before anything was queueing
+    /// uploads and the portal most likely has them already.
+    pub fn import_directory(&self, base_path: &Path) -> Result<Imported> {
+        let mut imported = Imported::default();
+
+        for entry in std::fs::read_dir(base_path)? {
+            let device_path = entry?.path();
+            if device_path.is_dir() {
+                self.import_device(&device_path, &mut imported)?;
+            }
+        }
+
+        info!("{} {:?}", base_path.display(), imported);
+
+        Ok(imported)
+    }
+
+    fn import_device(&self, device_path: &Path, imported: &mut Imported) -> Result<()> {
+        let files = synced_files(device_path, imported)?;
+
+        // Files are kept under a directory named for the station.
+        let Some(device_id) = files
+            .iter()
+            .rev()
+            .find_map(|f| f.header("Fk-DeviceId"))
+            .or_else(|| device_path.file_name().and_then(|n| n.to_str()))
+            .map(|id| DeviceId(id.to_owned()))
+        else {
+            return Ok(());
+        };
+
+        // Meta files aren't named for a sync, so only data says when we
+        // last heard from the station.
+        let Some(latest) = files
+            .iter()
+            .rev()
+            .find(|f| f.meta.kind() != Some(StreamKind::Meta))
+        else {
+            return Ok(());
+        };
+        let header = |key: &str| {
+            files
+                .iter()
+                .rev()
+                .filter(|f| f.meta.kind() != Some(StreamKind::Meta))
+                .chain(files.iter().rev())
+                .find_map(|f| f.header(key))
+        };
+
+        let modules = match files
+            .iter()
+            .rev()
+            .find(|f| f.meta.kind() == Some(StreamKind::Meta))
+        {
+            Some(file) => file.modules()?,
+            None => None,
+        };
+
+        let existing = self.hydrate_station(&device_id)?;
+        let station = match existing {
+            Some(existing) if existing.last_seen >= latest.started => existing,
+            Some(existing) => {
+                let incoming = Station {
+                    name: header("Fk-DeviceName").unwrap_or(&existing.name).to_owned(),
+                    generation_id: header("Fk-Generation")
+                        .unwrap_or(&existing.generation_id)
+                        .to_owned(),
+                    modules: modules.unwrap_or_else(|| existing.modules.clone()),
+                    ..existing.clone()
+                };
+                imported.stations += 1;
+                self.persist_station(&Station {
+                    last_seen: latest.started,
+                    ..merge::merge(Some(existing), incoming)?
+                })?
+            }
+            None => {
+                imported.stations += 1;
+                self.persist_station(&Station {
+                    id: None,
+                    device_id: device_id.clone(),
+                    generation_id: header("Fk-Generation").unwrap_or_default().to_owned(),
+                    name: header("Fk-DeviceName").unwrap_or_default().to_owned(),
+                    firmware: Firmware {
+                        label: String::new(),
+                        time: 0,
+                    },
+                    last_seen: latest.started,
+                    status: None,
+                    meta: Stream::default(),
+                    data: Stream::default(),
+                    battery: Battery::default(),
+                    solar: Solar::default(),
+                    modules: modules.unwrap_or_default(),
+                })?
+            }
+        };
+        let station_id = station.id.ok_or(DbError::SeriousBug)?;
+
+        let downloads = self.get_station_downloads(station_id)?;
+
+        for file in files.iter() {
+            if file.meta.kind() == Some(StreamKind::Meta) {
+                continue;
+            }
+            let Some(blocks) = file.meta.blocks() else {
+                warn!("{} malformed Fk-Blocks", file.path.display());
+                imported.skipped += 1;
+                continue;
+            };
+
+            let path = file.path.display().to_string();
+            let download = StationDownload {
+                id: None,
+                station_id: Some(station_id),
+                generation_id: file.header("Fk-Generation").unwrap_or_default().to_owned(),
+                started: file.started,
+                begin: *blocks.start(),
+                end: *blocks.end(),
+                path: path.clone(),
+                uploaded: true,
+                finished: Some(file.started),
+                size: Some(file.size as i64),
+                error: None,
+            };
+
+            match downloads.iter().find(|d| d.path == path) {
+                Some(existing)
+                    if (
+                        existing.begin,
+                        existing.end,
+                        existing.size,
+                        &existing.generation_id,
+                    ) == (
+                        download.begin,
+                        download.end,
+                        download.size,
+                        &download.generation_id,
+                    ) =>
+                {
+                    imported.unchanged += 1;
+                }
+                Some(existing) => {
+                    self.update_station_download(&StationDownload {
+                        generation_id: download.generation_id,
+                        begin: download.begin,
+                        end: download.end,
+                        size: download.size,
+                        ..existing.clone()
+                    })?;
+                    imported.downloads += 1;
+                }
+                None => {
+                    self.add_station_download(&download)?;
+                    imported.downloads += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use protos::data::{DataRecord, Metadata, ModuleInfo, SensorInfo};
+    use tempdir::TempDir;
+
+    use crate::test::*;
+
+    use super::*;
+
+    fn write_file(path: &Path, records: &[DataRecord], headers: &[(&str, &str)]) -> Result<()> {
+        let bytes: Vec<u8> = records
+            .iter()
+            .flat_map(|r| r.encode_length_delimited_to_vec())
+            .collect();
+        std::fs::write(path, bytes)?;
+        let meta = FileMeta {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        std::fs::write(meta_path(path), serde_json::to_string(&meta)?)?;
+        Ok(())
+    }
+
+    fn data_headers<'a>(blocks: &'a str, name: &'a str) -> Vec<(&'a str, &'a str)> {
+        vec![
+            ("Fk-DeviceId", "0011"),
+            ("Fk-Generation", "aabb"),
+            ("Fk-DeviceName", name),
+            ("Fk-Blocks", blocks),
+            ("Fk-Type", "data"),
+        ]
+    }
+
+    fn fk_data() -> Result<TempDir> {
+        let dir = TempDir::new("fk-tests-import")?;
+        let device_path = dir.path().join("0011");
+        std::fs::create_dir_all(&device_path)?;
+
+        write_file(
+            &device_path.join("20230710_120000.fkpb"),
+            &[DataRecord::default(), DataRecord::default()],
+            &data_headers("0,1", "Early Impala"),
+        )?;
+        write_file(
+            &device_path.join("20230711_120000.fkpb"),
+            &[DataRecord::default()],
+            &data_headers("2,2", "Late Impala"),
+        )?;
+        write_file(
+            &device_path.join("meta.fkpb"),
+            &[DataRecord {
+                metadata: Some(Metadata::default()),
+                modules: vec![ModuleInfo {
+                    id: vec![0x01, 0x02],
+                    name: "modules.water.temp".to_owned(),
+                    sensors: vec![SensorInfo {
+                        number: 0,
+                        name: "temp".to_owned(),
+                        unit_of_measure: "°C".to_owned(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            &[
+                ("Fk-DeviceId", "0011"),
+                ("Fk-Generation", "aabb"),
+                ("Fk-Blocks", "0,0"),
+                ("Fk-Type", "meta"),
+            ],
+        )?;
+
+        Ok(dir)
+    }
+
+    #[test]
+    fn test_imports_directory() -> Result<()> {
+        let dir = fk_data()?;
+        let mut db = Db::new();
+        db.open()?;
+
+        let imported = db.import_directory(dir.path())?;
+        assert_eq!(
+            imported,
+            Imported {
+                stations: 1,
+                downloads: 2,
+                unchanged: 0,
+                skipped: 0,
+            }
+        );
+
+        let station = db
+            .hydrate_station(&DeviceId("0011".to_owned()))?
+            .expect("No station");
+        assert_eq!(station.name, "Late Impala");
+        assert_eq!(station.generation_id, "aabb");
+        assert_eq!(station.modules.len(), 1);
+        assert_eq!(station.modules[0].hardware_id, "0102");
+        assert_eq!(station.modules[0].sensors[0].key, "temp");
+
+        let downloads = db.get_station_downloads(station.id.unwrap())?;
+        assert_eq!(downloads.len(), 2);
+        assert_eq!((downloads[0].begin, downloads[0].end), (0, 1));
+        assert_eq!((downloads[1].begin, downloads[1].end), (2, 2));
+        assert!(downloads[1].path.ends_with("20230711_120000.fkpb"));
+        assert!(downloads.iter().all(|d| d.uploaded));
+        assert_eq!(
+            downloads[1].size,
+            Some(std::fs::metadata(&downloads[1].path)?.len() as i64)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_is_idempotent() -> Result<()> {
+        let dir = fk_data()?;
+        let mut db = Db::new();
+        db.open()?;
+
+        db.import_directory(dir.path())?;
+        let imported = db.import_directory(dir.path())?;
+        assert_eq!(
+            imported,
+            Imported {
+                stations: 0,
+                downloads: 0,
+                unchanged: 2,
+                skipped: 0,
+            }
+        );
+
+        let station = db
+            .hydrate_station(&DeviceId("0011".to_owned()))?
+            .expect("No station");
+        assert_eq!(station.modules.len(), 1);
+        assert_eq!(db.get_station_downloads(station.id.unwrap())?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_skips_merged_generation() -> Result<()> {
+        let dir = fk_data()?;
+        let device_path = dir.path().join("0011");
+        sync::merge_files(
+            &[
+                device_path.join("20230710_120000.fkpb"),
+                device_path.join("20230711_120000.fkpb"),
+            ],
+            &device_path.join("aabb.fkpb"),
+        )?;
+
+        let mut db = Db::new();
+        db.open()?;
+
+        let imported = db.import_directory(dir.path())?;
+        assert_eq!(imported.downloads, 2);
+
+        let station = db
+            .hydrate_station(&DeviceId("0011".to_owned()))?
+            .expect("No station");
+        let downloads = db.get_station_downloads(station.id.unwrap())?;
+        assert!(downloads.iter().all(|d| !d.path.ends_with("aabb.fkpb")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_leaves_newer_station_alone() -> Result<()> {
+        let dir = fk_data()?;
+        let mut db = Db::new();
+        db.open()?;
+
+        let station = db.add_station(&Station {
+            device_id: DeviceId("0011".to_owned()),
+            ..build().station().build()
+        })?;
+
+        let imported = db.import_directory(dir.path())?;
+        assert_eq!(imported.stations, 0);
+        assert_eq!(imported.downloads, 2);
+
+        let hydrated = db.hydrate_station(&station.device_id)?.expect("No station");
+        assert_eq!(hydrated.name, "Hoppy Kangaroo");
+
+        Ok(())
+    }
+}
@@ -1,19 +1,27 @@
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use rusqlite::{params, Connection};
+use std::{ops::Range, path::Path};
 use thiserror::Error;
 use tracing::*;
 
+mod downloads;
+mod history;
+mod import;
 mod merge;
 mod migrations;
 mod model;
 mod parse_reply;
+mod readings;
 
+pub use import::Imported;
 pub use model::*;
 pub use parse_reply::*;
+pub use readings::Ingested;
 
 pub struct Db {
     conn: Option<Connection>,
+    retention: Retention,
 }
 
 #[derive(Error, Debug)]
@@ -22,15 +30,43 @@ pub enum DbError {
     SeriousBug,
 }
 
+impl Default for Db {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Db {
     pub fn new() -> Self {
-        Self { conn: None }
+        Self {
+            conn: None,
+            retention: Retention::default(),
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
     }
 
     pub fn open(&mut self) -> Result<()> {
-        let mut conn = Connection::open_in_memory()?;
+        self.prepare(Connection::open_in_memory()?)
+    }
+
+    pub fn open_path(&mut self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        info!("opening {}", path.display());
+
+        self.prepare(Connection::open(path)?)
+    }
 
-        conn.pragma_update(None, "journal_mode", &"WAL")?;
+    fn prepare(&mut self, mut conn: Connection) -> Result<()> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
 
         let migrations = migrations::get_migrations();
 
@@ -46,6 +82,8 @@ impl Db {
         let saving = merge::merge(existing, incoming)?;
         let saved = self.persist_station(&saving)?;
 
+        self.add_live_values(&saved)?;
+
         info!("{:?} saved {:?}", &saved.device_id, &saved.id);
 
         Ok(saved)
@@ -58,7 +96,7 @@ impl Db {
     ) -> Result<Station> {
         let incoming = http_reply_to_station(reply)?;
         assert_eq!(device_id, incoming.device_id);
-        Ok(self.synchornize(incoming)?)
+        self.synchornize(incoming)
     }
 
     pub fn hydrate_station(&self, device_id: &DeviceId) -> Result<Option<Station>> {
@@ -249,9 +287,9 @@ impl Db {
                FROM station"#,
         )?;
 
-        let stations = stmt.query_map(params![], |row| Ok(self.row_to_station(row)?))?;
+        let stations = stmt.query_map(params![], |row| self.row_to_station(row))?;
 
-        Ok(stations.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()?)
+        stations.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
     }
 
     pub fn get_station_by_device_id(&self, device_id: &DeviceId) -> Result<Option<Station>> {
@@ -262,7 +300,7 @@ impl Db {
                FROM station WHERE device_id = ?"#,
         )?;
 
-        let stations = stmt.query_map(params![device_id.0], |row| Ok(self.row_to_station(row)?))?;
+        let stations = stmt.query_map(params![device_id.0], |row| self.row_to_station(row))?;
         let stations = stations.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()?;
         Ok(stations.first().cloned())
     }
@@ -369,7 +407,7 @@ impl Db {
             })
         })?;
 
-        Ok(modules.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()?)
+        modules.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
     }
 
     pub fn add_sensor(&self, sensor: &Sensor) -> Result<Sensor> {
@@ -482,7 +520,54 @@ impl Db {
             })
         })?;
 
-        Ok(sensors.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()?)
+        sensors.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
+    }
+
+    /// Aggregates a sensor's readings over the range in SQLite. Buckets of an
+    /// interval are aligned to the epoch, so the first may start before the
+    /// range does, and without an interval the whole range is one bucket.
+    /// Buckets without readings are left out.
+    pub fn get_aggregated_readings(
+        &self,
+        sensor_id: i64,
+        range: Range<DateTime<Utc>>,
+        interval: Option<Interval>,
+    ) -> Result<Vec<Aggregate>> {
+        let (start, end) = (range.start.timestamp(), range.end.timestamp());
+        let (offset, width) = match interval {
+            Some(interval) => (0, interval.seconds()),
+            None => (start, (end - start).max(1)),
+        };
+
+        let mut stmt = self.require_opened()?.prepare(
+            r#"
+            WITH bucketed AS (
+                SELECT (time - ?1) / ?2 AS bucket, calibrated_value AS value,
+                       ROW_NUMBER() OVER (PARTITION BY (time - ?1) / ?2 ORDER BY time DESC, reading DESC) AS recency
+                FROM reading WHERE sensor_id = ?3 AND time >= ?4 AND time < ?5
+            )
+            SELECT bucket, COUNT(*), MIN(value), MAX(value), AVG(value), MAX(CASE WHEN recency = 1 THEN value END)
+            FROM bucketed GROUP BY bucket ORDER BY bucket
+            "#,
+        )?;
+
+        let aggregates = stmt.query_map(params![offset, width, sensor_id, start, end], |row| {
+            let bucket: i64 = row.get(0)?;
+            let mean: f64 = row.get(4)?;
+            Ok(Aggregate {
+                time: Utc
+                    .timestamp_opt(offset + bucket * width, 0)
+                    .single()
+                    .unwrap_or_default(),
+                count: row.get(1)?,
+                min: row.get(2)?,
+                max: row.get(3)?,
+                mean: mean as f32,
+                last: row.get(5)?,
+            })
+        })?;
+
+        aggregates.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
     }
 
     pub fn add_station_download(&self, download: &StationDownload) -> Result<StationDownload> {
@@ -556,40 +641,78 @@ impl Db {
         Ok(download.clone())
     }
 
+    fn row_to_station_download(
+        &self,
+        row: &rusqlite::Row,
+    ) -> Result<StationDownload, rusqlite::Error> {
+        let started: String = row.get(3)?;
+        let started = DateTime::parse_from_rfc3339(&started)
+            .expect("Parsing started")
+            .with_timezone(&Utc);
+        let finished: Option<String> = row.get(8)?;
+        let finished = finished.map(|f| {
+            DateTime::parse_from_rfc3339(&f)
+                .expect("Parsing finished")
+                .with_timezone(&Utc)
+        });
+
+        Ok(StationDownload {
+            id: row.get(0)?,
+            station_id: row.get(1)?,
+            generation_id: row.get(2)?,
+            started,
+            begin: row.get(4)?,
+            end: row.get(5)?,
+            path: row.get(6)?,
+            uploaded: row.get(7)?,
+            finished,
+            size: row.get(9)?,
+            error: row.get(10)?,
+        })
+    }
+
     pub fn get_station_downloads(&self, station_id: i64) -> Result<Vec<StationDownload>> {
         let mut stmt = self.require_opened()?.prepare(
-            r#"SELECT id, station_id, generation_id, started, begin, end, path, uploaded, finished, size, error, id
+            r#"SELECT id, station_id, generation_id, started, begin, end, path, uploaded, finished, size, error
                FROM station_download WHERE station_id = ?"#,
         )?;
 
-        let downloads = stmt.query_map(params![station_id], |row| {
-            let started: String = row.get(3)?;
-            let started = DateTime::parse_from_rfc3339(&started)
-                .expect("Parsing started")
-                .with_timezone(&Utc);
-            let finished: Option<String> = row.get(8)?;
-            let finished = finished.map(|f| {
-                DateTime::parse_from_rfc3339(&f)
-                    .expect("Parsing finished")
-                    .with_timezone(&Utc)
-            });
+        let downloads =
+            stmt.query_map(params![station_id], |row| self.row_to_station_download(row))?;
 
-            Ok(StationDownload {
-                id: row.get(0)?,
-                station_id: row.get(1)?,
-                generation_id: row.get(2)?,
-                started,
-                begin: row.get(4)?,
-                end: row.get(5)?,
-                path: row.get(6)?,
-                uploaded: row.get(7)?,
-                finished,
-                size: row.get(9)?,
-                error: row.get(10)?,
-            })
-        })?;
+        downloads.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
+    }
+
+    /// Downloads that have neither finished nor failed, newest first.
+    pub fn get_unfinished_station_downloads(
+        &self,
+        station_id: i64,
+    ) -> Result<Vec<StationDownload>> {
+        let mut stmt = self.require_opened()?.prepare(
+            r#"SELECT id, station_id, generation_id, started, begin, end, path, uploaded, finished, size, error
+               FROM station_download WHERE station_id = ? AND finished IS NULL AND error IS NULL
+               ORDER BY id DESC"#,
+        )?;
+
+        let downloads =
+            stmt.query_map(params![station_id], |row| self.row_to_station_download(row))?;
 
-        Ok(downloads.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()?)
+        downloads.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
+    }
+
+    /// Downloads that wrote a file the portal hasn't been sent yet, oldest
+    /// first.
+    pub fn get_pending_uploads(&self) -> Result<Vec<StationDownload>> {
+        let mut stmt = self.require_opened()?.prepare(
+            r#"SELECT id, station_id, generation_id, started, begin, end, path, uploaded, finished, size, error
+               FROM station_download
+               WHERE finished IS NOT NULL AND error IS NULL AND NOT uploaded AND size > 0 AND path != ''
+               ORDER BY id"#,
+        )?;
+
+        let downloads = stmt.query_map([], |row| self.row_to_station_download(row))?;
+
+        downloads.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
     }
 
     pub fn require_opened(&self) -> Result<&Connection> {
@@ -602,6 +725,8 @@ impl Db {
 
 #[cfg(test)]
 mod tests {
+    use tempdir::TempDir;
+
     use crate::test::*;
 
     use super::*;
@@ -614,6 +739,26 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_opening_path_db_persists() -> Result<()> {
+        let dir = TempDir::new("fk-tests-store")?;
+        let path = dir.path().join("nested").join("fk.db");
+
+        {
+            let mut db = Db::new();
+            db.open_path(&path)?;
+            db.add_station(&build().station().build())?;
+        }
+
+        let mut db = Db::new();
+        db.open_path(&path)?;
+
+        let stations = db.get_stations()?;
+        assert_eq!(stations.len(), 1);
+
+        Ok(())
+    }
+
     #[test]
     fn test_adding_new_station() -> Result<()> {
         let mut db = Db::new();
@@ -647,14 +792,14 @@ mod tests {
 
         let stations = db.get_stations()?;
         assert_eq!(stations.len(), 1);
-        assert_eq!(stations.get(0).unwrap().name, "Hoppy Kangaroo");
+        assert_eq!(stations.first().unwrap().name, "Hoppy Kangaroo");
 
         added.name = "Tired Kangaroo".to_owned();
         db.update_station(&added)?;
 
         let stations = db.get_stations()?;
         assert_eq!(stations.len(), 1);
-        assert_eq!(stations.get(0).unwrap().name, "Tired Kangaroo");
+        assert_eq!(stations.first().unwrap().name, "Tired Kangaroo");
 
         Ok(())
     }
@@ -694,14 +839,14 @@ mod tests {
 
         let modules = db.get_modules(station.id.expect("No station id"))?;
         assert_eq!(modules.len(), 1);
-        assert_eq!(modules.get(0).unwrap().key, "module-0");
+        assert_eq!(modules.first().unwrap().key, "module-0");
 
         added.key = "renamed-module-0".to_owned();
         db.update_module(&added)?;
 
         let modules = db.get_modules(station.id.expect("No station id"))?;
         assert_eq!(modules.len(), 1);
-        assert_eq!(modules.get(0).unwrap().key, "renamed-module-0");
+        assert_eq!(modules.first().unwrap().key, "renamed-module-0");
 
         Ok(())
     }
@@ -742,14 +887,14 @@ mod tests {
 
         let sensors = db.get_sensors(module.id.expect("No module id"))?;
         assert_eq!(sensors.len(), 1);
-        assert_eq!(sensors.get(0).unwrap().key, "sensor-0");
+        assert_eq!(sensors.first().unwrap().key, "sensor-0");
 
         sensor.key = "renamed-sensor-0".to_owned();
         db.update_sensor(&sensor)?;
 
         let sensors = db.get_sensors(module.id.expect("No module id"))?;
         assert_eq!(sensors.len(), 1);
-        assert_eq!(sensors.get(0).unwrap().key, "renamed-sensor-0");
+        assert_eq!(sensors.first().unwrap().key, "renamed-sensor-0");
 
         Ok(())
     }
@@ -809,7 +954,7 @@ mod tests {
 
         assert_eq!(first.id, second.id);
         assert_eq!(second.modules.len(), 1);
-        assert_eq!(second.modules.get(0).map(|m| m.removed), Some(true));
+        assert_eq!(second.modules.first().map(|m| m.removed), Some(true));
 
         Ok(())
     }
@@ -875,15 +1020,133 @@ mod tests {
 
         let stations = db.get_station_downloads(station.id.unwrap())?;
         assert_eq!(stations.len(), 1);
-        assert!(stations.get(0).unwrap().finished.is_none());
+        assert!(stations.first().unwrap().finished.is_none());
 
         added.finished = Some(Utc::now());
         db.update_station_download(&added)?;
 
         let stations = db.get_station_downloads(station.id.unwrap())?;
         assert_eq!(stations.len(), 1);
-        assert!(stations.get(0).unwrap().finished.is_some());
+        assert!(stations.first().unwrap().finished.is_some());
+
+        Ok(())
+    }
+
+    fn add_sensor_readings(db: &Db) -> Result<i64> {
+        let station = db.persist_station(
+            &build()
+                .station()
+                .module(build().module().basic("fk.modules.test").build())
+                .build(),
+        )?;
+        let sensor_id = station.modules[0].sensors[0].id.unwrap();
+
+        // Two hours of readings, a minute apart, valued by the minute.
+        let readings: Vec<_> = (0..120)
+            .map(|n| Reading {
+                id: None,
+                sensor_id,
+                time: Utc.timestamp_opt(1688601600 + n * 60, 0).unwrap(),
+                reading: n as u64,
+                value: (n % 60) as f32,
+                uncalibrated: 0.0,
+            })
+            .collect();
+        db.add_readings(&readings)?;
+
+        Ok(sensor_id)
+    }
+
+    #[test]
+    fn test_aggregating_readings_by_interval() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+        let sensor_id = add_sensor_readings(&db)?;
+
+        let start = Utc.timestamp_opt(1688601600, 0).unwrap();
+        let aggregates = db.get_aggregated_readings(
+            sensor_id,
+            start..start + chrono::Duration::days(1),
+            Some(Interval::HOUR),
+        )?;
+
+        assert_eq!(
+            aggregates,
+            vec![
+                Aggregate {
+                    time: start,
+                    count: 60,
+                    min: 0.0,
+                    max: 59.0,
+                    mean: 29.5,
+                    last: 59.0,
+                },
+                Aggregate {
+                    time: start + chrono::Duration::hours(1),
+                    count: 60,
+                    min: 0.0,
+                    max: 59.0,
+                    mean: 29.5,
+                    last: 59.0,
+                },
+            ]
+        );
+
+        let aggregates = db.get_aggregated_readings(
+            sensor_id,
+            start..start + chrono::Duration::minutes(30),
+            Some("15m".parse()?),
+        )?;
+        assert_eq!(aggregates.len(), 2);
+        assert_eq!(aggregates[1].min, 15.0);
+        assert_eq!(aggregates[1].last, 29.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregating_readings_over_range() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+        let sensor_id = add_sensor_readings(&db)?;
+
+        let start = Utc.timestamp_opt(1688601600 + 90 * 60, 0).unwrap();
+        let aggregates =
+            db.get_aggregated_readings(sensor_id, start..start + chrono::Duration::hours(1), None)?;
+
+        assert_eq!(
+            aggregates,
+            vec![Aggregate {
+                time: start,
+                count: 30,
+                min: 30.0,
+                max: 59.0,
+                mean: 44.5,
+                last: 59.0,
+            }]
+        );
+
+        assert!(db
+            .get_aggregated_readings(sensor_id, start..start, None)?
+            .is_empty());
 
         Ok(())
     }
+
+    #[test]
+    fn test_parsing_intervals() {
+        assert_eq!("1m".parse::<Interval>().ok(), Some(Interval::MINUTE));
+        assert_eq!("1h".parse::<Interval>().ok(), Some(Interval::HOUR));
+        assert_eq!("1d".parse::<Interval>().ok(), Some(Interval::DAY));
+        assert_eq!(
+            "90s".parse::<Interval>().ok(),
+            Interval::from_seconds(90).ok()
+        );
+        assert!("0h".parse::<Interval>().is_err());
+        assert!("h".parse::<Interval>().is_err());
+        assert!("1w".parse::<Interval>().is_err());
+        assert!("999999999999999d".parse::<Interval>().is_err());
+        assert!(Interval::from_seconds(0).is_err());
+        assert!(Interval::from_seconds(-60).is_err());
+    }
 }
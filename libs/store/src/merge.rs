@@ -38,8 +38,7 @@ fn merge_modules(existing: Vec<Module>, incoming: Vec<Module>) -> Result<Vec<Mod
         .chain(incoming.keys().clone())
         .collect();
 
-    Ok(keys
-        .into_iter()
+    keys.into_iter()
         .map(|key| (existing.get(key), incoming.get(key)))
         .map(|pair| match pair {
             (Some(existing), Some(incoming)) => Ok(Module {
@@ -58,7 +57,7 @@ fn merge_modules(existing: Vec<Module>, incoming: Vec<Module>) -> Result<Vec<Mod
             }),
             (None, None) => panic!("Surprise module key?"),
         })
-        .collect::<Result<Vec<_>>>()?)
+        .collect::<Result<Vec<_>>>()
 }
 
 fn merge_sensors(existing: Vec<Sensor>, incoming: Vec<Sensor>) -> Result<Vec<Sensor>> {
@@ -71,8 +70,7 @@ fn merge_sensors(existing: Vec<Sensor>, incoming: Vec<Sensor>) -> Result<Vec<Sen
         .chain(incoming.keys().clone())
         .collect();
 
-    Ok(keys
-        .into_iter()
+    keys.into_iter()
         .map(|key| (existing.get(key), incoming.get(key)))
         .map(|pair| match pair {
             (Some(existing), Some(incoming)) => Ok(Sensor {
@@ -90,5 +88,5 @@ fn merge_sensors(existing: Vec<Sensor>, incoming: Vec<Sensor>) -> Result<Vec<Sen
             }),
             (None, None) => panic!("Surprise sensor key?"),
         })
-        .collect::<Result<Vec<_>>>()?)
+        .collect::<Result<Vec<_>>>()
 }
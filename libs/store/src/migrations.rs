@@ -1,8 +1,9 @@
 use rusqlite_migration::{Migrations, M};
 
 pub(crate) fn get_migrations<'m>() -> Migrations<'m> {
-    Migrations::new(vec![M::up(
-        r#"
+    Migrations::new(vec![
+        M::up(
+            r#"
         CREATE TABLE station (
             id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
             device_id TEXT NOT NULL,
@@ -74,7 +75,37 @@ pub(crate) fn get_migrations<'m>() -> Migrations<'m> {
         CREATE INDEX station_download_idx_station_id ON station_download (station_id);
         CREATE INDEX station_download_idx_generation_id ON station_download (generation_id);
         "#,
-    )])
+        ),
+        // Times are seconds since the epoch so ranges and buckets are cheap.
+        M::up(
+            r#"
+        CREATE TABLE reading (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            sensor_id INTEGER NOT NULL REFERENCES sensor(id),
+            time INTEGER NOT NULL,
+            reading INTEGER NOT NULL,
+            calibrated_value REAL NOT NULL,
+            uncalibrated_value REAL NOT NULL
+        );
+
+        CREATE UNIQUE INDEX reading_idx_sensor_id_time_reading ON reading (sensor_id, time, reading);
+        "#,
+        ),
+        M::up(
+            r#"
+        CREATE TABLE live_value (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            sensor_id INTEGER NOT NULL REFERENCES sensor(id),
+            time INTEGER NOT NULL,
+            calibrated_value REAL NOT NULL,
+            uncalibrated_value REAL NOT NULL
+        );
+
+        CREATE UNIQUE INDEX live_value_idx_sensor_id_time ON live_value (sensor_id, time);
+        CREATE INDEX live_value_idx_time ON live_value (time);
+        "#,
+        ),
+    ])
 }
 
 #[cfg(test)]
@@ -1,4 +1,6 @@
 use chrono::{DateTime, Utc};
+use std::str::FromStr;
+use thiserror::Error;
 
 // NOTE This is also declared in the `discovery` crate.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -78,13 +80,102 @@ pub struct Sensor {
     pub removed: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct LiveValue {
     pub time: DateTime<Utc>,
     pub value: f32,
     pub uncalibrated: f32,
 }
 
+/// How much live value history to keep. Anything older than `max_age`, or
+/// beyond the newest `max_per_sensor` for a sensor, is removed.
+#[derive(Clone, Debug)]
+pub struct Retention {
+    pub max_age: Option<chrono::Duration>,
+    pub max_per_sensor: Option<u64>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age: Some(chrono::Duration::days(90)),
+            max_per_sensor: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    pub id: Option<i64>,
+    pub sensor_id: i64,
+    pub time: DateTime<Utc>,
+    pub reading: u64,
+    pub value: f32,
+    pub uncalibrated: f32,
+}
+
+/// Width of the buckets readings are aggregated into, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interval(i64);
+
+impl Interval {
+    pub const MINUTE: Interval = Interval(60);
+    pub const HOUR: Interval = Interval(60 * 60);
+    pub const DAY: Interval = Interval(60 * 60 * 24);
+
+    /// Buckets have to be at least a second wide.
+    pub fn from_seconds(seconds: i64) -> Result<Self, MalformedInterval> {
+        if seconds <= 0 {
+            return Err(MalformedInterval(format!("{}s", seconds)));
+        }
+
+        Ok(Self(seconds))
+    }
+
+    pub fn seconds(&self) -> i64 {
+        self.0
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Malformed interval: {0}")]
+pub struct MalformedInterval(String);
+
+impl FromStr for Interval {
+    type Err = MalformedInterval;
+
+    /// Parses intervals like `30s`, `15m`, `1h` or `1d`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || MalformedInterval(s.to_owned());
+        let unit = match s.chars().last().ok_or_else(malformed)? {
+            's' => 1,
+            'm' => Self::MINUTE.0,
+            'h' => Self::HOUR.0,
+            'd' => Self::DAY.0,
+            _ => return Err(malformed()),
+        };
+        let count: i64 = s[..s.len() - 1].parse().map_err(|_| malformed())?;
+        if count <= 0 {
+            return Err(malformed());
+        }
+
+        Ok(Self(count.checked_mul(unit).ok_or_else(malformed)?))
+    }
+}
+
+/// Summary of a sensor's readings over one bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    pub count: u64,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// The most recent value in the bucket.
+    pub last: f32,
+}
+
 #[derive(Clone, Debug)]
 pub struct StationDownload {
     pub id: Option<i64>,
@@ -229,7 +320,7 @@ pub(crate) mod test {
                 uncalibrated_uom: "mV".to_owned(),
                 value: Some(LiveValue {
                     time: Utc::now(),
-                    value: 3.14159,
+                    value: std::f32::consts::PI,
                     uncalibrated: 1200.0,
                 }),
                 removed: false,
@@ -279,14 +370,14 @@ pub(crate) mod test {
             Sensor {
                 id: None,
                 module_id: self.module_id,
-                number: self.number as u32,
+                number: self.number,
                 flags: 0,
                 key: format!("sensor-{}", self.number),
                 calibrated_uom: "m".to_owned(),
                 uncalibrated_uom: "mV".to_owned(),
                 value: Some(LiveValue {
                     time: Utc::now(),
-                    value: 3.14159,
+                    value: std::f32::consts::PI,
                     uncalibrated: 1200.0,
                 }),
                 removed: false,
@@ -52,7 +52,7 @@ pub fn http_reply_to_station(reply: HttpReply) -> Result<Station, ReplyMappingEr
         .flat_map(|r| {
             r.modules.iter().map(|m| {
                 let time = Utc.timestamp_millis_opt(r.time as i64 * 1000).unwrap();
-                Ok(to_module_with_live_readings(&m, time)?)
+                to_module_with_live_readings(m, time)
             })
         })
         .collect::<Result<Vec<_>, ReplyMappingError>>()?;
@@ -67,14 +67,8 @@ pub fn http_reply_to_station(reply: HttpReply) -> Result<Station, ReplyMappingEr
             time: firmware.timestamp as i64,
         },
         last_seen: Utc::now(),
-        meta: streams
-            .get(1)
-            .map(|s| s.clone())
-            .unwrap_or(Stream::default()),
-        data: streams
-            .get(0)
-            .map(|s| s.clone())
-            .unwrap_or(Stream::default()),
+        meta: streams.get(1).cloned().unwrap_or_default(),
+        data: streams.first().cloned().unwrap_or_default(),
         battery: Battery {
             percentage: battery.percentage as f32,
             voltage: battery.voltage as f32,
@@ -94,7 +88,7 @@ fn to_module_with_live_readings(
     let sensors = m
         .readings
         .iter()
-        .map(|sc| Ok(to_sensor_with_live_readings(sc, time.clone())?))
+        .map(|sc| to_sensor_with_live_readings(sc, time))
         .collect::<Result<Vec<_>, ReplyMappingError>>()?;
 
     to_module(
@@ -112,7 +106,7 @@ fn to_module(
         .as_ref()
         .ok_or(ReplyMappingError::NoModuleHeader)?;
 
-    let configuration = if mc.configuration.len() > 0 {
+    let configuration = if !mc.configuration.is_empty() {
         Some(mc.configuration.clone())
     } else {
         None
@@ -0,0 +1,393 @@
+use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::params;
+use std::{collections::HashMap, ops::Range, path::Path};
+use tracing::*;
+
+use protos::{RecordsReader, ResolvedValue, Resolver};
+
+use crate::{Db, DbError, DeviceId, Reading};
+
+/// Values are written a batch at a time, each in its own transaction.
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Ingested {
+    pub records: u64,
+    pub readings: u64,
+    /// Values we already had.
+    pub duplicates: u64,
+    /// Records that couldn't be decoded or resolved, and values from
+    /// sensors the store doesn't know about.
+    pub skipped: u64,
+}
+
+/// Finds the sensor row for resolved values, remembering stations it has
+/// already looked up.
+struct Sensors<'a> {
+    db: &'a Db,
+    stations: HashMap<String, HashMap<(u32, String, String), i64>>,
+}
+
+impl<'a> Sensors<'a> {
+    fn new(db: &'a Db) -> Self {
+        Self {
+            db,
+            stations: HashMap::new(),
+        }
+    }
+
+    fn find(&mut self, value: &ResolvedValue) -> Result<Option<i64>> {
+        if !self.stations.contains_key(&value.device_id) {
+            let mut sensors = HashMap::new();
+            let station = self
+                .db
+                .hydrate_station(&DeviceId(value.device_id.clone()))?;
+            for module in station.into_iter().flat_map(|s| s.modules) {
+                for sensor in module.sensors.iter().filter(|s| !s.removed) {
+                    sensors.insert(
+                        (module.position, module.key.clone(), sensor.key.clone()),
+                        sensor.id.ok_or(DbError::SeriousBug)?,
+                    );
+                }
+            }
+            self.stations.insert(value.device_id.clone(), sensors);
+        }
+
+        Ok(self.stations[&value.device_id]
+            .get(&(
+                value.module_position,
+                value.module.clone(),
+                value.sensor.clone(),
+            ))
+            .copied())
+    }
+}
+
+impl Db {
+    /// Adds readings, ignoring any we already have for the same sensor, time
+    /// and reading number. Returns how many were added.
+    pub fn add_readings(&self, readings: &[Reading]) -> Result<usize> {
+        let conn = self.require_opened()?;
+        let tx = conn.unchecked_transaction()?;
+        let mut added = 0;
+
+        {
+            let mut stmt = tx.prepare(
+                r#"
+                INSERT OR IGNORE INTO reading
+                (sensor_id, time, reading, calibrated_value, uncalibrated_value) VALUES
+                (?, ?, ?, ?, ?)
+                "#,
+            )?;
+
+            for reading in readings {
+                added += stmt.execute(params![
+                    reading.sensor_id,
+                    reading.time.timestamp(),
+                    reading.reading,
+                    reading.value,
+                    reading.uncalibrated,
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(added)
+    }
+
+    /// Readings for a sensor at or after the start of the range and before
+    /// its end, oldest first.
+    pub fn get_readings(
+        &self,
+        sensor_id: i64,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Reading>> {
+        let mut stmt = self.require_opened()?.prepare(
+            r#"SELECT id, sensor_id, time, reading, calibrated_value, uncalibrated_value
+               FROM reading WHERE sensor_id = ? AND time >= ? AND time < ?
+               ORDER BY time, reading"#,
+        )?;
+
+        let readings = stmt.query_map(
+            params![sensor_id, range.start.timestamp(), range.end.timestamp()],
+            |row| {
+                let time: i64 = row.get(2)?;
+                Ok(Reading {
+                    id: row.get(0)?,
+                    sensor_id: row.get(1)?,
+                    time: Utc.timestamp_opt(time, 0).single().unwrap_or_default(),
+                    reading: row.get(3)?,
+                    value: row.get(4)?,
+                    uncalibrated: row.get(5)?,
+                })
+            },
+        )?;
+
+        readings.map(|r| Ok(r?)).collect::<Result<Vec<_>>>()
+    }
+
+    /// Stores the readings in a synced data file, labeled using the meta
+    /// they refer to. Sensors have to be known already, as they are after a
+    /// query or `import_directory`.
+    pub fn ingest_readings(&self, data_path: &Path, resolver: &Resolver) -> Result<Ingested> {
+        let mut ingested = Ingested::default();
+        let mut sensors = Sensors::new(self);
+        let mut batch = Vec::new();
+
+        for read in RecordsReader::open(data_path)? {
+            let read = read?;
+            ingested.records += 1;
+
+            let record = match read.record {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("#{} {:?}", read.number, e);
+                    ingested.skipped += 1;
+                    continue;
+                }
+            };
+
+            let Some(readings) = record.readings else {
+                continue;
+            };
+
+            let values = match resolver.resolve(read.number, &readings) {
+                Ok(values) => values,
+                Err(e) => {
+                    warn!("#{} {}", read.number, e);
+                    ingested.skipped += 1;
+                    continue;
+                }
+            };
+
+            for value in values {
+                let Some(sensor_id) = sensors.find(&value)? else {
+                    debug!(
+                        "#{} unknown sensor {}/{}",
+                        read.number, value.module, value.sensor
+                    );
+                    ingested.skipped += 1;
+                    continue;
+                };
+                let Some(time) = Utc.timestamp_opt(value.time, 0).single() else {
+                    ingested.skipped += 1;
+                    continue;
+                };
+
+                batch.push(Reading {
+                    id: None,
+                    sensor_id,
+                    time,
+                    reading: value.reading,
+                    value: value.value,
+                    uncalibrated: value.uncalibrated,
+                });
+            }
+
+            if batch.len() >= BATCH_SIZE {
+                self.flush_readings(&mut batch, &mut ingested)?;
+            }
+        }
+
+        self.flush_readings(&mut batch, &mut ingested)?;
+
+        info!("{} {:?}", data_path.display(), ingested);
+
+        Ok(ingested)
+    }
+
+    fn flush_readings(&self, batch: &mut Vec<Reading>, ingested: &mut Ingested) -> Result<()> {
+        let added = self.add_readings(batch)? as u64;
+        ingested.readings += added;
+        ingested.duplicates += batch.len() as u64 - added;
+        batch.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use protos::data::{
+        DataRecord, Metadata, ModuleInfo, Readings, SensorAndValue, SensorGroup, SensorInfo,
+    };
+    use tempdir::TempDir;
+
+    use crate::test::*;
+    use crate::Station;
+
+    use super::*;
+
+    const TIME: i64 = 1688659549;
+
+    fn meta() -> DataRecord {
+        DataRecord {
+            metadata: Some(Metadata {
+                device_id: vec![0x00, 0x11],
+                ..Default::default()
+            }),
+            modules: vec![ModuleInfo {
+                position: 0,
+                name: "fk.modules.test".to_owned(),
+                sensors: vec![
+                    SensorInfo {
+                        number: 0,
+                        name: "sensor-0".to_owned(),
+                        ..Default::default()
+                    },
+                    SensorInfo {
+                        number: 1,
+                        name: "sensor-1".to_owned(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn data(readings: std::ops::Range<u64>) -> Vec<u8> {
+        readings
+            .flat_map(|reading| {
+                DataRecord {
+                    readings: Some(Readings {
+                        reading,
+                        time: TIME + reading as i64 * 60,
+                        sensor_groups: vec![SensorGroup {
+                            module: 0,
+                            readings: vec![
+                                SensorAndValue {
+                                    sensor: 0,
+                                    value: reading as f32,
+                                    uncalibrated: 100.0,
+                                },
+                                SensorAndValue {
+                                    sensor: 1,
+                                    value: 0.5,
+                                    uncalibrated: 50.0,
+                                },
+                            ],
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+                .encode_length_delimited_to_vec()
+            })
+            .collect()
+    }
+
+    fn open_db() -> Result<(Db, Station)> {
+        let mut db = Db::new();
+        db.open()?;
+        let station = db.persist_station(&Station {
+            device_id: DeviceId("0011".to_owned()),
+            ..build()
+                .station()
+                .module(build().module().basic("fk.modules.test").build())
+                .build()
+        })?;
+        Ok((db, station))
+    }
+
+    fn resolver() -> Resolver {
+        let mut resolver = Resolver::new();
+        resolver.add(0, &meta());
+        resolver
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn test_adding_and_querying_readings() -> Result<()> {
+        let (db, station) = open_db()?;
+        let sensor_id = station.modules[0].sensors[0].id.unwrap();
+
+        let readings: Vec<_> = (0..10)
+            .map(|n| Reading {
+                id: None,
+                sensor_id,
+                time: at(TIME + n * 60),
+                reading: n as u64,
+                value: n as f32,
+                uncalibrated: 0.0,
+            })
+            .collect();
+
+        assert_eq!(db.add_readings(&readings)?, 10);
+        assert_eq!(db.add_readings(&readings[5..])?, 0);
+
+        let found = db.get_readings(sensor_id, at(TIME + 120)..at(TIME + 300))?;
+        assert_eq!(
+            found.iter().map(|r| r.reading).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(found[0].time, at(TIME + 120));
+        assert!(found[0].id.is_some());
+
+        let other = station.modules[0].sensors[1].id.unwrap();
+        assert!(db.get_readings(other, at(TIME)..at(TIME + 600))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingesting_data_file() -> Result<()> {
+        let (db, station) = open_db()?;
+        let dir = TempDir::new("fk-tests-readings")?;
+        let path = dir.path().join("data.fkpb");
+        std::fs::write(&path, data(0..5))?;
+
+        let ingested = db.ingest_readings(&path, &resolver())?;
+        assert_eq!(
+            ingested,
+            Ingested {
+                records: 5,
+                readings: 10,
+                duplicates: 0,
+                skipped: 0,
+            }
+        );
+
+        let sensor_id = station.modules[0].sensors[0].id.unwrap();
+        let found = db.get_readings(sensor_id, at(TIME)..at(TIME + 3600))?;
+        assert_eq!(
+            found.iter().map(|r| r.value).collect::<Vec<_>>(),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0]
+        );
+
+        // A later sync overlapping this one only adds what's new.
+        std::fs::write(&path, data(3..8))?;
+        let ingested = db.ingest_readings(&path, &resolver())?;
+        assert_eq!(ingested.readings, 6);
+        assert_eq!(ingested.duplicates, 4);
+        assert_eq!(
+            db.get_readings(sensor_id, at(TIME)..at(TIME + 3600))?.len(),
+            8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingesting_skips_unknown_sensors() -> Result<()> {
+        let mut db = Db::new();
+        db.open()?;
+        let dir = TempDir::new("fk-tests-readings")?;
+        let path = dir.path().join("data.fkpb");
+        std::fs::write(&path, data(0..2))?;
+
+        let ingested = db.ingest_readings(&path, &resolver())?;
+        assert_eq!(ingested.readings, 0);
+        assert_eq!(ingested.skipped, 4);
+
+        Ok(())
+    }
+}
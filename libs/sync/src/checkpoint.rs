@@ -0,0 +1,35 @@
+use range_set_blaze::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+/// The records received so far for a single sync of a station generation,
+/// saved so that an interrupted sync can pick up where it left off.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub sync_id: String,
+    pub generation_id: String,
+    /// The first record this sync asked for, non-zero for incremental syncs.
+    #[serde(default)]
+    pub head: u64,
+    pub received: Vec<RangeInclusive<u64>>,
+}
+
+impl Checkpoint {
+    pub fn new(
+        sync_id: &str,
+        generation_id: &str,
+        head: u64,
+        received: &RangeSetBlaze<u64>,
+    ) -> Self {
+        Self {
+            sync_id: sync_id.to_owned(),
+            generation_id: generation_id.to_owned(),
+            head,
+            received: received.ranges().collect(),
+        }
+    }
+
+    pub fn to_set(&self) -> RangeSetBlaze<u64> {
+        RangeSetBlaze::from_iter(self.received.iter().cloned())
+    }
+}
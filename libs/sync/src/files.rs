@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Context, Result};
 use itertools::*;
-use quick_protobuf::Reader;
+use quick_protobuf::{BytesReader, Reader};
+use range_set_blaze::prelude::*;
 use std::{
     collections::HashMap,
     fs::OpenOptions,
@@ -12,13 +13,17 @@ use std::{
 use tracing::*;
 
 use crate::{
+    checkpoint::Checkpoint,
+    merge::{merge_files, Merged},
     proto::{Identity, ReceivedRecords, Record},
-    RecordsSink,
+    repair::{repair_file, write_atomically, Repaired},
+    Flushed, RecordsSink,
 };
 use discovery::DeviceId;
-use protos::FileMeta;
+use protos::{meta_path, FileMeta, StreamKind};
 
 struct Previous {
+    sync_id: String,
     path: PathBuf,
     range: RangeInclusive<u64>,
 }
@@ -49,7 +54,7 @@ impl FilesRecordSink {
             .with_context(|| format!("Creating {:?}", &file_path))?;
 
         for record in records.iter() {
-            writing.write(record.to_delimited()?.bytes())?;
+            writing.write_all(record.to_delimited()?.bytes())?;
         }
 
         Ok(())
@@ -85,94 +90,332 @@ impl FilesRecordSink {
         Ok(file_path)
     }
 
+    fn joined_path(&self, sync_id: &str, identity: &Identity) -> PathBuf {
+        self.device_path(&identity.device_id)
+            .join(format!("{}.fkpb", sync_id))
+    }
+
+    fn checkpoint_path(&self, identity: &Identity) -> PathBuf {
+        self.device_path(&identity.device_id)
+            .join(format!("{}.checkpoint.json", identity.generation_id))
+    }
+
+    fn chunk_files(&self, sync_path: &Path) -> Result<Vec<RecordsFile>> {
+        Ok(std::fs::read_dir(sync_path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            // Skips anything left half written by a crash.
+            .filter(|path| path.extension().map(|e| e == "fkpb").unwrap_or(false))
+            .map(|path| RecordsFile::new(&path))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .sorted_unstable_by_key(|r| r.head)
+            .collect())
+    }
+
+    /// Trims every chunk in the sync directory down to the records the
+    /// checkpoint says were received, dropping anything written after the
+    /// checkpoint was saved or left half written by a crash. Returns what's
+    /// actually on disk afterwards.
+    fn reconcile(&self, identity: &Identity, checkpoint: Checkpoint) -> Result<Checkpoint> {
+        let sync_path = self
+            .device_path(&identity.device_id)
+            .join(&checkpoint.sync_id);
+        if !sync_path.is_dir() {
+            return Ok(Checkpoint {
+                received: Vec::new(),
+                ..checkpoint
+            });
+        }
+
+        let expected = checkpoint.to_set();
+        let mut kept = RangeSetBlaze::new();
+
+        for file in self.chunk_files(&sync_path)? {
+            let bytes = std::fs::read(&file.path)?;
+            let mut reader = BytesReader::from_bytes(&bytes);
+            let mut number = file.head as u64;
+            let mut keeping = 0;
+
+            while !reader.is_eof() && expected.contains(number) {
+                if reader.read_bytes(&bytes).is_err() {
+                    break;
+                }
+                keeping = bytes.len() - reader.len();
+                number += 1;
+            }
+
+            if keeping == 0 {
+                info!("{} removing", file.path.display());
+                std::fs::remove_file(&file.path)?;
+                continue;
+            }
+
+            if keeping < bytes.len() {
+                info!(
+                    "{} truncating {} -> {}",
+                    file.path.display(),
+                    bytes.len(),
+                    keeping
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&file.path)?
+                    .set_len(keeping as u64)?;
+            }
+
+            kept.ranges_insert(file.head as u64..=number - 1);
+        }
+
+        Ok(Checkpoint::new(
+            &checkpoint.sync_id,
+            &checkpoint.generation_id,
+            checkpoint.head,
+            &kept,
+        ))
+    }
+
     #[allow(dead_code)]
     fn get_device_ids(&self) -> Result<Vec<DeviceId>> {
         let previous = self.previous.lock().expect("Lock error");
-        Ok(previous.keys().map(|d| d.clone()).collect())
+        Ok(previous.keys().cloned().collect())
     }
 
     fn join_files(
         &self,
-        sync_id: &String,
+        sync_id: &str,
         identity: &Identity,
         files: Vec<RecordsFile>,
-    ) -> Result<i64> {
-        assert_eq!(files.get(0).map(|f| f.head), Some(0));
-
-        let device_path = self.device_path(&identity.device_id);
-        let path = device_path.join(format!("{}.fkpb", sync_id));
-
-        let mut writing = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .with_context(|| format!("Creating {:?}", &path))?;
-
-        let mut written = 0;
-
-        for file in files.iter() {
-            let mut skipping = written - file.head;
-            debug!("{:?} Records={:?} Skipped={:?}", file, written, skipping);
-            assert!(skipping >= 0);
-
-            let mut reader = Reader::from_file(&file.path)?;
-            while let Some(record) = reader.read(|r, b| {
-                if r.is_eof() {
-                    Ok(None)
-                } else {
-                    Ok(Some(r.read_bytes(b)?))
+    ) -> Result<RangeInclusive<i64>> {
+        // Incremental syncs start after the last record we already have, so
+        // the first chunk decides where this file begins.
+        let head = files
+            .first()
+            .map(|f| f.head)
+            .ok_or_else(|| anyhow!("No records to flush"))?;
+
+        let path = self.joined_path(sync_id, identity);
+
+        let mut number = head;
+
+        write_atomically(&path, |writing| {
+            for file in files.iter() {
+                let mut skipping = number - file.head;
+                debug!("{:?} Number={:?} Skipped={:?}", file, number, skipping);
+                if skipping < 0 {
+                    return Err(anyhow!("Missing records {}..{}", number, file.head));
                 }
-            })? {
-                if skipping == 0 {
-                    let record = Record::Undelimited(record.to_vec());
-                    let record = record.to_delimited()?;
-                    writing.write(record.bytes())?;
-                    written += 1;
-                } else {
-                    skipping -= 1;
+
+                let mut reader = Reader::from_file(&file.path)?;
+                while let Some(record) = reader.read(|r, b| {
+                    if r.is_eof() {
+                        Ok(None)
+                    } else {
+                        Ok(Some(r.read_bytes(b)?))
+                    }
+                })? {
+                    if skipping == 0 {
+                        let record = Record::Undelimited(record.to_vec());
+                        let record = record.to_delimited()?;
+                        writing.write_all(record.bytes())?;
+                        number += 1;
+                    } else {
+                        skipping -= 1;
+                    }
                 }
             }
-        }
 
-        info!("{} Flushed {} records", path.display(), written);
+            Ok(())
+        })?;
+
+        info!("{} Flushed {} records", path.display(), number - head);
 
-        Ok(written)
+        Ok(head..=number - 1)
     }
 
     fn write_identity(
         &self,
         sync_id: &String,
         identity: &Identity,
-        total_records: i64,
+        blocks: RangeInclusive<i64>,
     ) -> Result<()> {
         let device_path = self.device_path(&identity.device_id);
         let path = device_path.join(format!("{}.fkpb.json", sync_id));
 
-        let writing = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .with_context(|| format!("Creating {:?}", &path))?;
-
         let mut headers = identity.to_headers_map();
         // Yes, this appears to be "last record number" instead of "total number
         // of records" based on the firmware.
         headers.insert(
             "Fk-Blocks".to_owned(),
-            format!("{},{}", 0, total_records - 1),
+            format!("{},{}", blocks.start(), blocks.end()),
         );
         headers.insert("Fk-Type".to_owned(), "data".to_owned());
 
         let fm = FileMeta { headers };
 
-        serde_json::to_writer(writing, &fm)?;
+        write_atomically(&path, |writing| Ok(serde_json::to_writer(writing, &fm)?))?;
 
         info!("{} Wrote", &path.display());
 
         Ok(())
     }
+
+    /// The checkpoint saved for a sync. They're named for the generation, so
+    /// we have to look inside to find the one we're after.
+    fn find_checkpoint(&self, device_id: &DeviceId, sync_id: &str) -> Result<Option<Checkpoint>> {
+        for entry in std::fs::read_dir(self.device_path(device_id))? {
+            let path = entry?.path();
+            let is_checkpoint = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.ends_with(".checkpoint.json"))
+                .unwrap_or(false);
+            if !is_checkpoint {
+                continue;
+            }
+
+            let file =
+                std::fs::File::open(&path).with_context(|| format!("Opening {:?}", &path))?;
+            let checkpoint: Checkpoint = serde_json::from_reader(file)?;
+            if checkpoint.sync_id == sync_id {
+                return Ok(Some(checkpoint));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Station name from any earlier sync of the same generation.
+    fn device_name(&self, device_id: &DeviceId, generation_id: &str) -> Option<String> {
+        std::fs::read_dir(self.device_path(device_id))
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.to_string_lossy().ends_with(".fkpb.json"))
+            .filter_map(|p| FileMeta::load_from_json_sync(&p).ok())
+            .filter(|fm| fm.headers.get("Fk-Generation").map(|g| g.as_str()) == Some(generation_id))
+            .find_map(|fm| fm.headers.get("Fk-DeviceName").cloned())
+    }
+
+    /// Joins the chunks of a sync whose flush never ran, trimming them to
+    /// what its checkpoint says was received first.
+    pub fn rejoin(&self, device_id: &DeviceId, sync_id: &str) -> Result<Flushed> {
+        let checkpoint = self
+            .find_checkpoint(device_id, sync_id)?
+            .ok_or_else(|| anyhow!("No checkpoint for {}", sync_id))?;
+
+        let identity = Identity {
+            device_id: device_id.clone(),
+            generation_id: checkpoint.generation_id.clone(),
+            name: self
+                .device_name(device_id, &checkpoint.generation_id)
+                .unwrap_or_default(),
+        };
+
+        let checkpoint = self.reconcile(&identity, checkpoint)?;
+        match checkpoint.received.len() {
+            0 => return Err(anyhow!("No records in {}", sync_id)),
+            1 => {}
+            _ => {
+                return Err(anyhow!(
+                    "Missing records in {}, received {:?}",
+                    sync_id,
+                    checkpoint.received
+                ))
+            }
+        }
+
+        self.flush(sync_id.to_owned(), identity)?
+            .ok_or_else(|| anyhow!("Nothing flushed for {}", sync_id))
+    }
+
+    /// Merges every data file of one station generation into
+    /// `<generation>.fkpb`. An earlier merge goes first, so its records win
+    /// any conflicts, then syncs in the order they happened.
+    pub fn merge(&self, device_id: &DeviceId, generation_id: &str) -> Result<Merged> {
+        let device_path = self.device_path(device_id);
+        let path = device_path.join(format!("{}.fkpb", generation_id));
+
+        let mut paths = std::fs::read_dir(&device_path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|p| p.is_file() && p.extension().map(|e| e == "fkpb").unwrap_or(false))
+            .filter(|p| *p != path)
+            .filter(|p| {
+                FileMeta::load_from_json_sync(&meta_path(p))
+                    .map(|fm| {
+                        fm.headers.get("Fk-Generation").map(|g| g.as_str()) == Some(generation_id)
+                            && fm.kind() != Some(StreamKind::Meta)
+                    })
+                    .unwrap_or(false)
+            })
+            .sorted_unstable()
+            .collect::<Vec<_>>();
+
+        if path.exists() {
+            paths.insert(0, path.clone());
+        }
+
+        merge_files(&paths, &path)
+    }
+
+    /// Rejoins orphaned sync directories and then truncates and rebuilds the
+    /// meta of every file under the base path. Syncs that can't be rejoined
+    /// are skipped and left alone for a later sync to resume.
+    pub fn repair(&self) -> Result<Vec<Repaired>> {
+        let mut repaired = Vec::new();
+        if !self.base_path.is_dir() {
+            return Ok(repaired);
+        }
+
+        for device in std::fs::read_dir(&self.base_path)? {
+            let device_path = device?.path();
+            let Some(device_id) = device_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .filter(|_| device_path.is_dir())
+                .map(|n| DeviceId(n.to_owned()))
+            else {
+                continue;
+            };
+
+            for entry in std::fs::read_dir(&device_path)? {
+                let sync_path = entry?.path();
+                let Some(sync_id) = sync_path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                let meta_path = device_path.join(format!("{}.fkpb.json", sync_id));
+                if !sync_path.is_dir() || meta_path.exists() {
+                    continue;
+                }
+
+                // Flushed, and only the meta went missing afterwards.
+                let joined_path = device_path.join(format!("{}.fkpb", sync_id));
+                if joined_path.exists() && self.find_checkpoint(&device_id, sync_id)?.is_none() {
+                    continue;
+                }
+
+                match self.rejoin(&device_id, sync_id) {
+                    Ok(flushed) => repaired.push(Repaired::Rejoined(flushed)),
+                    Err(e) => {
+                        warn!("{} {}", sync_path.display(), e);
+                        repaired.push(Repaired::Skipped(sync_path.clone(), e.to_string()));
+                    }
+                }
+            }
+
+            for entry in std::fs::read_dir(&device_path)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().map(|e| e == "fkpb").unwrap_or(false) {
+                    repaired.extend(repair_file(&path)?);
+                }
+            }
+        }
+
+        Ok(repaired)
+    }
 }
 
 #[derive(Debug)]
@@ -182,19 +425,19 @@ struct RecordsFile {
 }
 
 impl RecordsFile {
-    fn new(path: &PathBuf) -> Result<Self> {
+    fn new(path: &Path) -> Result<Self> {
         let name = path.file_name().expect("No file name on path");
         let head = name
             .to_os_string()
             .into_string()
             .map_err(|_| anyhow!("Quirky file name"))?
-            .split(".")
+            .split('.')
             .next()
             .map(|v| Ok(v.parse()?))
             .unwrap_or(Err(anyhow!("Malformed record file name")))?;
 
         Ok(Self {
-            path: path.clone(),
+            path: path.to_owned(),
             head,
         })
     }
@@ -207,9 +450,10 @@ impl RecordsSink for FilesRecordSink {
             .ok_or(anyhow!("No range on received records"))?;
 
         let mut previous = self.previous.lock().expect("Lock error");
-        let consecutive = previous
-            .get_mut(&records.device_id)
-            .map(|p| (*range.start() == *p.range.end() + 1, p));
+        let consecutive = previous.get_mut(&records.device_id).map(|p| {
+            let consecutive = p.sync_id == records.sync_id && *range.start() == *p.range.end() + 1;
+            (consecutive, p)
+        });
 
         match consecutive {
             Some((true, previous)) => {
@@ -223,6 +467,7 @@ impl RecordsSink for FilesRecordSink {
                 previous.insert(
                     records.device_id.clone(),
                     Previous {
+                        sync_id: records.sync_id.clone(),
                         path: file_path,
                         range,
                     },
@@ -233,25 +478,115 @@ impl RecordsSink for FilesRecordSink {
         Ok(())
     }
 
-    fn flush(&self, sync_id: String, identity: Identity) -> Result<()> {
+    fn flush(&self, sync_id: String, identity: Identity) -> Result<Option<Flushed>> {
         let device_path = self.device_path(&identity.device_id);
         let sync_path = device_path.join(&sync_id);
 
         info!("flushing {:?}", &sync_path);
 
-        let files: Vec<_> = std::fs::read_dir(sync_path)?
-            .map(|entry| Ok(RecordsFile::new(&entry?.path())?))
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .sorted_unstable_by_key(|r| r.head)
-            .collect();
+        let files = self.chunk_files(&sync_path)?;
 
-        let total_records = self.join_files(&sync_id, &identity, files)?;
+        let blocks = self.join_files(&sync_id, &identity, files)?;
 
-        self.write_identity(&sync_id, &identity, total_records)?;
+        self.write_identity(&sync_id, &identity, blocks.clone())?;
+
+        let checkpoint_path = self.checkpoint_path(&identity);
+        if checkpoint_path.exists() {
+            std::fs::remove_file(&checkpoint_path)
+                .with_context(|| format!("Removing {:?}", &checkpoint_path))?;
+        }
+
+        let path = self.joined_path(&sync_id, &identity);
+        let size = std::fs::metadata(&path)?.len();
+
+        Ok(Some(Flushed {
+            path,
+            size,
+            generation_id: identity.generation_id,
+            records: *blocks.start() as u64..=*blocks.end() as u64,
+        }))
+    }
+
+    fn checkpoint(&self, identity: &Identity, checkpoint: &Checkpoint) -> Result<()> {
+        let path = self.checkpoint_path(identity);
+        let device_path = self.device_path(&identity.device_id);
+        std::fs::create_dir_all(&device_path)
+            .with_context(|| format!("Creating device path {:?}", &device_path))?;
+
+        write_atomically(&path, |writing| {
+            Ok(serde_json::to_writer(writing, checkpoint)?)
+        })?;
+
+        debug!("{} checkpointed", path.display());
 
         Ok(())
     }
+
+    fn last_synced(&self, identity: &Identity) -> Result<Option<u64>> {
+        let device_path = self.device_path(&identity.device_id);
+        if !device_path.is_dir() {
+            return Ok(None);
+        }
+
+        let mut last = None;
+
+        for entry in std::fs::read_dir(&device_path)? {
+            let path = entry?.path();
+            let is_meta = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.ends_with(".fkpb.json"))
+                .unwrap_or(false);
+            if !is_meta {
+                continue;
+            }
+
+            let file =
+                std::fs::File::open(&path).with_context(|| format!("Opening {:?}", &path))?;
+            let fm: FileMeta = serde_json::from_reader(file)?;
+            if fm.headers.get("Fk-Generation") != Some(&identity.generation_id) {
+                continue;
+            }
+
+            match fm.blocks() {
+                Some(blocks) => last = last.max(Some(*blocks.end())),
+                None => warn!("{} malformed Fk-Blocks", path.display()),
+            }
+        }
+
+        Ok(last)
+    }
+
+    fn resume(&self, identity: &Identity) -> Result<Option<Checkpoint>> {
+        let path = self.checkpoint_path(identity);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = std::fs::File::open(&path).with_context(|| format!("Opening {:?}", &path))?;
+        let checkpoint: Checkpoint = match serde_json::from_reader(file) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                // Kept for a look later, but out of the way of the next sync.
+                let unreadable = path.with_extension("json.unreadable");
+                warn!("{} unreadable, moving aside: {}", path.display(), e);
+                std::fs::rename(&path, &unreadable)
+                    .with_context(|| format!("Renaming {:?}", &path))?;
+                return Ok(None);
+            }
+        };
+        if checkpoint.generation_id != identity.generation_id {
+            warn!("{} generation mismatch, ignoring", path.display());
+            return Ok(None);
+        }
+
+        // Anything we remember writing for this device belongs to a sync
+        // that's being replaced.
+        let mut previous = self.previous.lock().expect("Lock error");
+        previous.remove(&identity.device_id);
+
+        Ok(Some(self.reconcile(identity, checkpoint)?))
+    }
 }
 
 #[cfg(test)]
@@ -324,6 +659,239 @@ mod tests {
         Ok(())
     }
 
+    fn test_identity() -> Identity {
+        Identity {
+            device_id: DeviceId("device".to_owned()),
+            generation_id: "generation".to_owned(),
+            name: "Test Station".to_owned(),
+        }
+    }
+
+    fn count_records(path: &Path) -> Result<usize> {
+        let bytes = std::fs::read(path)?;
+        let mut reader = BytesReader::from_bytes(&bytes);
+        let mut count = 0;
+        while !reader.is_eof() {
+            reader.read_bytes(&bytes)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    #[test]
+    pub fn test_resume_without_checkpoint() -> Result<()> {
+        let (sink, _dir) = new_sink()?;
+
+        assert_eq!(sink.resume(&test_identity())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_resume_moves_unreadable_checkpoint_aside() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+        let device_path = dir.path().join("device");
+        std::fs::create_dir_all(&device_path)?;
+        std::fs::write(device_path.join("generation.checkpoint.json"), b"{")?;
+
+        assert_eq!(sink.resume(&test_identity())?, None);
+        assert!(!device_path.join("generation.checkpoint.json").exists());
+        assert!(device_path
+            .join("generation.checkpoint.json.unreadable")
+            .exists());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_resume_trims_records_after_checkpoint() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+        let identity = test_identity();
+
+        sink.write(&builder().records(1000).build())?;
+        sink.checkpoint(
+            &identity,
+            &Checkpoint::new(
+                "sync_id",
+                "generation",
+                0,
+                &RangeSetBlaze::from_iter([0..=499]),
+            ),
+        )?;
+
+        let restarted = FilesRecordSink::new(dir.path());
+        let resumed = restarted.resume(&identity)?.expect("No checkpoint");
+        assert_eq!(resumed.sync_id, "sync_id");
+        assert_eq!(resumed.received, vec![0..=499]);
+
+        restarted.write(&builder().first(500).records(500).build())?;
+        restarted.flush("sync_id".to_owned(), identity.clone())?;
+
+        let device_path = dir.path().join("device");
+        assert_eq!(count_records(&device_path.join("sync_id.fkpb"))?, 1000);
+        assert!(!device_path.join("generation.checkpoint.json").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_flushes_delta_after_last_synced() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+        let identity = test_identity();
+
+        assert_eq!(sink.last_synced(&identity)?, None);
+
+        sink.write(&builder().records(1000).build())?;
+        sink.flush("sync_id".to_owned(), identity.clone())?;
+        assert_eq!(sink.last_synced(&identity)?, Some(999));
+
+        let delta = ReceivedRecordsBuilder {
+            sync_id: "delta_id".to_owned(),
+            ..builder()
+        };
+        sink.write(&delta.first(1000).records(500).build())?;
+        let flushed = sink
+            .flush("delta_id".to_owned(), identity.clone())?
+            .expect("No joined file");
+        assert_eq!(sink.last_synced(&identity)?, Some(1499));
+
+        let device_path = dir.path().join("device");
+        assert_eq!(flushed.path, device_path.join("delta_id.fkpb"));
+        assert_eq!(flushed.size, std::fs::metadata(&flushed.path)?.len());
+        assert_eq!(flushed.records, 1000..=1499);
+        assert_eq!(count_records(&device_path.join("delta_id.fkpb"))?, 500);
+
+        let file = std::fs::File::open(device_path.join("delta_id.fkpb.json"))?;
+        let fm: FileMeta = serde_json::from_reader(file)?;
+        assert_eq!(fm.headers.get("Fk-Blocks"), Some(&"1000,1499".to_owned()));
+
+        let other = Identity {
+            generation_id: "other".to_owned(),
+            ..identity
+        };
+        assert_eq!(sink.last_synced(&other)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_repair_rejoins_orphaned_sync() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+        let identity = test_identity();
+
+        sink.write(&builder().records(1000).build())?;
+        sink.checkpoint(
+            &identity,
+            &Checkpoint::new(
+                "sync_id",
+                "generation",
+                0,
+                &RangeSetBlaze::from_iter([0..=999]),
+            ),
+        )?;
+
+        // A crash part way through writing the next batch.
+        let device_path = dir.path().join("device");
+        let chunk = device_path.join("sync_id").join("0.fkpb");
+        let mut writing = OpenOptions::new().append(true).open(&chunk)?;
+        writing.write_all(&[0x80, 0x02, 0x00, 0x00])?;
+
+        let restarted = FilesRecordSink::new(dir.path());
+        let repaired = restarted.repair()?;
+        assert_eq!(repaired.len(), 1);
+        let Repaired::Rejoined(flushed) = &repaired[0] else {
+            panic!("Expected rejoin, got {:?}", repaired);
+        };
+        assert_eq!(flushed.records, 0..=999);
+        assert_eq!(flushed.generation_id, "generation");
+        assert_eq!(count_records(&device_path.join("sync_id.fkpb"))?, 1000);
+        assert!(device_path.join("sync_id.fkpb.json").exists());
+        assert!(!device_path.join("generation.checkpoint.json").exists());
+
+        assert!(restarted.repair()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_repair_skips_sync_with_missing_records() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+        let identity = test_identity();
+
+        sink.write(&builder().records(500).build())?;
+        sink.write(&builder().first(600).records(400).build())?;
+        sink.checkpoint(
+            &identity,
+            &Checkpoint::new(
+                "sync_id",
+                "generation",
+                0,
+                &RangeSetBlaze::from_iter([0..=499, 600..=999]),
+            ),
+        )?;
+
+        let repaired = sink.repair()?;
+        assert!(matches!(&repaired[..], [Repaired::Skipped(..)]));
+
+        let device_path = dir.path().join("device");
+        assert!(!device_path.join("sync_id.fkpb").exists());
+        assert!(device_path.join("generation.checkpoint.json").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_repair_rebuilds_missing_meta() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+
+        sink.write(&builder().records(100).build())?;
+        sink.flush("sync_id".to_owned(), test_identity())?;
+
+        let device_path = dir.path().join("device");
+        std::fs::remove_file(device_path.join("sync_id.fkpb.json"))?;
+
+        let repaired = sink.repair()?;
+        assert!(matches!(&repaired[..], [Repaired::RebuiltMeta(..)]));
+
+        let fm = FileMeta::load_from_json_sync(&device_path.join("sync_id.fkpb.json"))?;
+        assert_eq!(fm.blocks(), Some(0..=99));
+        assert_eq!(fm.headers.get("Fk-DeviceId"), Some(&"device".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_merges_generation_after_resync() -> Result<()> {
+        let (sink, dir) = new_sink()?;
+        let identity = test_identity();
+
+        sink.write(&builder().records(1000).build())?;
+        sink.flush("sync_id".to_owned(), identity.clone())?;
+
+        let resync = ReceivedRecordsBuilder {
+            sync_id: "resync_id".to_owned(),
+            ..builder()
+        };
+        sink.write(&resync.records(1500).build())?;
+        sink.flush("resync_id".to_owned(), identity.clone())?;
+
+        let merged = sink.merge(&identity.device_id, "generation")?;
+        let device_path = dir.path().join("device");
+        assert_eq!(merged.flushed.path, device_path.join("generation.fkpb"));
+        assert_eq!(merged.flushed.records, 0..=1499);
+        assert_eq!(merged.sources.len(), 2);
+        assert_eq!(merged.duplicates, 1000);
+        assert_eq!(count_records(&merged.flushed.path)?, 1500);
+
+        // Merging again starts from the merged file.
+        let merged = sink.merge(&identity.device_id, "generation")?;
+        assert_eq!(merged.sources[0], device_path.join("generation.fkpb"));
+        assert_eq!(merged.duplicates, 2500);
+        assert_eq!(sink.last_synced(&identity)?, Some(1499));
+
+        Ok(())
+    }
+
     fn builder() -> ReceivedRecordsBuilder {
         ReceivedRecordsBuilder::new()
     }
@@ -367,7 +935,6 @@ mod tests {
                 sync_id: self.sync_id,
                 device_id: self.device_id,
                 records: (0..number)
-                    .into_iter()
                     .map(|n| NumberedRecord {
                         number: (n + self.number) as u64,
                         record: Record::new_all_zeros(256)
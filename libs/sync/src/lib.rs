@@ -1,9 +1,22 @@
+mod checkpoint;
 mod files;
+mod merge;
 mod progress;
 mod proto;
+mod repair;
 mod server;
 mod transport;
 
+pub use checkpoint::Checkpoint;
 pub use files::FilesRecordSink;
-pub use server::{DevNullSink, RecordsSink, Server, ServerEvent};
-pub use transport::{Transport, TransportMessage, UdpTransport};
+pub use merge::{merge_files, Conflict, MergeError, Merged};
+pub use proto::{
+    pack_records, Fragmenting, Identity, Message, MessageCodec, Record, RecordRange,
+    MAXIMUM_PACKET_SIZE,
+};
+pub use repair::{rebuild_file_meta, repair_file, truncate_incomplete, Repaired};
+pub use server::{DevNullSink, Flushed, RecordsSink, Server, ServerEvent, SyncFailure};
+pub use transport::{
+    NetworkConditions, SimulatedStation, SimulatedTransport, Transport, TransportMessage,
+    UdpTransport,
+};
@@ -0,0 +1,370 @@
+use anyhow::{Context, Result};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::*;
+
+use crate::{repair::write_atomically, Flushed};
+use protos::{meta_path, FileMeta, ReadError, RecordsReader};
+
+#[derive(Error, Debug)]
+pub enum MergeError {
+    #[error("No files to merge")]
+    NoFiles,
+    #[error("{0:?} has no meta")]
+    MissingMeta(PathBuf),
+    #[error("Generations differ, {0} and {1}")]
+    GenerationMismatch(String, String),
+    #[error("Missing records {0:?}")]
+    MissingRecords(Vec<RangeInclusive<u64>>),
+}
+
+/// The same record number with different bytes in two files. The record
+/// from the file given first is the one kept.
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub number: u64,
+    pub kept: PathBuf,
+    pub discarded: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct Merged {
+    pub flushed: Flushed,
+    pub sources: Vec<PathBuf>,
+    /// Records found in more than one file with the same bytes.
+    pub duplicates: u64,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A file being merged, read a record at a time.
+struct Source {
+    path: PathBuf,
+    meta: FileMeta,
+    records: RecordsReader<BufReader<File>>,
+    /// The same file read alongside `records`, for each record's bytes.
+    raw: BufReader<File>,
+}
+
+impl Source {
+    fn open(path: &Path) -> Result<Self> {
+        let meta_path = meta_path(path);
+        if !meta_path.exists() {
+            return Err(MergeError::MissingMeta(path.to_owned()).into());
+        }
+
+        let meta = FileMeta::load_from_json_sync(&meta_path)?;
+        let first = meta.blocks().map(|b| *b.start()).unwrap_or_default();
+        let open = || -> Result<BufReader<File>> {
+            Ok(BufReader::new(
+                File::open(path).with_context(|| format!("Reading {:?}", path))?,
+            ))
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            meta,
+            records: RecordsReader::new(open()?).starting_at(first),
+            raw: open()?,
+        })
+    }
+
+    fn generation_id(&self) -> &str {
+        self.meta
+            .headers
+            .get("Fk-Generation")
+            .map(|g| g.as_str())
+            .unwrap_or_default()
+    }
+
+    /// The next record's number and its delimited bytes.
+    fn next(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+        let read = match self.records.next() {
+            None => return Ok(None),
+            Some(Ok(read)) => read,
+            Some(Err(ReadError::Io(e))) => return Err(e.into()),
+            Some(Err(e)) => {
+                warn!("{} {}, ignoring the rest", self.path.display(), e);
+                return Ok(None);
+            }
+        };
+
+        let mut bytes = vec![0u8; (read.end() - read.offset) as usize];
+        self.raw.read_exact(&mut bytes)?;
+
+        Ok(Some((read.number, bytes)))
+    }
+}
+
+/// Combines files from one station generation into a single file ordered by
+/// record number, keeping one copy of each record. Sources are left alone.
+/// Each source is already in record order, so they're merged a record at a
+/// time rather than read into memory.
+pub fn merge_files(paths: &[PathBuf], path: &Path) -> Result<Merged> {
+    let mut sources = paths
+        .iter()
+        .map(|p| Source::open(p))
+        .collect::<Result<Vec<_>>>()?;
+
+    let first = sources.first().ok_or(MergeError::NoFiles)?;
+    if let Some(other) = sources
+        .iter()
+        .find(|s| s.generation_id() != first.generation_id())
+    {
+        return Err(MergeError::GenerationMismatch(
+            first.generation_id().to_owned(),
+            other.generation_id().to_owned(),
+        )
+        .into());
+    }
+
+    let generation_id = first.generation_id().to_owned();
+    let mut headers = first.meta.headers.clone();
+
+    // Ordered by number and then source, so the first file given wins.
+    let mut pending = BinaryHeap::new();
+    for (index, source) in sources.iter_mut().enumerate() {
+        if let Some((number, bytes)) = source.next()? {
+            pending.push(Reverse((number, index, bytes)));
+        }
+    }
+
+    let mut range: Option<RangeInclusive<u64>> = None;
+    let mut missing = Vec::new();
+    let mut written = 0;
+    let mut duplicates = 0;
+    let mut conflicts = Vec::new();
+
+    write_atomically(path, |file| {
+        let mut writing = BufWriter::new(file);
+        let mut kept: Option<(u64, usize, Vec<u8>)> = None;
+
+        while let Some(Reverse((number, index, bytes))) = pending.pop() {
+            if let Some((next, bytes)) = sources[index].next()? {
+                pending.push(Reverse((next, index, bytes)));
+            }
+
+            match &kept {
+                Some((kept_number, kept_index, kept_bytes)) if *kept_number == number => {
+                    if *kept_bytes == bytes {
+                        duplicates += 1;
+                    } else {
+                        conflicts.push(Conflict {
+                            number,
+                            kept: sources[*kept_index].path.clone(),
+                            discarded: sources[index].path.clone(),
+                        });
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            // Records are numbered by position, so the merged file can't
+            // have holes.
+            range = match range.take() {
+                Some(range) => {
+                    if number > *range.end() + 1 {
+                        missing.push(*range.end() + 1..=number - 1);
+                    }
+                    Some(*range.start()..=number)
+                }
+                None => Some(number..=number),
+            };
+
+            writing.write_all(&bytes)?;
+            written += 1;
+            kept = Some((number, index, bytes));
+        }
+
+        if range.is_none() {
+            return Err(MergeError::NoFiles.into());
+        }
+        if !missing.is_empty() {
+            return Err(MergeError::MissingRecords(missing.clone()).into());
+        }
+
+        writing.flush()?;
+
+        Ok(())
+    })?;
+
+    let records = range.ok_or(MergeError::NoFiles)?;
+
+    headers.insert(
+        "Fk-Blocks".to_owned(),
+        format!("{},{}", records.start(), records.end()),
+    );
+    headers.insert("Fk-Merged".to_owned(), sources.len().to_string());
+    let fm = FileMeta { headers };
+    write_atomically(&meta_path(path), |writing| {
+        Ok(serde_json::to_writer(writing, &fm)?)
+    })?;
+
+    info!(
+        "{} merged {} records from {} files, {} duplicates, {} conflicts",
+        path.display(),
+        written,
+        sources.len(),
+        duplicates,
+        conflicts.len()
+    );
+
+    Ok(Merged {
+        flushed: Flushed {
+            path: path.to_owned(),
+            size: std::fs::metadata(path)?.len(),
+            generation_id,
+            records,
+        },
+        sources: paths.to_vec(),
+        duplicates,
+        conflicts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use protos::data::{DataRecord, Readings};
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn record(reading: u64, time: i64) -> Vec<u8> {
+        DataRecord {
+            readings: Some(Readings {
+                reading,
+                time,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .encode_length_delimited_to_vec()
+    }
+
+    fn write_file(
+        dir: &Path,
+        name: &str,
+        generation: &str,
+        records: RangeInclusive<u64>,
+        time: i64,
+    ) -> Result<PathBuf> {
+        let path = dir.join(name);
+        let bytes: Vec<u8> = records
+            .clone()
+            .flat_map(|n| record(n, time + n as i64))
+            .collect();
+        std::fs::write(&path, bytes)?;
+        std::fs::write(
+            meta_path(&path),
+            format!(
+                r#"{{"headers":{{"Fk-Generation":"{}","Fk-Blocks":"{},{}","Fk-Type":"data"}}}}"#,
+                generation,
+                records.start(),
+                records.end()
+            ),
+        )?;
+        Ok(path)
+    }
+
+    fn readings(path: &Path) -> Result<Vec<u64>> {
+        RecordsReader::open(path)?
+            .map(|read| {
+                let read = read?;
+                assert_eq!(read.record?.readings.map(|r| r.reading), Some(read.number));
+                Ok(read.number)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merges_duplicated_syncs() -> Result<()> {
+        let dir = TempDir::new("fk-tests-merge")?;
+        let first = write_file(dir.path(), "first.fkpb", "aabb", 0..=99, 1000)?;
+        let full = write_file(dir.path(), "full.fkpb", "aabb", 0..=149, 1000)?;
+        let delta = write_file(dir.path(), "delta.fkpb", "aabb", 150..=199, 1000)?;
+
+        let path = dir.path().join("merged.fkpb");
+        let merged = merge_files(&[first, full, delta], &path)?;
+
+        assert_eq!(merged.flushed.records, 0..=199);
+        assert_eq!(merged.flushed.generation_id, "aabb");
+        assert_eq!(merged.duplicates, 100);
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(readings(&path)?, (0..=199).collect::<Vec<_>>());
+
+        let fm = FileMeta::load_from_json_sync(&meta_path(&path))?;
+        assert_eq!(fm.blocks(), Some(0..=199));
+        assert_eq!(fm.headers.get("Fk-Type").map(|v| v.as_str()), Some("data"));
+        assert!(fm.is_merged());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reports_conflicts() -> Result<()> {
+        let dir = TempDir::new("fk-tests-merge")?;
+        let kept = write_file(dir.path(), "kept.fkpb", "aabb", 0..=9, 1000)?;
+        let other = write_file(dir.path(), "other.fkpb", "aabb", 8..=11, 5000)?;
+
+        let path = dir.path().join("merged.fkpb");
+        let merged = merge_files(&[kept.clone(), other.clone()], &path)?;
+
+        assert_eq!(merged.flushed.records, 0..=11);
+        assert_eq!(
+            merged.conflicts,
+            vec![
+                Conflict {
+                    number: 8,
+                    kept: kept.clone(),
+                    discarded: other.clone(),
+                },
+                Conflict {
+                    number: 9,
+                    kept,
+                    discarded: other,
+                },
+            ]
+        );
+
+        let times = RecordsReader::open(&path)?
+            .map(|read| Ok(read?.record?.readings.map(|r| r.time)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(times[9], Some(1009));
+        assert_eq!(times[10], Some(5010));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_gaps_and_mixed_generations() -> Result<()> {
+        let dir = TempDir::new("fk-tests-merge")?;
+        let head = write_file(dir.path(), "head.fkpb", "aabb", 0..=9, 1000)?;
+        let tail = write_file(dir.path(), "tail.fkpb", "aabb", 20..=29, 1000)?;
+        let other = write_file(dir.path(), "other.fkpb", "ccdd", 10..=19, 1000)?;
+
+        let path = dir.path().join("merged.fkpb");
+        let err = merge_files(&[head.clone(), tail], &path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MergeError>(),
+            Some(MergeError::MissingRecords(missing)) if *missing == vec![10..=19]
+        ));
+
+        let err = merge_files(&[head, other], &path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MergeError>(),
+            Some(MergeError::GenerationMismatch(..))
+        ));
+
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
-use anyhow::{anyhow, Result};
-use discovery::DeviceId;
+use anyhow::Result;
+use discovery::{DeviceId, ProtocolError};
 use quick_protobuf::reader::BytesReader;
 use quick_protobuf::writer::Writer;
 use range_set_blaze::prelude::*;
 use std::collections::HashMap;
 use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::time::Instant;
 use tracing::*;
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -113,7 +115,7 @@ impl Record {
 
     #[cfg(test)]
     pub fn new_all_zeros(len: usize) -> Self {
-        let zeros: Vec<u8> = std::iter::repeat(0 as u8).take(len).collect();
+        let zeros: Vec<u8> = vec![0; len];
         Self::Undelimited(zeros)
     }
 
@@ -123,7 +125,7 @@ impl Record {
                 let mut writing = Vec::new();
                 {
                     let mut writer = Writer::new(&mut writing);
-                    writer.write_bytes(&bytes)?;
+                    writer.write_bytes(bytes)?;
                 }
                 Ok(Self::Bytes(writing))
             }
@@ -167,7 +169,7 @@ impl Identity {
         let generation_id = hex::encode(wire_identity.generation_id);
         Ok(Identity {
             device_id,
-            generation_id: generation_id,
+            generation_id,
             name: wire_identity.name,
         })
     }
@@ -185,7 +187,7 @@ impl Identity {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Message {
     Query,
     Statistics {
@@ -220,7 +222,7 @@ impl Message {
                 records,
             } => Ok(Some(
                 records
-                    .into_iter()
+                    .iter()
                     .enumerate()
                     .map(|(i, r)| NumberedRecord::new(i as u64 + head, r.clone()))
                     .collect(),
@@ -229,7 +231,7 @@ impl Message {
         }
     }
 
-    pub(crate) fn write(&self, bytes: &mut Vec<u8>) -> Result<()> {
+    pub fn write(&self, bytes: &mut Vec<u8>) -> Result<()> {
         let mut writer = Writer::new(bytes);
 
         match self {
@@ -295,7 +297,10 @@ impl Message {
         }
     }
 
-    fn read_header(reader: &mut BytesReader, bytes: &[u8]) -> Result<(Self, Option<Vec<u8>>)> {
+    fn read_header(
+        reader: &mut BytesReader,
+        bytes: &[u8],
+    ) -> Result<(Self, Option<Vec<u8>>), ProtocolError> {
         let kind = reader.read_fixed32(bytes)?;
 
         match kind {
@@ -303,7 +308,8 @@ impl Message {
             FK_UDP_PROTOCOL_KIND_STATISTICS => {
                 let nrecords = reader.read_fixed32(bytes)? as u64;
                 let identity_bytes = reader.read_bytes(bytes)?;
-                let identity = Identity::from_bytes(identity_bytes)?;
+                let identity = Identity::from_bytes(identity_bytes)
+                    .map_err(|_| ProtocolError::MalformedIdentity)?;
 
                 Ok((Self::Statistics { nrecords, identity }, None))
             }
@@ -311,7 +317,12 @@ impl Message {
                 let head = reader.read_fixed32(bytes)? as u64;
                 let nrecords = reader.read_fixed32(bytes)? as u64;
 
-                Ok((Self::Require(RecordRange::new(head, nrecords - 1)), None))
+                let tail = nrecords
+                    .checked_sub(1)
+                    .and_then(|n| head.checked_add(n))
+                    .ok_or(ProtocolError::InvalidRange(head, nrecords))?;
+
+                Ok((Self::Require(RecordRange::new(head, tail)), None))
             }
             FK_UDP_PROTOCOL_KIND_RECORDS => {
                 let head = reader.read_fixed32(bytes)? as u64;
@@ -336,19 +347,20 @@ impl Message {
 
                 Ok((Self::Batch { flags }, None))
             }
-            _ => todo!(),
+            kind => Err(ProtocolError::UnknownKind(kind)),
         }
     }
 }
 
 #[derive(Default)]
-pub(crate) struct MessageCodec {
+pub struct MessageCodec {
     partial: Option<(u64, u32)>,
     buffered: Vec<u8>,
+    partial_at: Option<Instant>,
 }
 
 impl MessageCodec {
-    pub(crate) fn try_read(&mut self, bytes: &[u8]) -> Result<Option<Message>> {
+    pub fn try_read(&mut self, bytes: &[u8]) -> Result<Option<Message>, ProtocolError> {
         let mut reader = BytesReader::from_bytes(bytes);
 
         let (header, payload) = Message::read_header(&mut reader, bytes)?;
@@ -362,28 +374,25 @@ impl MessageCodec {
                     records: _,
                 } => {
                     if flags > 0 {
-                        self.buffered.extend(payload);
-
-                        match self.partial.clone() {
-                            Some(partial) => {
-                                if head != partial.0 {
-                                    warn!(
-                                        "Partial head mismatch ({} != {}) dropping",
-                                        head, partial.0
-                                    );
-                                    self.reset();
-                                }
-
-                                if sequence != partial.1 + 1 {
-                                    warn!(
-                                        "Partial sequence mismatch ({} != {}) dropping",
-                                        sequence, partial.1
-                                    );
-                                    self.reset();
-                                }
+                        if let Some(partial) = self.partial {
+                            if head != partial.0 {
+                                warn!("Partial head mismatch ({} != {}) dropping", head, partial.0);
+                                self.reset();
+                            } else if sequence != partial.1 + 1 {
+                                warn!(
+                                    "Partial sequence mismatch ({} != {}) dropping",
+                                    sequence, partial.1
+                                );
+                                self.reset();
+                            }
+                        }
+
+                        match self.partial {
+                            Some(_) => {
+                                self.buffered.extend(payload);
 
                                 let mut reader = BytesReader::from_bytes(&self.buffered);
-                                let records = self.read_raw_records(&mut reader, &self.buffered)?;
+                                let records = self.read_raw_records(&mut reader, &self.buffered);
 
                                 match &records {
                                     Some(_) => {
@@ -392,6 +401,8 @@ impl MessageCodec {
                                     }
                                     None => {
                                         info!("Partial record #{}: Waiting for remainder", head);
+                                        self.partial = Some((head, sequence));
+                                        self.partial_at = Some(Instant::now());
                                     }
                                 };
 
@@ -402,51 +413,155 @@ impl MessageCodec {
                                     records,
                                 }))
                             }
-                            None => {
+                            None if sequence == 0 => {
                                 self.partial = Some((head, sequence));
+                                self.partial_at = Some(Instant::now());
+                                self.buffered.extend(payload);
 
                                 // No need to try parsing as this is the first partial packet.
                                 Ok(None)
                             }
+                            None => {
+                                warn!("Partial record #{}: Missing beginning, dropping", head);
+
+                                Ok(None)
+                            }
                         }
                     } else {
-                        let records = self.read_raw_records(&mut reader, bytes)?;
+                        let records = self.read_raw_records(&mut reader, bytes);
 
                         Ok(Some(Message::Records {
                             head,
                             flags,
                             sequence,
-                            records: records.ok_or(anyhow!("Error parsing records"))?,
+                            records: records.ok_or(ProtocolError::MalformedRecords)?,
                         }))
                     }
                 }
-                _ => todo!(),
+                _ => Err(ProtocolError::UnexpectedPayload),
             },
             None => Ok(Some(header)),
         }
     }
 
+    /// True when waiting on the remainder of a record whose last fragment
+    /// arrived more than `age` ago.
+    pub fn is_stale(&self, age: Duration) -> bool {
+        self.partial_at
+            .map(|at| Instant::now() - at > age)
+            .unwrap_or(false)
+    }
+
+    pub fn has_partial(&self) -> bool {
+        self.partial.is_some()
+    }
+
     fn reset(&mut self) {
         self.partial = None;
+        self.partial_at = None;
         self.buffered.clear();
     }
 
-    fn read_raw_records(
-        &self,
-        reader: &mut BytesReader,
-        bytes: &[u8],
-    ) -> Result<Option<Vec<Record>>> {
+    /// Returns None unless the bytes hold one or more complete records.
+    fn read_raw_records(&self, reader: &mut BytesReader, bytes: &[u8]) -> Option<Vec<Record>> {
         let mut records = vec![];
 
         while !reader.is_eof() {
             match reader.read_bytes(bytes) {
                 Ok(record) => records.push(Record::Undelimited(record.into())),
-                Err(_) => return Ok(None),
+                Err(_) => return None,
             }
         }
 
-        Ok(Some(records))
+        if records.is_empty() {
+            None
+        } else {
+            Some(records)
+        }
+    }
+}
+
+/// Largest payload the firmware will put in a single records packet, records
+/// bigger than this are split across several packets.
+pub const MAXIMUM_PACKET_SIZE: usize = 1024;
+
+/// Decides how many packets a record that would otherwise fit in one is
+/// split across, given its delimited bytes.
+pub type Fragmenting<'a> = &'a mut dyn FnMut(&[u8]) -> usize;
+
+/// Builds the packets a station sends in response to a Require, the way the
+/// firmware does. Records are packed together until a packet is full, those
+/// too big for one are split across several, and a Batch follows.
+pub fn pack_records(
+    records: &[Vec<u8>],
+    range: &RecordRange,
+    mut fragmenting: Option<Fragmenting>,
+) -> Vec<Message> {
+    fn flush(messages: &mut Vec<Message>, head: u64, packing: &mut Vec<Record>) {
+        if !packing.is_empty() {
+            messages.push(Message::Records {
+                head,
+                flags: 0,
+                sequence: 0,
+                records: std::mem::take(packing),
+            });
+        }
+    }
+
+    let available = records.len() as u64;
+    let mut messages = Vec::new();
+    let mut packing = Vec::new();
+    let mut packing_head = *range.0.start();
+    let mut packing_size = 0;
+
+    for number in range.0.clone().take_while(|n| *n < available) {
+        let record = Record::Undelimited(records[number as usize].clone());
+        let delimited = record.to_delimited().expect("Delimiting record");
+        let bytes = delimited.bytes();
+        let size = bytes.len();
+
+        let chunk = if size > MAXIMUM_PACKET_SIZE {
+            Some(MAXIMUM_PACKET_SIZE)
+        } else {
+            fragmenting
+                .as_mut()
+                .map(|fragmenting| fragmenting(bytes).clamp(1, size))
+                .filter(|pieces| *pieces > 1)
+                .map(|pieces| size.div_ceil(pieces))
+        };
+
+        if let Some(chunk) = chunk {
+            flush(&mut messages, packing_head, &mut packing);
+
+            for (sequence, fragment) in bytes.chunks(chunk).enumerate() {
+                messages.push(Message::Records {
+                    head: number,
+                    flags: 1,
+                    sequence: sequence as u32,
+                    records: vec![Record::Bytes(fragment.to_vec())],
+                });
+            }
+
+            packing_head = number + 1;
+            packing_size = 0;
+            continue;
+        }
+
+        if packing_size + size > MAXIMUM_PACKET_SIZE {
+            flush(&mut messages, packing_head, &mut packing);
+            packing_head = number;
+            packing_size = 0;
+        }
+
+        packing.push(record);
+        packing_size += size;
     }
+
+    flush(&mut messages, packing_head, &mut packing);
+
+    messages.push(Message::Batch { flags: 0 });
+
+    messages
 }
 
 #[cfg(test)]
@@ -550,7 +665,7 @@ mod tests {
                 head: 32768,
                 flags: 0,
                 sequence: 0,
-                records: records
+                records
             })
         );
 
@@ -597,4 +712,250 @@ mod tests {
 
         Ok(())
     }
+
+    fn fragments(head: u64, record: &Record, sizes: &[usize]) -> Result<Vec<Vec<u8>>> {
+        let delimited = record.to_delimited()?;
+        let mut remaining = delimited.bytes();
+        let mut packets = Vec::new();
+        for (sequence, size) in sizes.iter().enumerate() {
+            let (fragment, rest) = remaining.split_at(*size.min(&remaining.len()));
+            remaining = rest;
+            let mut bytes = Vec::new();
+            Message::Records {
+                head,
+                flags: 1,
+                sequence: sequence as u32,
+                records: vec![Record::Bytes(fragment.to_vec())],
+            }
+            .write(&mut bytes)?;
+            packets.push(bytes);
+        }
+
+        Ok(packets)
+    }
+
+    #[test]
+    pub fn test_serialization_records_many_fragments() -> Result<()> {
+        let original = Record::new_all_zeros(3000);
+        let packets = fragments(100, &original, &[1000, 1000, 1000, 1000])?;
+
+        let mut codec = MessageCodec::default();
+
+        assert_eq!(codec.try_read(&packets[0])?, None);
+        assert_eq!(codec.try_read(&packets[1])?, None);
+        assert_eq!(codec.try_read(&packets[2])?, None);
+        assert_eq!(
+            codec.try_read(&packets[3])?,
+            Some(Message::Records {
+                head: 100,
+                flags: 0,
+                sequence: 0,
+                records: vec![original]
+            })
+        );
+        assert!(!codec.has_partial());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_serialization_records_skipped_fragment() -> Result<()> {
+        let original = Record::new_all_zeros(3000);
+        let packets = fragments(100, &original, &[1000, 1000, 1000, 1000])?;
+
+        let mut codec = MessageCodec::default();
+
+        assert_eq!(codec.try_read(&packets[0])?, None);
+        assert_eq!(codec.try_read(&packets[2])?, None);
+        assert_eq!(codec.try_read(&packets[3])?, None);
+        assert!(!codec.has_partial());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_partial_records_go_stale() -> Result<()> {
+        let original = Record::new_all_zeros(3000);
+        let packets = fragments(100, &original, &[1000, 1000, 1000, 1000])?;
+
+        let mut codec = MessageCodec::default();
+
+        assert_eq!(codec.try_read(&packets[0])?, None);
+        assert!(!codec.is_stale(Duration::from_secs(1)));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        assert!(codec.is_stale(Duration::from_secs(1)));
+        assert_eq!(codec.try_read(&packets[1])?, None);
+        assert!(!codec.is_stale(Duration::from_secs(1)));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_packing_fragments_when_asked() -> Result<()> {
+        let records = vec![vec![1u8; 100], vec![2u8; 100]];
+        let mut fragmenting = |_: &[u8]| 3;
+        let messages = pack_records(&records, &RecordRange::new(0, 9), Some(&mut fragmenting));
+
+        assert_eq!(messages.len(), 7);
+        assert_eq!(messages.last(), Some(&Message::Batch { flags: 0 }));
+
+        let mut codec = MessageCodec::default();
+        let mut received = Vec::new();
+        for message in messages.iter() {
+            let mut bytes = Vec::new();
+            message.write(&mut bytes)?;
+            if let Some(Message::Records { records, .. }) = codec.try_read(&bytes)? {
+                received.extend(records.into_iter().map(|r| r.bytes().to_vec()));
+            }
+        }
+        assert_eq!(received, records);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_serialization_require_after_head() -> Result<()> {
+        let message = Message::Require(RecordRange::new(95_000, 99_999));
+        let mut buffer = Vec::new();
+        message.write(&mut buffer)?;
+
+        let mut codec = MessageCodec::default();
+
+        assert_eq!(
+            codec.try_read(&buffer)?,
+            Some(Message::Require(RecordRange::new(95_000, 99_999)))
+        );
+
+        Ok(())
+    }
+
+    fn header(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    #[test]
+    pub fn test_unknown_kind() {
+        let mut codec = MessageCodec::default();
+        assert!(matches!(
+            codec.try_read(&header(&[7])),
+            Err(ProtocolError::UnknownKind(7))
+        ));
+    }
+
+    #[test]
+    pub fn test_require_nothing() {
+        let mut codec = MessageCodec::default();
+        assert!(matches!(
+            codec.try_read(&header(&[FK_UDP_PROTOCOL_KIND_REQUIRE, 10, 0])),
+            Err(ProtocolError::InvalidRange(10, 0))
+        ));
+    }
+
+    #[test]
+    pub fn test_records_without_records() {
+        let mut codec = MessageCodec::default();
+        assert!(matches!(
+            codec.try_read(&header(&[FK_UDP_PROTOCOL_KIND_RECORDS, 10, 0, 0])),
+            Err(ProtocolError::MalformedRecords)
+        ));
+    }
+
+    #[test]
+    pub fn test_truncated() {
+        let mut codec = MessageCodec::default();
+        assert!(matches!(
+            codec.try_read(&[FK_UDP_PROTOCOL_KIND_BATCH as u8, 0]),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    fn valid_packets() -> Result<Vec<Vec<u8>>> {
+        let messages = [
+            Message::Query,
+            Message::Statistics {
+                nrecords: 100,
+                identity: Identity {
+                    device_id: DeviceId("0011aabbccddee".to_owned()),
+                    generation_id: "0011aabbccddee".to_owned(),
+                    name: "Name".to_owned(),
+                },
+            },
+            Message::Require(RecordRange::new(10, 100)),
+            Message::Records {
+                head: 10,
+                flags: 0,
+                sequence: 0,
+                records: vec![Record::new_all_zeros(20), Record::new_all_zeros(300)],
+            },
+            Message::Records {
+                head: 12,
+                flags: 1,
+                sequence: 0,
+                records: vec![Record::Bytes(vec![0xac, 0x02, 0, 0, 0])],
+            },
+            Message::Batch { flags: 0 },
+        ];
+
+        messages
+            .iter()
+            .map(|m| {
+                let mut bytes = Vec::new();
+                m.write(&mut bytes)?;
+                Ok(bytes)
+            })
+            .collect()
+    }
+
+    mod fuzzing {
+        use proptest::prelude::*;
+
+        use super::*;
+
+        proptest! {
+            #[test]
+            fn test_random_bytes_never_panic(
+                packets in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..512), 1..8)
+            ) {
+                let mut codec = MessageCodec::default();
+                for packet in packets {
+                    let _ = codec.try_read(&packet);
+                }
+            }
+
+            #[test]
+            fn test_random_payloads_never_panic(
+                kind in 0..6u32,
+                words in prop::collection::vec(any::<u32>(), 0..4),
+                tail in prop::collection::vec(any::<u8>(), 0..64),
+            ) {
+                let mut packet = header(&[kind]);
+                packet.extend(header(&words));
+                packet.extend(tail);
+
+                let mut codec = MessageCodec::default();
+                let _ = codec.try_read(&packet);
+            }
+
+            #[test]
+            fn test_mangled_packets_never_panic(
+                mangles in prop::collection::vec(
+                    (any::<prop::sample::Index>(), any::<prop::sample::Index>(), any::<u8>()),
+                    1..16
+                ),
+                truncate in any::<prop::sample::Index>(),
+            ) {
+                let mut codec = MessageCodec::default();
+                for (packet, offset, value) in mangles {
+                    let packets = valid_packets().unwrap();
+                    let mut bytes = packet.get(&packets).clone();
+                    let i = offset.index(bytes.len());
+                    bytes[i] = value;
+                    bytes.truncate(truncate.index(bytes.len() + 1));
+                    let _ = codec.try_read(&bytes);
+                }
+            }
+        }
+    }
 }
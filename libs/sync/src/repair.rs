@@ -0,0 +1,366 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+use tracing::*;
+
+use crate::Flushed;
+use protos::{meta_path, FileMeta, ReadError, RecordsReader, StreamKind};
+
+/// Something `FilesRecordSink::repair` changed, or couldn't.
+#[derive(Debug)]
+pub enum Repaired {
+    /// Bytes removed from the end of a file after its last complete record.
+    Truncated(PathBuf, u64),
+    RebuiltMeta(PathBuf),
+    Rejoined(Flushed),
+    Skipped(PathBuf, String),
+}
+
+impl Display for Repaired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated(path, bytes) => {
+                write!(f, "{}: truncated {} bytes", path.display(), bytes)
+            }
+            Self::RebuiltMeta(path) => write!(f, "{}: rebuilt meta", path.display()),
+            Self::Rejoined(flushed) => write!(
+                f,
+                "{}: rejoined records {}..={}",
+                flushed.path.display(),
+                flushed.records.start(),
+                flushed.records.end()
+            ),
+            Self::Skipped(path, reason) => write!(f, "{}: skipped, {}", path.display(), reason),
+        }
+    }
+}
+
+/// Writes to a temporary file beside `path` and renames it into place, so a
+/// crash never leaves a torn file behind.
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let writing_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut writing =
+        File::create(&writing_path).with_context(|| format!("Creating {:?}", &writing_path))?;
+
+    if let Err(e) = write(&mut writing).and_then(|_| Ok(writing.sync_all()?)) {
+        let _ = std::fs::remove_file(&writing_path);
+        return Err(e);
+    }
+
+    std::fs::rename(&writing_path, path)
+        .with_context(|| format!("Renaming {:?} to {:?}", &writing_path, path))?;
+
+    Ok(())
+}
+
+/// What a file's records say about it.
+#[derive(Default)]
+struct Scanned {
+    records: u64,
+    /// Offset just past the last complete record.
+    complete: u64,
+    kind: Option<StreamKind>,
+    device_id: Option<String>,
+    generation_id: Option<String>,
+    name: Option<String>,
+}
+
+fn scan(path: &Path) -> Result<Scanned> {
+    let file = File::open(path).with_context(|| format!("Opening {:?}", path))?;
+    let mut scanned = Scanned::default();
+
+    for read in RecordsReader::new(BufReader::new(file)) {
+        let read = match read {
+            Ok(read) => read,
+            Err(ReadError::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                debug!("{} {}", path.display(), e);
+                break;
+            }
+        };
+
+        scanned.records += 1;
+        scanned.complete = read.end();
+
+        let Ok(record) = read.record else {
+            continue;
+        };
+
+        if scanned.kind.is_none() {
+            if record.readings.is_some() {
+                scanned.kind = Some(StreamKind::Data);
+            } else if record.metadata.is_some() {
+                scanned.kind = Some(StreamKind::Meta);
+            }
+        }
+        if let Some(metadata) = record.metadata.as_ref() {
+            if !metadata.device_id.is_empty() {
+                scanned.device_id = Some(hex::encode(&metadata.device_id));
+            }
+            if !metadata.generation.is_empty() {
+                scanned.generation_id = Some(hex::encode(&metadata.generation));
+            }
+        }
+        if let Some(identity) = record.identity.as_ref() {
+            scanned.name = Some(identity.name.clone());
+        }
+    }
+
+    Ok(scanned)
+}
+
+/// Cuts a file off after its last complete record, returning how many bytes
+/// were removed.
+pub fn truncate_incomplete(path: &Path) -> Result<Option<u64>> {
+    let size = std::fs::metadata(path)?.len();
+    let complete = scan(path)?.complete;
+    if complete == size {
+        return Ok(None);
+    }
+
+    info!("{} truncating {} -> {}", path.display(), size, complete);
+
+    write_atomically(path, |writing| {
+        let mut reading = File::open(path)?.take(complete);
+        std::io::copy(&mut reading, writing)?;
+        Ok(())
+    })?;
+
+    Ok(Some(size - complete))
+}
+
+/// Number of the first record in a joined file whose meta is gone, taken
+/// from the lowest chunk left in its sync directory.
+fn first_chunk(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let sync_path = path.with_file_name(name.strip_suffix(".fkpb")?);
+    std::fs::read_dir(sync_path)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".fkpb")?.parse().ok())
+        .min()
+}
+
+/// Writes the `.fkpb.json` beside a file from what its records say, keeping
+/// any headers the records can't tell us about. Returns false if the
+/// existing meta already agreed or there were no records to describe.
+pub fn rebuild_file_meta(path: &Path) -> Result<bool> {
+    let meta_path = meta_path(path);
+    let existing = if meta_path.exists() {
+        match FileMeta::load_from_json_sync(&meta_path) {
+            Ok(fm) => Some(fm),
+            Err(e) => {
+                warn!("{} unreadable, replacing: {}", meta_path.display(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let scanned = scan(path)?;
+    if scanned.records == 0 {
+        warn!("{} has no records, leaving meta alone", path.display());
+        return Ok(false);
+    }
+
+    let first = existing
+        .as_ref()
+        .and_then(|fm| fm.blocks())
+        .map(|b| *b.start())
+        .or_else(|| first_chunk(path))
+        .unwrap_or_default();
+
+    let mut headers = existing
+        .as_ref()
+        .map(|fm| fm.headers.clone())
+        .unwrap_or_else(HashMap::new);
+
+    // Files live under a directory named for the station.
+    let directory = path
+        .parent()
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_owned());
+    let kind = match scanned
+        .kind
+        .or_else(|| existing.as_ref().and_then(|fm| fm.kind()))
+    {
+        Some(StreamKind::Meta) => "meta",
+        Some(StreamKind::Data) | None => "data",
+    };
+
+    headers.insert(
+        "Fk-Blocks".to_owned(),
+        format!("{},{}", first, first + scanned.records - 1),
+    );
+    headers.insert("Fk-Type".to_owned(), kind.to_owned());
+    if let Some(device_id) = scanned.device_id {
+        headers.insert("Fk-DeviceId".to_owned(), device_id);
+    } else if let Some(directory) = directory {
+        headers.entry("Fk-DeviceId".to_owned()).or_insert(directory);
+    }
+    if let Some(generation_id) = scanned.generation_id {
+        headers.insert("Fk-Generation".to_owned(), generation_id);
+    }
+    if let Some(name) = scanned.name {
+        headers.insert("Fk-DeviceName".to_owned(), name);
+    }
+
+    if existing.map(|fm| fm.headers) == Some(headers.clone()) {
+        return Ok(false);
+    }
+
+    info!("{} rebuilding", meta_path.display());
+
+    let fm = FileMeta { headers };
+    write_atomically(&meta_path, |writing| {
+        Ok(serde_json::to_writer(writing, &fm)?)
+    })?;
+
+    Ok(true)
+}
+
+/// Truncates a file and then rebuilds its meta, in that order so the meta
+/// describes what's left.
+pub fn repair_file(path: &Path) -> Result<Vec<Repaired>> {
+    let mut repaired = Vec::new();
+    if let Some(removed) = truncate_incomplete(path)? {
+        repaired.push(Repaired::Truncated(path.to_owned(), removed));
+    }
+    if rebuild_file_meta(path)? {
+        repaired.push(Repaired::RebuiltMeta(meta_path(path)));
+    }
+
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use protos::data::{DataRecord, Identity, Metadata, Readings};
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn meta_record() -> Vec<u8> {
+        DataRecord {
+            metadata: Some(Metadata {
+                device_id: vec![0x00, 0x11, 0x22, 0x33],
+                generation: vec![0xaa, 0xbb],
+                ..Default::default()
+            }),
+            identity: Some(Identity {
+                name: "Early Impala 91".to_owned(),
+            }),
+            ..Default::default()
+        }
+        .encode_length_delimited_to_vec()
+    }
+
+    fn data_records(count: u64) -> Vec<u8> {
+        (0..count)
+            .flat_map(|reading| {
+                DataRecord {
+                    readings: Some(Readings {
+                        reading,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+                .encode_length_delimited_to_vec()
+            })
+            .collect()
+    }
+
+    fn load_meta(path: &Path) -> Result<FileMeta> {
+        FileMeta::load_from_json_sync(&meta_path(path))
+    }
+
+    #[test]
+    fn test_truncates_partial_record() -> Result<()> {
+        let dir = TempDir::new("fk-tests-repair")?;
+        let path = dir.path().join("data.fkpb");
+        let complete = data_records(10);
+        let mut bytes = complete.clone();
+        bytes.extend([20, 0x08, 0x01]);
+        std::fs::write(&path, &bytes)?;
+
+        assert_eq!(truncate_incomplete(&path)?, Some(3));
+        assert_eq!(std::fs::read(&path)?, complete);
+        assert_eq!(truncate_incomplete(&path)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuilds_missing_meta() -> Result<()> {
+        let dir = TempDir::new("fk-tests-repair")?;
+        let device_path = dir.path().join("00112233");
+        std::fs::create_dir_all(&device_path)?;
+
+        let data = device_path.join("data.fkpb");
+        std::fs::write(&data, data_records(5))?;
+        let meta = device_path.join("meta.fkpb");
+        std::fs::write(&meta, meta_record())?;
+
+        assert!(rebuild_file_meta(&data)?);
+        let fm = load_meta(&data)?;
+        assert_eq!(fm.blocks(), Some(0..=4));
+        assert_eq!(fm.kind(), Some(StreamKind::Data));
+        assert_eq!(
+            fm.headers.get("Fk-DeviceId").map(|v| v.as_str()),
+            Some("00112233")
+        );
+
+        assert!(rebuild_file_meta(&meta)?);
+        let fm = load_meta(&meta)?;
+        assert_eq!(fm.kind(), Some(StreamKind::Meta));
+        assert_eq!(
+            fm.headers.get("Fk-Generation").map(|v| v.as_str()),
+            Some("aabb")
+        );
+        assert_eq!(
+            fm.headers.get("Fk-DeviceName").map(|v| v.as_str()),
+            Some("Early Impala 91")
+        );
+
+        assert!(!rebuild_file_meta(&data)?);
+        assert!(!rebuild_file_meta(&meta)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuilds_wrong_meta() -> Result<()> {
+        let dir = TempDir::new("fk-tests-repair")?;
+        let path = dir.path().join("data.fkpb");
+        let mut bytes = data_records(8);
+        bytes.extend([20, 0x08]);
+        std::fs::write(&path, bytes)?;
+        std::fs::write(
+            meta_path(&path),
+            r#"{"headers":{"Fk-Blocks":"100,199","Fk-Generation":"aabb"}}"#,
+        )?;
+
+        let repaired = repair_file(&path)?;
+        assert_eq!(repaired.len(), 2);
+
+        let fm = load_meta(&path)?;
+        assert_eq!(fm.blocks(), Some(100..=107));
+        assert_eq!(
+            fm.headers.get("Fk-Generation").map(|v| v.as_str()),
+            Some("aabb")
+        );
+
+        Ok(())
+    }
+}
@@ -1,14 +1,20 @@
 use anyhow::{anyhow, Result};
-use backoff::{backoff::Backoff, ExponentialBackoff, ExponentialBackoffBuilder};
+use backoff::{
+    backoff::Backoff,
+    exponential::{ExponentialBackoff, ExponentialBackoffBuilder},
+    Clock,
+};
 use discovery::{DeviceId, Discovered};
 use range_set_blaze::prelude::*;
 use std::{
     collections::HashMap,
     net::SocketAddr,
     ops::RangeInclusive,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, SystemTime},
 };
+use thiserror::Error;
 use tokio::{
     sync::mpsc::Sender,
     sync::mpsc::{self},
@@ -18,8 +24,9 @@ use tokio::{
 use tracing::*;
 
 use crate::{
+    checkpoint::Checkpoint,
     progress::{Progress, RangeProgress},
-    proto::{Identity, Message, ReceivedRecords, RecordRange},
+    proto::{Identity, Message, NumberedRecord, ReceivedRecords, RecordRange},
     transport::{ReceiveTransport, SendTransport},
     Transport, TransportMessage,
 };
@@ -31,12 +38,41 @@ const REQUIRES_MERGE_WIDTH: u64 = 500;
 #[derive(Debug)]
 pub enum SinkMessage {
     Records(ReceivedRecords),
+    Checkpoint(Identity, Checkpoint),
     Flush(String, Identity),
 }
 
+/// The file a finished sync was joined into.
+#[derive(Clone, Debug)]
+pub struct Flushed {
+    pub path: PathBuf,
+    pub size: u64,
+    pub generation_id: String,
+    pub records: RangeInclusive<u64>,
+}
+
 pub trait RecordsSink: Send + Sync {
     fn write(&self, records: &ReceivedRecords) -> Result<()>;
-    fn flush(&self, sync_id: String, identity: Identity) -> Result<()>;
+
+    /// Returns the file the sync was joined into, if the sink writes one.
+    fn flush(&self, sync_id: String, identity: Identity) -> Result<Option<Flushed>>;
+
+    /// Save progress for the given generation so that a later sync can be
+    /// resumed. Always sent after the records it describes.
+    fn checkpoint(&self, _identity: &Identity, _checkpoint: &Checkpoint) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the progress of an unfinished sync of this generation, if any.
+    fn resume(&self, _identity: &Identity) -> Result<Option<Checkpoint>> {
+        Ok(None)
+    }
+
+    /// Returns the number of the last record already held for this
+    /// generation, so that only newer records are requested.
+    fn last_synced(&self, _identity: &Identity) -> Result<Option<u64>> {
+        Ok(None)
+    }
 }
 
 #[derive(Default)]
@@ -47,18 +83,29 @@ impl RecordsSink for DevNullSink {
         Ok(())
     }
 
-    fn flush(&self, _sync_id: String, _identity: Identity) -> Result<()> {
-        Ok(())
+    fn flush(&self, _sync_id: String, _identity: Identity) -> Result<Option<Flushed>> {
+        Ok(None)
     }
 }
 
+#[derive(Clone, Debug, Error)]
+pub enum SyncFailure {
+    #[error("Station stopped responding")]
+    Stalled,
+    #[error("Sync cancelled")]
+    Cancelled,
+    #[error("Flush failed: {0}")]
+    Flush(String),
+}
+
 #[derive(Debug)]
 pub enum ServerEvent {
     Began(DeviceId),
     Transferring(DeviceId, SystemTime, Progress),
     Processing(DeviceId),
-    Completed(DeviceId),
-    Failed(DeviceId),
+    /// Carries the joined file, which is None when there was nothing new.
+    Completed(DeviceId, Option<Flushed>),
+    Failed(DeviceId, SyncFailure),
 }
 
 #[derive(Debug)]
@@ -67,7 +114,18 @@ pub enum ServerCommand {
     Cancel(DeviceId),
     Received(TransportMessage),
     Tick,
-    Flushed,
+    Flushed(DeviceId, Result<Option<Flushed>, String>),
+}
+
+/// Keeps backoff on the same clock as the rest of the server, which matters
+/// when tests pause and advance time.
+#[derive(Default)]
+struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> std::time::Instant {
+        Instant::now().into_std()
+    }
 }
 
 #[derive(Clone)]
@@ -94,13 +152,7 @@ enum DeviceState {
     Stalled(Until),
     Processing,
     Synced,
-    Failed,
-}
-
-#[derive(Debug)]
-enum HasGaps {
-    Continuous(RangeInclusive<u64>),
-    HasGaps(RangeInclusive<u64>, Vec<RangeInclusive<u64>>),
+    Failed(SyncFailure),
 }
 
 #[derive(Default)]
@@ -118,10 +170,13 @@ struct ConnectedDevice {
     syncing_started: Option<SystemTime>,
     progress_published: Option<Instant>,
     received_at: Option<Instant>,
+    sent_at: Option<Instant>,
     total_records: Option<u64>,
+    head: u64,
     received: RangeSetBlaze<u64>,
-    backoff: ExponentialBackoff,
+    backoff: ExponentialBackoff<TokioClock>,
     statistics: Statistics,
+    flushed: Option<Flushed>,
 }
 
 #[derive(Debug)]
@@ -147,13 +202,16 @@ impl ConnectedDevice {
             state: DeviceState::Discovered,
             identity: None,
             received_at: None,
+            sent_at: None,
             total_records: None,
+            head: 0,
             received: RangeSetBlaze::new(),
             backoff: Self::stall_backoff(),
             sync_id: new_sync_id(),
             syncing_started: None,
             progress_published: None,
             statistics: Default::default(),
+            flushed: None,
         }
     }
 
@@ -165,12 +223,15 @@ impl ConnectedDevice {
         match &self.state {
             DeviceState::Discovered | DeviceState::Synced => {
                 self.total_records = None;
+                self.head = 0;
                 self.received = Default::default();
                 self.received_at = None;
+                self.sent_at = None;
                 self.sync_id = new_sync_id();
                 self.syncing_started = None;
                 self.progress_published = None;
                 self.statistics = Default::default();
+                self.flushed = None;
                 self.backoff.reset();
 
                 Transition::Send(Message::Query, DeviceState::Learning)
@@ -181,7 +242,7 @@ impl ConnectedDevice {
 
     fn tick(&mut self) -> Result<Transition> {
         match &self.state {
-            DeviceState::Synced | DeviceState::Failed => Ok(Transition::None),
+            DeviceState::Synced | DeviceState::Failed(_) => Ok(Transition::None),
             DeviceState::Discovered => {
                 self.received.clear();
 
@@ -190,6 +251,9 @@ impl ConnectedDevice {
             DeviceState::Stalled(until) => {
                 if until.before() {
                     Ok(Transition::None)
+                } else if self.total_records.is_none() {
+                    // Never heard back from our Query, so ask again.
+                    Ok(Transition::Send(Message::Query, DeviceState::Learning))
                 } else {
                     self.query_requires()
                 }
@@ -203,7 +267,9 @@ impl ConnectedDevice {
                         ))))
                     } else {
                         info!("FAILED {:?}", self.last_received_at(),);
-                        Ok(Transition::Direct(DeviceState::Failed))
+                        Ok(Transition::Direct(DeviceState::Failed(
+                            SyncFailure::Stalled,
+                        )))
                     }
                 } else {
                     Ok(Transition::None)
@@ -212,9 +278,15 @@ impl ConnectedDevice {
         }
     }
 
-    fn flushed(&self) -> Transition {
-        match &self.state {
-            DeviceState::Processing => Transition::Direct(DeviceState::Synced),
+    fn flushed(&mut self, flushed: &Result<Option<Flushed>, String>) -> Transition {
+        match (&self.state, flushed) {
+            (DeviceState::Processing, Ok(flushed)) => {
+                self.flushed = flushed.clone();
+                Transition::Direct(DeviceState::Synced)
+            }
+            (DeviceState::Processing, Err(e)) => {
+                Transition::Direct(DeviceState::Failed(SyncFailure::Flush(e.clone())))
+            }
             _ => {
                 warn!("Flushed during {:?}", self.state);
                 Transition::None
@@ -227,6 +299,14 @@ impl ConnectedDevice {
         self.received_at = Some(Instant::now());
         self.backoff.reset();
 
+        // Late and duplicated packets can show up after we've finished.
+        if matches!(
+            self.state,
+            DeviceState::Processing | DeviceState::Synced | DeviceState::Failed(_)
+        ) {
+            return Ok(Transition::None);
+        }
+
         match message {
             Message::Statistics { nrecords, identity } => {
                 self.identity = Some(identity.clone());
@@ -248,9 +328,8 @@ impl ConnectedDevice {
                 self.received(just_received);
 
                 let progress = self.progress();
-                let has_gaps = self.received_has_gaps();
                 let progress = progress.map_or("".to_owned(), |f| format!("{:?}", f));
-                trace!(target: "transfer-progress", "{} {:?}", progress, &has_gaps);
+                trace!(target: "transfer-progress", "{}", progress);
 
                 match &self.state {
                     DeviceState::Required(range) => {
@@ -269,7 +348,11 @@ impl ConnectedDevice {
             let new_state = DeviceState::Required(range.clone());
             Ok(Transition::Send(Message::Require(range), new_state))
         } else {
-            if self.total_records.is_some() {
+            if self.total_records.is_some() && self.received.is_empty() {
+                info!("Nothing new since #{}", self.head);
+
+                Ok(Transition::Direct(DeviceState::Synced))
+            } else if self.total_records.is_some() {
                 let elapsed = SystemTime::now().duration_since(self.syncing_started.unwrap())?;
                 info!("Transferring took {:?}", elapsed);
 
@@ -280,6 +363,43 @@ impl ConnectedDevice {
         }
     }
 
+    fn wants_resume(&self) -> bool {
+        matches!(self.state, DeviceState::Learning)
+    }
+
+    fn resume(&mut self, checkpoint: Checkpoint) {
+        info!(
+            "{:?} resuming {} ({} received)",
+            self.device_id,
+            checkpoint.sync_id,
+            checkpoint.to_set().len()
+        );
+
+        self.head = checkpoint.head;
+        self.received = checkpoint.to_set();
+        self.sync_id = checkpoint.sync_id;
+    }
+
+    fn starting_after(&mut self, last: u64) {
+        info!("{:?} already have through #{}", self.device_id, last);
+
+        self.head = last + 1;
+    }
+
+    fn checkpoint(&self) -> Option<(Identity, Checkpoint)> {
+        self.identity.as_ref().map(|identity| {
+            (
+                identity.clone(),
+                Checkpoint::new(
+                    &self.sync_id,
+                    &identity.generation_id,
+                    self.head,
+                    &self.received,
+                ),
+            )
+        })
+    }
+
     fn requires(&self) -> Option<RecordRange> {
         fn cap_length(r: RangeInclusive<u64>, len: u64) -> RangeInclusive<u64> {
             assert!(len > 0);
@@ -312,7 +432,12 @@ impl ConnectedDevice {
         }
 
         let total_records = self.total_records?;
-        let total_required = RangeSetBlaze::from_iter([0..=total_records - 1]) - &self.received;
+        if self.head >= total_records {
+            return None;
+        }
+
+        let total_required =
+            RangeSetBlaze::from_iter([self.head..=total_records - 1]) - &self.received;
 
         let mut requiring: Option<RangeInclusive<u64>> = None;
         for range in total_required.into_ranges() {
@@ -331,6 +456,25 @@ impl ConnectedDevice {
         self.received.append(&mut appending);
     }
 
+    /// Drops records we already have, returning the remaining runs of
+    /// consecutive records so duplicated packets are never written twice.
+    fn unreceived(&self, records: Vec<NumberedRecord>) -> Vec<Vec<NumberedRecord>> {
+        let mut runs: Vec<Vec<NumberedRecord>> = Vec::new();
+        for record in records {
+            if self.received.contains(record.number) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run) if run.last().map(|r| r.number + 1) == Some(record.number) => {
+                    run.push(record)
+                }
+                _ => runs.push(vec![record]),
+            }
+        }
+
+        runs
+    }
+
     fn last_received_at(&self) -> Option<Duration> {
         self.received_at.map(|r| Instant::now() - r)
     }
@@ -339,51 +483,46 @@ impl ConnectedDevice {
         self.last_received_at().map(|v| v > d).unwrap_or(false)
     }
 
+    fn last_sent_more_than(&self, d: Duration) -> bool {
+        self.sent_at
+            .map(|s| Instant::now() - s > d)
+            .unwrap_or(false)
+    }
+
+    /// Quiet since both our last request and the last reply, so a request
+    /// that was lost before anything came back still stalls.
+    fn quiet_for_more_than(&self, d: Duration) -> bool {
+        self.last_sent_more_than(d)
+            && (self.received_at.is_none() || self.last_received_more_than(d))
+    }
+
     fn is_stalled(&self) -> bool {
         match &self.state {
             DeviceState::Learning | DeviceState::Required(_) => {
-                self.last_received_more_than(Duration::from_millis(STALLED_EXPECTING_MILLIS))
+                self.quiet_for_more_than(Duration::from_millis(STALLED_EXPECTING_MILLIS))
             }
             DeviceState::Receiving(_) => {
-                self.last_received_more_than(Duration::from_millis(STALLED_RECEIVING_MILLIS))
+                self.quiet_for_more_than(Duration::from_millis(STALLED_RECEIVING_MILLIS))
             }
             _ => false,
         }
     }
 
-    fn received_has_gaps(&self) -> HasGaps {
-        match self.received.last() {
-            Some(last) => {
-                let full_range = 0..=last;
-                if last as u128 + 1 == self.received.len() {
-                    HasGaps::Continuous(full_range)
-                } else {
-                    let inverted = RangeSetBlaze::from_iter(full_range.clone()) - &self.received;
-                    let gaps = inverted
-                        .into_ranges()
-                        .collect::<Vec<std::ops::RangeInclusive<_>>>();
-                    HasGaps::HasGaps(full_range, gaps)
-                }
-            }
-            None => HasGaps::Continuous(0..=0),
-        }
-    }
-
-    fn stall_backoff() -> ExponentialBackoff {
-        ExponentialBackoffBuilder::new()
+    fn stall_backoff() -> ExponentialBackoff<TokioClock> {
+        let mut backoff = ExponentialBackoffBuilder::new()
             .with_initial_interval(Duration::from_millis(5000))
             .with_randomization_factor(0.0)
             .with_multiplier(1.8)
             .with_max_elapsed_time(Some(Duration::from_secs(30)))
-            .build()
+            .build();
+        backoff.reset();
+        backoff
     }
 
     fn completed(&self) -> Option<RangeProgress> {
-        if let Some(total) = self.total_records {
-            Some(RangeProgress::new(0..=(total - 1), &self.received))
-        } else {
-            None
-        }
+        self.total_records
+            .filter(|total| *total > self.head)
+            .map(|total| RangeProgress::new(self.head..=(total - 1), &self.received))
     }
 
     fn batch(&self) -> Option<RangeProgress> {
@@ -421,12 +560,19 @@ impl ConnectedDevice {
 
         match (&self.state, &new) {
             (DeviceState::Synced, _) => {}
-            (DeviceState::Failed, _) => {}
+            (DeviceState::Failed(_), _) => {}
+            (DeviceState::Learning | DeviceState::Stalled(_), DeviceState::Learning) => {}
             (_, DeviceState::Learning) => {
                 events
                     .send(ServerEvent::Began(self.device_id.clone()))
                     .await?;
             }
+            (_, DeviceState::Required(_)) => {
+                if let Some((identity, checkpoint)) = self.checkpoint() {
+                    sink.send(SinkMessage::Checkpoint(identity, checkpoint))
+                        .await?;
+                }
+            }
             (_, DeviceState::Processing) => {
                 events
                     .send(ServerEvent::Processing(self.device_id.clone()))
@@ -441,12 +587,15 @@ impl ConnectedDevice {
             }
             (_, DeviceState::Synced) => {
                 events
-                    .send(ServerEvent::Completed(self.device_id.clone()))
+                    .send(ServerEvent::Completed(
+                        self.device_id.clone(),
+                        self.flushed.clone(),
+                    ))
                     .await?;
             }
-            (_, DeviceState::Failed) => {
+            (_, DeviceState::Failed(failure)) => {
                 events
-                    .send(ServerEvent::Failed(self.device_id.clone()))
+                    .send(ServerEvent::Failed(self.device_id.clone(), failure.clone()))
                     .await?;
             }
             (_, _) => {}
@@ -472,9 +621,8 @@ impl ConnectedDevice {
                 Ok(())
             }
             Transition::Send(sending, state) => {
-                sender
-                    .send(TransportMessage((self.addr.clone(), sending)))
-                    .await?;
+                sender.send(TransportMessage((self.addr, sending))).await?;
+                self.sent_at = Some(Instant::now());
 
                 self.transition(state, events, sink).await?;
 
@@ -529,18 +677,28 @@ impl<T: Transport, R: RecordsSink + 'static> Server<T, R> {
                 while let Some(message) = sink_rx.recv().await {
                     let sink = records_sink.lock().await;
                     match message {
-                        SinkMessage::Records(received) => match sink.write(&received) {
-                            Err(e) => warn!("Write records error: {:?}", e),
-                            Ok(_) => (),
-                        },
+                        SinkMessage::Records(received) => {
+                            if let Err(e) = sink.write(&received) {
+                                warn!("Write records error: {:?}", e);
+                            }
+                        }
+                        SinkMessage::Checkpoint(identity, checkpoint) => {
+                            if let Err(e) = sink.checkpoint(&identity, &checkpoint) {
+                                warn!("Checkpoint error: {:?}", e);
+                            }
+                        }
                         SinkMessage::Flush(sync_id, identity) => {
                             info!("flushing");
-                            match sink.flush(sync_id, identity) {
-                                Err(e) => warn!("Send Flushed error: {:?}", e),
-                                Ok(_) => match tx.send(vec![ServerCommand::Flushed]).await {
-                                    Err(e) => warn!("Send Flushed error: {:?}", e),
-                                    Ok(_) => {}
-                                },
+                            let device_id = identity.device_id.clone();
+                            let flushed = sink.flush(sync_id, identity).map_err(|e| {
+                                warn!("Flush error: {:?}", e);
+                                format!("{:#}", e)
+                            });
+                            if let Err(e) = tx
+                                .send(vec![ServerCommand::Flushed(device_id, flushed)])
+                                .await
+                            {
+                                warn!("Send Flushed error: {:?}", e);
                             }
                         }
                     }
@@ -552,17 +710,11 @@ impl<T: Transport, R: RecordsSink + 'static> Server<T, R> {
             let tx = tx.clone();
             async move {
                 while let Ok(Some(received)) = receive.recv().await {
-                    match tx
-                        .send(
-                            received
-                                .into_iter()
-                                .map(|m| ServerCommand::Received(m))
-                                .collect(),
-                        )
+                    if let Err(e) = tx
+                        .send(received.into_iter().map(ServerCommand::Received).collect())
                         .await
                     {
-                        Err(e) => warn!("Error forwarding received: {:?}", e),
-                        Ok(_) => {}
+                        warn!("Error forwarding received: {:?}", e);
                     }
                 }
             }
@@ -570,6 +722,7 @@ impl<T: Transport, R: RecordsSink + 'static> Server<T, R> {
 
         let pump = tokio::spawn({
             let tx = tx.clone();
+            let records_sink = Arc::clone(&self.records_sink);
             let mut devices = Devices::new();
             let mut locked = self.sender.lock().await;
             *locked = Some(tx.clone());
@@ -587,6 +740,7 @@ impl<T: Transport, R: RecordsSink + 'static> Server<T, R> {
                         match handle_server_command::<R, T::Send>(
                             cmd,
                             &send,
+                            &records_sink,
                             &publish,
                             &sink_tx,
                             &mut devices,
@@ -607,9 +761,8 @@ impl<T: Transport, R: RecordsSink + 'static> Server<T, R> {
             loop {
                 interval.tick().await;
 
-                match tx.send(vec![ServerCommand::Tick]).await {
-                    Err(e) => warn!("Tick error: {}", e),
-                    Ok(_) => {}
+                if let Err(e) = tx.send(vec![ServerCommand::Tick]).await {
+                    warn!("Tick error: {}", e);
                 }
             }
         });
@@ -658,6 +811,10 @@ impl Devices {
         Some(entry)
     }
 
+    fn get_device(&mut self, device_id: &DeviceId) -> Option<&mut ConnectedDevice> {
+        self.devices.get_mut(device_id)
+    }
+
     fn get_device_by_addr(&mut self, addr: &SocketAddr) -> Option<&mut ConnectedDevice> {
         if let Some(device_id) = self.by_addr.get(addr) {
             self.devices.get_mut(device_id)
@@ -697,9 +854,57 @@ impl Devices {
     }
 }
 
-async fn handle_server_command<R: RecordsSink, S: SendTransport>(
+/// Where a station's sync begins, given what the sink already has.
+#[derive(Debug, PartialEq)]
+enum StartingPoint {
+    Resume(Checkpoint),
+    After(u64),
+    Fresh,
+}
+
+/// Asks the sink where to begin, off the async threads since sinks read
+/// files. Anything the sink can't answer means syncing from the start, so
+/// one bad file never stops a station from syncing.
+async fn starting_point<R: RecordsSink + 'static>(
+    records_sink: &Arc<Mutex<R>>,
+    identity: &Identity,
+) -> StartingPoint {
+    let sink = Arc::clone(records_sink).lock_owned().await;
+    let identity = identity.clone();
+
+    let loading = tokio::task::spawn_blocking(move || {
+        match sink.resume(&identity) {
+            Ok(Some(checkpoint)) => return StartingPoint::Resume(checkpoint),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "{:?} resume error, starting over: {:?}",
+                identity.device_id, e
+            ),
+        }
+
+        match sink.last_synced(&identity) {
+            Ok(Some(last)) => StartingPoint::After(last),
+            Ok(None) => StartingPoint::Fresh,
+            Err(e) => {
+                warn!(
+                    "{:?} last synced error, starting over: {:?}",
+                    identity.device_id, e
+                );
+                StartingPoint::Fresh
+            }
+        }
+    });
+
+    loading.await.unwrap_or_else(|e| {
+        warn!("Loading starting point: {:?}", e);
+        StartingPoint::Fresh
+    })
+}
+
+async fn handle_server_command<R: RecordsSink + 'static, S: SendTransport>(
     cmd: &ServerCommand,
     sending: &S,
+    records_sink: &Arc<Mutex<R>>,
     events: &Sender<ServerEvent>,
     sink: &Sender<SinkMessage>,
     devices: &mut Devices,
@@ -723,10 +928,25 @@ async fn handle_server_command<R: RecordsSink, S: SendTransport>(
 
             match devices.get_device_by_addr(addr) {
                 Some(connected) => {
+                    if let Message::Statistics { identity, .. } = message {
+                        if connected.wants_resume() {
+                            match starting_point(records_sink, identity).await {
+                                StartingPoint::Resume(checkpoint) => connected.resume(checkpoint),
+                                StartingPoint::After(last) => connected.starting_after(last),
+                                StartingPoint::Fresh => {}
+                            }
+                        }
+                    }
+
+                    let fresh = match message.numbered_records()? {
+                        Some(records) => connected.unreceived(records),
+                        None => Vec::new(),
+                    };
+
                     let transition = connected.handle(message)?;
                     connected.apply(transition, events, sink, sending).await?;
 
-                    if let Some(records) = message.numbered_records()? {
+                    for records in fresh {
                         sink.send(SinkMessage::Records(ReceivedRecords {
                             sync_id: connected.sync_id.clone(),
                             device_id: connected.device_id.clone(),
@@ -749,12 +969,13 @@ async fn handle_server_command<R: RecordsSink, S: SendTransport>(
                 None => warn!("Unsolicited message: {:?}", message),
             }
         }
-        ServerCommand::Flushed => {
-            for connected in devices.iter() {
-                let transition = connected.flushed();
+        ServerCommand::Flushed(device_id, flushed) => match devices.get_device(device_id) {
+            Some(connected) => {
+                let transition = connected.flushed(flushed);
                 connected.apply(transition, events, sink, sending).await?;
             }
-        }
+            None => warn!("{:?} flushed, no connected device", device_id),
+        },
         ServerCommand::Tick => {
             for connected in devices.iter() {
                 let transition = connected.tick()?;
@@ -765,8 +986,15 @@ async fn handle_server_command<R: RecordsSink, S: SendTransport>(
             if let Some(connected) = devices.remove(device_id) {
                 info!("{:?} removed", device_id);
                 match connected.state {
-                    DeviceState::Failed | DeviceState::Synced => {}
-                    _ => events.send(ServerEvent::Failed(device_id.clone())).await?,
+                    DeviceState::Failed(_) | DeviceState::Synced => {}
+                    _ => {
+                        events
+                            .send(ServerEvent::Failed(
+                                device_id.clone(),
+                                SyncFailure::Cancelled,
+                            ))
+                            .await?
+                    }
                 }
             }
         }
@@ -898,6 +1126,68 @@ mod tests {
         assert_eq!(connected.requires(), None);
     }
 
+    #[test]
+    pub fn test_requires_after_resuming() {
+        let mut connected = test_device();
+        connected.resume(Checkpoint::new(
+            "resumed",
+            "generation",
+            0,
+            &RangeSetBlaze::from_iter([0..=4999, 6000..=6999]),
+        ));
+        connected.total_records = Some(100_000);
+        assert_eq!(connected.sync_id, "resumed");
+        assert_eq!(connected.requires(), Some(RecordRange(5000..=5999)));
+    }
+
+    #[test]
+    pub fn test_requires_only_newer_records() {
+        let mut connected = test_device();
+        connected.total_records = Some(100_000);
+        connected.starting_after(94_999);
+        assert_eq!(connected.requires(), Some(RecordRange(95_000..=99_999)));
+    }
+
+    #[test]
+    pub fn test_requires_nothing_when_already_synced() {
+        let mut connected = test_device();
+        connected.total_records = Some(100_000);
+        connected.starting_after(99_999);
+        assert_eq!(connected.requires(), None);
+    }
+
+    struct BrokenSink {}
+
+    impl RecordsSink for BrokenSink {
+        fn write(&self, _records: &ReceivedRecords) -> Result<()> {
+            Ok(())
+        }
+
+        fn flush(&self, _sync_id: String, _identity: Identity) -> Result<Option<Flushed>> {
+            Ok(None)
+        }
+
+        fn resume(&self, _identity: &Identity) -> Result<Option<Checkpoint>> {
+            Err(anyhow::anyhow!("Unreadable checkpoint"))
+        }
+
+        fn last_synced(&self, _identity: &Identity) -> Result<Option<u64>> {
+            Err(anyhow::anyhow!("Unreadable directory"))
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_starts_over_when_sink_fails() {
+        let identity = Identity {
+            device_id: DeviceId("device".to_owned()),
+            generation_id: "generation".to_owned(),
+            name: "Test Station".to_owned(),
+        };
+
+        let sink = Arc::new(Mutex::new(BrokenSink {}));
+        assert_eq!(starting_point(&sink, &identity).await, StartingPoint::Fresh);
+    }
+
     #[test]
     pub fn test_backoff_is_sensibly_tuned() {
         let mut backoff = ConnectedDevice::stall_backoff();
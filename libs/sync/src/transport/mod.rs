@@ -1,16 +1,25 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use std::{
+    collections::HashMap,
     net::{SocketAddr, SocketAddrV4},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio::net::UdpSocket;
 use tracing::*;
 
+use discovery::ErrorCounts;
+
 use crate::proto::{Message, MessageCodec};
 
+mod simulated;
+
+pub use simulated::{NetworkConditions, SimulatedStation, SimulatedTransport};
+
 const DEFAULT_PORT: u16 = 22144;
 const IP_ALL: [u8; 4] = [0, 0, 0, 0];
+const STALE_PARTIAL_MILLIS: u64 = 5000;
 
 #[derive(Debug)]
 pub struct TransportMessage(pub (SocketAddr, Message));
@@ -33,9 +42,51 @@ pub trait Transport {
     async fn open(&self) -> Result<(Self::Send, Self::Receive)>;
 }
 
+/// Decoding state for each peer, kept across receives so that records split
+/// over several packets are reassembled even when those packets arrive in
+/// different wakeups or interleaved with another station's. Malformed
+/// packets are dropped and counted per peer rather than failing the receive.
+#[derive(Default)]
+pub(crate) struct PeerCodecs {
+    codecs: HashMap<SocketAddr, MessageCodec>,
+    errors: ErrorCounts<SocketAddr>,
+}
+
+impl PeerCodecs {
+    pub(crate) fn try_read(&mut self, addr: SocketAddr, bytes: &[u8]) -> Option<Message> {
+        match self.codecs.entry(addr).or_default().try_read(bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                let count = self.errors.increment(addr);
+                warn!("{:?} Malformed ({} so far): {}", addr, count, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn errors(&self, addr: &SocketAddr) -> u64 {
+        self.errors.get(addr)
+    }
+
+    /// Forgets peers that aren't midway through a record, and drops partial
+    /// records that have waited too long for their remainder.
+    pub(crate) fn remove_stale(&mut self) {
+        self.codecs.retain(|addr, codec| {
+            if codec.is_stale(Duration::from_millis(STALE_PARTIAL_MILLIS)) {
+                warn!("{:?} Stale partial record, dropping", addr);
+                false
+            } else {
+                codec.has_partial()
+            }
+        });
+    }
+}
+
 #[derive(Clone)]
 pub struct OpenUdp {
     socket: Arc<UdpSocket>,
+    codecs: Arc<Mutex<PeerCodecs>>,
 }
 
 #[async_trait]
@@ -55,11 +106,12 @@ impl SendTransport for OpenUdp {
 #[async_trait]
 impl ReceiveTransport for OpenUdp {
     async fn recv(&self) -> Result<Option<Vec<TransportMessage>>> {
-        let mut codec = MessageCodec::default();
         let mut batch: Vec<TransportMessage> = Vec::new();
 
         self.socket.readable().await?;
 
+        let mut codecs = self.codecs.lock().expect("Lock error");
+
         loop {
             let mut buffer = vec![0u8; 4096];
 
@@ -67,18 +119,17 @@ impl ReceiveTransport for OpenUdp {
                 Ok((len, addr)) => {
                     trace!("{:?} Received {:?}", addr, len);
 
-                    match codec.try_read(&buffer[..len])? {
-                        Some(message) => {
-                            if let Message::Batch { flags: _flags } = message {
-                                info!("{:?} Batch", addr,)
-                            }
-
-                            batch.push(TransportMessage((addr, message)));
+                    if let Some(message) = codecs.try_read(addr, &buffer[..len]) {
+                        if let Message::Batch { flags: _flags } = message {
+                            info!("{:?} Batch", addr,)
                         }
-                        None => {}
+
+                        batch.push(TransportMessage((addr, message)));
                     }
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    codecs.remove_stale();
+
                     return Ok(Some(batch));
                 }
                 Err(e) => return Err(e.into()),
@@ -93,7 +144,17 @@ pub struct UdpTransport {
 
 impl UdpTransport {
     pub fn new() -> Self {
-        Self { port: DEFAULT_PORT }
+        Self::new_with_port(DEFAULT_PORT)
+    }
+
+    pub fn new_with_port(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl Default for UdpTransport {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -108,7 +169,10 @@ impl Transport for UdpTransport {
 
         info!("listening on {}", listening_addr);
 
-        let sender = OpenUdp { socket };
+        let sender = OpenUdp {
+            socket,
+            codecs: Default::default(),
+        };
 
         let receiver = sender.clone();
 
@@ -127,3 +191,92 @@ fn bind(addr: &SocketAddrV4) -> Result<UdpSocket> {
 
     Ok(UdpSocket::from_std(socket.into())?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Record;
+
+    fn fragments(head: u64, record: &Record, pieces: usize) -> Result<Vec<Vec<u8>>> {
+        let delimited = record.to_delimited()?;
+        let size = delimited.bytes().len().div_ceil(pieces);
+        delimited
+            .bytes()
+            .chunks(size)
+            .enumerate()
+            .map(|(sequence, fragment)| {
+                let mut bytes = Vec::new();
+                Message::Records {
+                    head,
+                    flags: 1,
+                    sequence: sequence as u32,
+                    records: vec![Record::Bytes(fragment.to_vec())],
+                }
+                .write(&mut bytes)?;
+                Ok(bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_reassembles_interleaved_peers() -> Result<()> {
+        let first: SocketAddr = "192.168.0.100:22144".parse()?;
+        let second: SocketAddr = "192.168.0.101:22144".parse()?;
+        let a = Record::new_all_zeros(2500);
+        let b = Record::Undelimited(vec![1; 2500]);
+        let from_first = fragments(10, &a, 3)?;
+        let from_second = fragments(20, &b, 3)?;
+
+        let mut codecs = PeerCodecs::default();
+        let mut received = Vec::new();
+        for (x, y) in from_first.iter().zip(from_second.iter()) {
+            received.extend(codecs.try_read(first, x));
+            codecs.remove_stale();
+            received.extend(codecs.try_read(second, y));
+            codecs.remove_stale();
+        }
+
+        assert_eq!(
+            received,
+            vec![
+                Message::Records {
+                    head: 10,
+                    flags: 0,
+                    sequence: 0,
+                    records: vec![a],
+                },
+                Message::Records {
+                    head: 20,
+                    flags: 0,
+                    sequence: 0,
+                    records: vec![b],
+                }
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_counts_malformed_packets_per_peer() -> Result<()> {
+        let first: SocketAddr = "192.168.0.100:22144".parse()?;
+        let second: SocketAddr = "192.168.0.101:22144".parse()?;
+
+        let mut codecs = PeerCodecs::default();
+        assert_eq!(codecs.try_read(first, &[0xff, 0, 0, 0]), None);
+        assert_eq!(
+            codecs.try_read(first, &[2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        assert_eq!(codecs.try_read(second, &[0]), None);
+        assert_eq!(
+            codecs.try_read(second, &[4, 0, 0, 0, 0, 0, 0, 0]),
+            Some(Message::Batch { flags: 0 })
+        );
+
+        assert_eq!(codecs.errors(&first), 2);
+        assert_eq!(codecs.errors(&second), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,511 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::*;
+
+use super::{PeerCodecs, ReceiveTransport, SendTransport, Transport, TransportMessage};
+use crate::proto::{pack_records, Identity, Message, RecordRange};
+
+/// Most packets a simulated station splits a single record across.
+const MAXIMUM_FRAGMENTS: usize = 4;
+
+/// How badly the simulated network treats packets, applied independently to
+/// every packet in both directions. Probabilities are from 0.0 to 1.0.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConditions {
+    pub seed: u64,
+    pub loss: f64,
+    pub duplication: f64,
+    /// Chance of a packet being held back long enough to arrive after the
+    /// ones sent behind it.
+    pub reordering: f64,
+    /// Upper bound on the random delay given to each packet.
+    pub delay: Duration,
+    /// Chance of a station splitting a record across several packets.
+    pub fragmentation: f64,
+}
+
+impl NetworkConditions {
+    /// Brings every probability into 0.0 to 1.0, treating NaN as never.
+    fn clamped(self) -> Self {
+        fn probability(p: f64) -> f64 {
+            if p.is_nan() {
+                0.0
+            } else {
+                p.clamp(0.0, 1.0)
+            }
+        }
+
+        Self {
+            loss: probability(self.loss),
+            duplication: probability(self.duplication),
+            reordering: probability(self.reordering),
+            fragmentation: probability(self.fragmentation),
+            ..self
+        }
+    }
+}
+
+/// A station living on the simulated network, answering Query and Require.
+#[derive(Clone, Debug)]
+pub struct SimulatedStation {
+    pub identity: Identity,
+    pub records: Vec<Vec<u8>>,
+}
+
+impl SimulatedStation {
+    pub fn new(identity: Identity, records: Vec<Vec<u8>>) -> Self {
+        Self { identity, records }
+    }
+
+    fn respond(&self, message: &Message, rng: &mut StdRng, fragmentation: f64) -> Vec<Message> {
+        match message {
+            Message::Query => vec![Message::Statistics {
+                nrecords: self.records.len() as u64,
+                identity: self.identity.clone(),
+            }],
+            Message::Require(range) => self.respond_to_require(range, rng, fragmentation),
+            _ => Vec::new(),
+        }
+    }
+
+    fn respond_to_require(
+        &self,
+        range: &RecordRange,
+        rng: &mut StdRng,
+        fragmentation: f64,
+    ) -> Vec<Message> {
+        let mut fragmenting = |bytes: &[u8]| {
+            // A single byte, such as an empty record, can't be split.
+            if bytes.len() < 2 {
+                return 1;
+            }
+            if rng.gen_bool(fragmentation) {
+                rng.gen_range(2..=MAXIMUM_FRAGMENTS.min(bytes.len()))
+            } else {
+                1
+            }
+        };
+
+        pack_records(&self.records, range, Some(&mut fragmenting))
+    }
+}
+
+struct Network {
+    conditions: NetworkConditions,
+    rng: Mutex<StdRng>,
+    stations: HashMap<SocketAddr, Arc<SimulatedStation>>,
+}
+
+impl Network {
+    /// Decides the fate of a single packet, returning the delay before each
+    /// copy that should be delivered.
+    fn deliveries(&self) -> Vec<Duration> {
+        let conditions = &self.conditions;
+        let mut rng = self.rng.lock().expect("Lock error");
+        let copies = if rng.gen_bool(conditions.duplication) {
+            2
+        } else {
+            1
+        };
+
+        let mut deliveries = Vec::new();
+        for _ in 0..copies {
+            if rng.gen_bool(conditions.loss) {
+                continue;
+            }
+
+            let delay = conditions.delay.mul_f64(rng.gen::<f64>());
+            if rng.gen_bool(conditions.reordering) {
+                deliveries.push(delay + conditions.delay + Duration::from_millis(1));
+            } else {
+                deliveries.push(delay);
+            }
+        }
+
+        deliveries
+    }
+
+    fn respond(&self, station: &SimulatedStation, message: &Message) -> Vec<Message> {
+        let mut rng = self.rng.lock().expect("Lock error");
+        station.respond(message, &mut rng, self.conditions.fragmentation)
+    }
+}
+
+/// An in-memory network of stations for exercising the sync protocol under
+/// seedable loss, duplication, reordering, delay and fragmentation.
+pub struct SimulatedTransport {
+    network: Arc<Network>,
+}
+
+impl SimulatedTransport {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        let conditions = conditions.clamped();
+        Self {
+            network: Arc::new(Network {
+                rng: Mutex::new(StdRng::seed_from_u64(conditions.seed)),
+                conditions,
+                stations: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn with_station(self, addr: SocketAddr, station: SimulatedStation) -> Self {
+        let mut network = Arc::try_unwrap(self.network)
+            .unwrap_or_else(|_| panic!("Stations must be added before opening"));
+        network.stations.insert(addr, Arc::new(station));
+
+        Self {
+            network: Arc::new(network),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for SimulatedTransport {
+    type Send = OpenSimulated;
+    type Receive = ReceiveSimulated;
+
+    async fn open(&self) -> Result<(OpenSimulated, ReceiveSimulated)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        Ok((
+            OpenSimulated {
+                network: Arc::clone(&self.network),
+                inbox: tx,
+            },
+            ReceiveSimulated {
+                inbox: tokio::sync::Mutex::new(rx),
+                codecs: Default::default(),
+            },
+        ))
+    }
+}
+
+type Packet = (SocketAddr, Vec<u8>);
+
+pub struct OpenSimulated {
+    network: Arc<Network>,
+    inbox: UnboundedSender<Packet>,
+}
+
+#[async_trait]
+impl SendTransport for OpenSimulated {
+    async fn send(&self, message: TransportMessage) -> Result<()> {
+        let TransportMessage((addr, message)) = message;
+        let station = match self.network.stations.get(&addr) {
+            Some(station) => Arc::clone(station),
+            None => {
+                warn!("{:?} No such station", addr);
+                return Ok(());
+            }
+        };
+
+        for delay in self.network.deliveries() {
+            let network = Arc::clone(&self.network);
+            let inbox = self.inbox.clone();
+            let station = Arc::clone(&station);
+            let message = message.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+
+                for replying in network.respond(&station, &message) {
+                    let mut bytes = Vec::new();
+                    if let Err(e) = replying.write(&mut bytes) {
+                        warn!("{:?} Write error: {:?}", addr, e);
+                        continue;
+                    }
+
+                    for delay in network.deliveries() {
+                        let inbox = inbox.clone();
+                        let bytes = bytes.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            // The server going away is the only way this fails.
+                            let _ = inbox.send((addr, bytes));
+                        });
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ReceiveSimulated {
+    inbox: tokio::sync::Mutex<UnboundedReceiver<Packet>>,
+    codecs: Mutex<PeerCodecs>,
+}
+
+impl ReceiveSimulated {
+    fn decode(&self, (addr, bytes): Packet) -> Option<TransportMessage> {
+        let mut codecs = self.codecs.lock().expect("Lock error");
+        codecs
+            .try_read(addr, &bytes)
+            .map(|m| TransportMessage((addr, m)))
+    }
+}
+
+#[async_trait]
+impl ReceiveTransport for ReceiveSimulated {
+    async fn recv(&self) -> Result<Option<Vec<TransportMessage>>> {
+        let mut inbox = self.inbox.lock().await;
+
+        let first = match inbox.recv().await {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+
+        let mut batch: Vec<TransportMessage> = self.decode(first).into_iter().collect();
+        while let Ok(packet) = inbox.try_recv() {
+            batch.extend(self.decode(packet));
+        }
+
+        self.codecs.lock().expect("Lock error").remove_stale();
+
+        Ok(Some(batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use discovery::{DeviceId, Discovered};
+    use proptest::prelude::*;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{FilesRecordSink, Record, Server, ServerEvent};
+
+    fn test_identity(station: usize) -> Identity {
+        Identity {
+            device_id: DeviceId(format!("00112233445566{:02x}", station)),
+            generation_id: "8899aabbccddeeff".to_owned(),
+            name: format!("Simulated {}", station),
+        }
+    }
+
+    fn station_addr(station: usize) -> SocketAddr {
+        SocketAddr::new([192, 168, 0, 100 + station as u8].into(), 22144)
+    }
+
+    /// Syncs the records over a simulated network, returning the joined file
+    /// if the sync completed and None if it failed.
+    fn sync(conditions: NetworkConditions, records: Vec<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(sync_all(conditions, vec![records])?.remove(0))
+    }
+
+    /// Syncs several stations at once, returning each one's joined file.
+    fn sync_all(
+        conditions: NetworkConditions,
+        stations: Vec<Vec<Vec<u8>>>,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?;
+
+        runtime.block_on(async {
+            let dir = TempDir::new("fk-tests-simulated")?;
+            let mut transport = SimulatedTransport::new(conditions);
+            for (station, records) in stations.iter().enumerate() {
+                transport = transport.with_station(
+                    station_addr(station),
+                    SimulatedStation::new(test_identity(station), records.clone()),
+                );
+            }
+            let server = Arc::new(Server::new(transport, FilesRecordSink::new(dir.path())));
+            let (publish, mut events) = mpsc::channel::<ServerEvent>(32);
+            let serving = tokio::spawn({
+                let server = Arc::clone(&server);
+                async move { server.run(publish).await }
+            });
+
+            for station in 0..stations.len() {
+                let discovered = Discovered {
+                    device_id: test_identity(station).device_id,
+                    http_addr: None,
+                    udp_addr: Some(station_addr(station)),
+                };
+
+                while server.sync(discovered.clone()).await.is_err() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+
+            let finished = tokio::time::timeout(Duration::from_secs(3600), async {
+                let mut finished = HashMap::new();
+                while finished.len() < stations.len() {
+                    match events.recv().await {
+                        Some(ServerEvent::Completed(device_id, _)) => {
+                            finished.insert(device_id, true);
+                        }
+                        Some(ServerEvent::Failed(device_id, _)) => {
+                            finished.insert(device_id, false);
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                finished
+            })
+            .await?;
+
+            serving.abort();
+
+            let mut joined = Vec::new();
+            for station in 0..stations.len() {
+                let device_id = test_identity(station).device_id;
+                if finished.get(&device_id) != Some(&true) {
+                    joined.push(None);
+                    continue;
+                }
+
+                let device_path = dir.path().join(&device_id.0);
+                let path = std::fs::read_dir(&device_path)?
+                    .map(|entry| Ok(entry?.path()))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .find(|p| p.extension().map(|e| e == "fkpb").unwrap_or_default())
+                    .ok_or_else(|| anyhow::anyhow!("Completed without a joined file"))?;
+
+                joined.push(Some(std::fs::read(path)?));
+            }
+
+            Ok(joined)
+        })
+    }
+
+    fn expected(records: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for record in records {
+            bytes.extend(Record::Undelimited(record.clone()).to_delimited()?.bytes());
+        }
+
+        Ok(bytes)
+    }
+
+    fn test_records(number: usize) -> Vec<Vec<u8>> {
+        (0..number)
+            .map(|n| vec![n as u8; 20 + (n * 13) % 300])
+            .collect()
+    }
+
+    #[test]
+    pub fn test_syncs_over_perfect_network() -> Result<()> {
+        let records = test_records(1000);
+        let joined = sync(NetworkConditions::default(), records.clone())?;
+
+        assert_eq!(joined, Some(expected(&records)?));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fails_when_station_never_answers() -> Result<()> {
+        let conditions = NetworkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+
+        assert_eq!(sync(conditions, test_records(10))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_syncs_stations_concurrently() -> Result<()> {
+        let conditions = NetworkConditions {
+            seed: 7,
+            fragmentation: 0.5,
+            reordering: 0.1,
+            delay: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let stations = vec![test_records(300), test_records(500)];
+        let joined = sync_all(conditions, stations.clone())?;
+
+        assert_eq!(joined[0], Some(expected(&stations[0])?));
+        assert_eq!(joined[1], Some(expected(&stations[1])?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditions_are_clamped() {
+        let conditions = NetworkConditions {
+            loss: -0.5,
+            duplication: 1.5,
+            reordering: f64::NAN,
+            fragmentation: 0.25,
+            ..Default::default()
+        }
+        .clamped();
+
+        assert_eq!(conditions.loss, 0.0);
+        assert_eq!(conditions.duplication, 1.0);
+        assert_eq!(conditions.reordering, 0.0);
+        assert_eq!(conditions.fragmentation, 0.25);
+    }
+
+    #[test]
+    fn test_syncs_empty_records() -> Result<()> {
+        let conditions = NetworkConditions {
+            seed: 3,
+            fragmentation: 1.0,
+            ..Default::default()
+        };
+        let records = vec![Vec::new(), vec![1, 2, 3], Vec::new()];
+
+        assert_eq!(
+            sync(conditions, records.clone())?,
+            Some(expected(&records)?)
+        );
+
+        Ok(())
+    }
+
+    fn conditions() -> impl Strategy<Value = NetworkConditions> {
+        (
+            any::<u64>(),
+            0.0..0.3,
+            0.0..0.3,
+            0.0..0.3,
+            0..50u64,
+            0.0..0.3,
+        )
+            .prop_map(
+                |(seed, loss, duplication, reordering, delay, fragmentation)| NetworkConditions {
+                    seed,
+                    loss,
+                    duplication,
+                    reordering,
+                    delay: Duration::from_millis(delay),
+                    fragmentation,
+                },
+            )
+    }
+
+    fn records() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        prop::collection::vec(prop::collection::vec(any::<u8>(), 0..400), 1..300)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn test_syncs_identical_file_or_fails(conditions in conditions(), records in records()) {
+            if let Some(joined) = sync(conditions, records.clone()).unwrap() {
+                prop_assert_eq!(joined, expected(&records).unwrap());
+            }
+        }
+    }
+}